@@ -23,7 +23,14 @@ fn main() -> ! {
 
     let mut porta = p.GPIO_PORTA.split(&sc.power_control);
 
-    let mut eeprom = Eeprom::new(p.EEPROM, &sc.power_control);
+    let mut eeprom = match Eeprom::new(p.EEPROM, &sc.power_control) {
+        Ok(eeprom) => eeprom,
+        Err(mut failed) => {
+            // Maybe the supply was still settling, so have another go
+            failed.eeprom.recover(&sc.power_control, 3).unwrap();
+            failed.eeprom
+        }
+    };
 
     match eeprom_test_all(&mut eeprom) {
         Ok(_) => {
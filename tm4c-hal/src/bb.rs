@@ -4,10 +4,248 @@
 //! words. Writing a 1 to that word sets the matching bit. Writing a 0 clears
 //! the matching bit. It means you can perform atomic bit set/clear; i.e.
 //! without a read-modify-write.
+//!
+//! Only two 1 MiB windows are bit-banded: SRAM from `0x2000_0000` and the
+//! peripherals from `0x4000_0000`.
+//!
+//! [`BitBand`] is a checked handle on one bit of a register, and
+//! [`AtomicFlags`] is an array of flags in SRAM which interrupt handlers and
+//! the main loop can share without a lock:
+//!
+//! ```ignore
+//! static EVENTS: AtomicFlags<1> = AtomicFlags::new();
+//!
+//! // In the interrupt handler
+//! EVENTS.set(3);
+//!
+//! // In the main loop
+//! if EVENTS.take(3) {
+//!     // ...
+//! }
+//! ```
 
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::marker::PhantomData;
 use core::ptr::{read_volatile, write_volatile};
 use cortex_m::asm::nop;
 
+/// The bit-banded SRAM window
+const SRAM: (u32, u32) = (0x2000_0000, 0x200F_FFFF);
+
+/// The bit-banded peripheral window
+const PERIPHERALS: (u32, u32) = (0x4000_0000, 0x400F_FFFF);
+
+/// Why a bit can't be bit-banded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The address isn't in the SRAM or peripheral bit-band window
+    OutOfRange(u32),
+    /// The bit number is 32 or more
+    BadBit(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OutOfRange(address) => {
+                write!(f, "address {:#010x} is not bit-banded", address)
+            }
+            Error::BadBit(bit) => write!(f, "bit {} is not in a 32-bit word", bit),
+        }
+    }
+}
+
+fn in_window(address: u32, window: (u32, u32)) -> bool {
+    address >= window.0 && address <= window.1
+}
+
+/// Checks that bit `bit` of the word at `address` can be bit-banded, and
+/// returns the address of its bit-band alias
+fn checked_bitband(address: u32, bit: u8, windows: &[(u32, u32)]) -> Result<*mut u32, Error> {
+    if bit >= 32 {
+        return Err(Error::BadBit(bit));
+    }
+    // The bit might be in a later byte than `address`
+    let byte = address.wrapping_add(u32::from(bit / 8));
+    if !windows
+        .iter()
+        .any(|&w| in_window(address, w) && in_window(byte, w))
+    {
+        return Err(Error::OutOfRange(address));
+    }
+    Ok(ref_to_bitband(address, bit))
+}
+
+/// One bit of a peripheral register (or a word of SRAM), accessed through its
+/// bit-band alias, so it can be set, cleared and read atomically.
+///
+/// The address and bit are checked when the `BitBand` is made, so using it
+/// is safe.
+#[derive(Clone, Copy)]
+pub struct BitBand<'a> {
+    word: *mut u32,
+    _register: PhantomData<&'a ()>,
+}
+
+// The bit-band alias is a single word, which is only ever accessed
+// atomically.
+unsafe impl Send for BitBand<'_> {}
+unsafe impl Sync for BitBand<'_> {}
+
+impl<'a> BitBand<'a> {
+    /// Bit `bit` of a peripheral `register`, such as `&p.GPIO_PORTF.data`.
+    ///
+    /// Fails if `register` isn't in the peripheral window (`0x4000_0000` to
+    /// `0x400F_FFFF`) or `bit` is 32 or more.
+    pub fn new<T>(register: &'a T, bit: u8) -> Result<Self, Error> {
+        // Everything in the peripheral window is a memory-mapped register,
+        // which svd2rust only ever gives us shared references to, so writing
+        // through the alias can't invalidate anything Rust knows about.
+        let word = checked_bitband(register as *const T as u32, bit, &[PERIPHERALS])?;
+        Ok(BitBand {
+            word,
+            _register: PhantomData,
+        })
+    }
+
+    /// Bit `bit` of the word at `address`, which can be in SRAM or in the
+    /// peripherals.
+    ///
+    /// # Safety
+    ///
+    /// `address` must stay valid for `'a`, and nothing else may rely on the
+    /// bit not changing while this exists (e.g. through a `&` reference to
+    /// non-`UnsafeCell` data).
+    pub unsafe fn from_ptr<T>(address: *const T, bit: u8) -> Result<Self, Error> {
+        let word = checked_bitband(address as u32, bit, &[SRAM, PERIPHERALS])?;
+        Ok(BitBand {
+            word,
+            _register: PhantomData,
+        })
+    }
+
+    /// Reads the bit
+    pub fn read(&self) -> bool {
+        unsafe { read_volatile(self.word) != 0 }
+    }
+
+    /// Sets or clears the bit
+    pub fn write(&self, value: bool) {
+        unsafe { write_volatile(self.word, u32::from(value)) }
+    }
+
+    /// Sets the bit
+    pub fn set(&self) {
+        self.write(true)
+    }
+
+    /// Clears the bit
+    pub fn clear(&self) {
+        self.write(false)
+    }
+
+    /// Sets and then clears the bit, e.g. to pulse a reset line
+    pub fn pulse(&self) {
+        self.set();
+        self.clear();
+    }
+
+    /// Spins until the bit is set
+    pub fn spin_until_set(&self) {
+        while !self.read() {
+            nop();
+        }
+    }
+}
+
+/// An array of `32 * N` flags in SRAM, each of which can be set, cleared and
+/// read atomically through the bit-band alias, without a critical section.
+///
+/// This must live in SRAM (a `static` does), or its methods panic.
+pub struct AtomicFlags<const N: usize> {
+    words: [UnsafeCell<u32>; N],
+}
+
+// Every access goes through the bit-band alias, or is a single word read,
+// so they're all atomic.
+unsafe impl<const N: usize> Sync for AtomicFlags<N> {}
+
+impl<const N: usize> AtomicFlags<N> {
+    /// All flags clear
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const fn new() -> Self {
+        const CLEAR: UnsafeCell<u32> = UnsafeCell::new(0);
+        AtomicFlags { words: [CLEAR; N] }
+    }
+
+    /// The number of flags
+    pub const fn len(&self) -> usize {
+        32 * N
+    }
+
+    /// Is this an array of no flags at all?
+    pub const fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    /// The bit-band alias of flag `index`
+    fn alias(&self, index: usize) -> *mut u32 {
+        assert!(index < self.len(), "flag index out of range");
+        let word = self.words[index / 32].get();
+        match checked_bitband(word as u32, (index % 32) as u8, &[SRAM]) {
+            Ok(alias) => alias,
+            Err(_) => panic!("AtomicFlags must be in SRAM"),
+        }
+    }
+
+    /// Is flag `index` set?
+    pub fn get(&self, index: usize) -> bool {
+        unsafe { read_volatile(self.alias(index)) != 0 }
+    }
+
+    /// Sets flag `index` to `value`
+    pub fn write(&self, index: usize, value: bool) {
+        unsafe { write_volatile(self.alias(index), u32::from(value)) }
+    }
+
+    /// Sets flag `index`
+    pub fn set(&self, index: usize) {
+        self.write(index, true)
+    }
+
+    /// Clears flag `index`
+    pub fn clear(&self, index: usize) {
+        self.write(index, false)
+    }
+
+    /// Clears flag `index`, returning whether it was set.
+    ///
+    /// The read and the clear are separate accesses, so if the flag is set
+    /// again in between, the two sets count as one.
+    pub fn take(&self, index: usize) -> bool {
+        let alias = self.alias(index);
+        unsafe {
+            let was_set = read_volatile(alias) != 0;
+            if was_set {
+                write_volatile(alias, 0);
+            }
+            was_set
+        }
+    }
+
+    /// Reads 32 flags at once, starting from flag `32 * word`
+    pub fn word(&self, word: usize) -> u32 {
+        unsafe { read_volatile(self.words[word].get()) }
+    }
+}
+
+impl<const N: usize> Default for AtomicFlags<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Sets/Clears a bit at the given address atomically, using the bit-banding
 /// feature.
 ///
@@ -52,11 +290,71 @@ pub fn read_bit<T>(address: *const T, bit: u8) -> bool {
     unsafe { read_volatile(bit_word) != 0 }
 }
 
-/// Address must be >= 0x2000_0000 and <= 0x2007_FFFC. Bit must be < 32.
+/// Address must be in the SRAM or peripheral window. Bit must be < 32.
 fn ref_to_bitband(address: u32, bit: u8) -> *mut u32 {
+    debug_assert!(bit < 32, "bit-band bit out of range");
+    debug_assert!(
+        in_window(address, SRAM) || in_window(address, PERIPHERALS),
+        "address is not bit-banded"
+    );
     let prefix = address & 0xF000_0000;
     let byte_offset = address & 0x0FFF_FFFF;
     let bit_word_offset = (byte_offset * 32) + (u32::from(bit) * 4);
     let bit_word_addr = bit_word_offset + prefix + 0x0200_0000;
     bit_word_addr as *mut u32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alias(address: u32, bit: u8, windows: &[(u32, u32)]) -> Result<u32, Error> {
+        checked_bitband(address, bit, windows).map(|word| word as u32)
+    }
+
+    #[test]
+    fn sram_window() {
+        assert_eq!(alias(0x2000_0000, 0, &[SRAM]), Ok(0x2200_0000));
+        assert_eq!(alias(0x2000_0000, 5, &[SRAM]), Ok(0x2200_0014));
+        assert_eq!(alias(0x2000_0004, 31, &[SRAM]), Ok(0x2200_00FC));
+        assert_eq!(alias(0x200F_FFFC, 31, &[SRAM]), Ok(0x23FF_FFFC));
+    }
+
+    #[test]
+    fn peripheral_window() {
+        assert_eq!(alias(0x4002_5000, 3, &[PERIPHERALS]), Ok(0x424A_000C));
+        assert_eq!(
+            alias(0x400F_FFFC, 31, &[SRAM, PERIPHERALS]),
+            Ok(0x43FF_FFFC)
+        );
+    }
+
+    #[test]
+    fn out_of_window() {
+        for &address in &[0x1FFF_FFFC, 0x2010_0000, 0x3FFF_FFFC, 0x4010_0000] {
+            assert_eq!(
+                alias(address, 0, &[SRAM, PERIPHERALS]),
+                Err(Error::OutOfRange(address))
+            );
+        }
+        // Each handle only accepts its own windows
+        assert_eq!(
+            alias(0x2000_0000, 0, &[PERIPHERALS]),
+            Err(Error::OutOfRange(0x2000_0000))
+        );
+        // The bit is in a byte past the end of the window
+        assert_eq!(
+            alias(0x200F_FFFF, 8, &[SRAM]),
+            Err(Error::OutOfRange(0x200F_FFFF))
+        );
+    }
+
+    #[test]
+    fn bad_bit() {
+        assert_eq!(alias(0x2000_0000, 32, &[SRAM]), Err(Error::BadBit(32)));
+        assert_eq!(
+            alias(0x4000_0000, 255, &[PERIPHERALS]),
+            Err(Error::BadBit(255))
+        );
+    }
+}
@@ -0,0 +1,17 @@
+//! CRC calculations shared by the storage code.
+
+/// Continues a CRC-32 calculation over `bytes`. Start with `!0` and invert
+/// the result at the end.
+pub(crate) fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -1,9 +1,15 @@
 //! Code for busy-waiting
 
-use crate::{sysctl::Clocks, time::Hertz};
-use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use crate::{
+    sysctl::Clocks,
+    time::{Hertz, MonoTimer},
+};
+use cortex_m::peripheral::{syst::SystClkSource, DCB, DWT, SYST};
 use embedded_hal::delay::DelayNs;
 
+/// SysTick is a 24-bit counter, so this is the most we can wait for in one go
+const MAX_PERIOD: u32 = 1 << 24;
+
 /// System timer (SysTick) as a delay provider
 pub struct Delay {
     sysclk: Hertz,
@@ -25,19 +31,143 @@ impl Delay {
     pub fn free(self) -> SYST {
         self.syst
     }
+
+    /// Counts down one SysTick period of `cycles` cycles
+    fn wait_period(&mut self, cycles: u32) {
+        // SysTick counts from the reload value down to zero, so one period
+        // is one more than the reload value. A reload value of zero doesn't
+        // count at all, so the shortest period is two cycles.
+        self.syst.set_reload(cycles.max(2) - 1);
+        self.syst.clear_current();
+        self.syst.enable_counter();
+        while !self.syst.has_wrapped() {}
+        self.syst.disable_counter();
+    }
+}
+
+/// The number of `sysclk` cycles in `ns` nanoseconds, rounded up so a delay
+/// never ends early
+#[doc(hidden)]
+pub fn cycles(ns: u32, sysclk: Hertz) -> u64 {
+    (u64::from(ns) * u64::from(sysclk.0)).div_ceil(1_000_000_000)
+}
+
+/// Splits a number of cycles into SysTick periods: some number of full
+/// (2<sup>24</sup> cycle) periods, and one shorter period (which may be zero
+/// cycles long)
+fn periods(cycles: u64) -> (u64, u32) {
+    (
+        cycles / u64::from(MAX_PERIOD),
+        (cycles % u64::from(MAX_PERIOD)) as u32,
+    )
 }
 
 impl DelayNs for Delay {
     fn delay_ns(&mut self, ns: u32) {
-        // * : u32 x u32 => u64, so do the multiplication in u64 to avoid overflow
-        let rvr: u32 = (((ns as u64) * (self.sysclk.0 as u64)) / 1_000_000_000) as u32;
+        // At 80 MHz, SysTick can only count about 200 ms, so longer delays
+        // are made up of several periods.
+        let (full, partial) = periods(cycles(ns, self.sysclk));
+        if partial != 0 {
+            self.wait_period(partial);
+        }
+        for _ in 0..full {
+            self.wait_period(MAX_PERIOD);
+        }
+    }
+}
 
+/// The DWT cycle counter as a delay provider.
+///
+/// This only reads the cycle counter, so it doesn't own any peripheral and
+/// can be copied freely: every driver and task can have its own.
+#[derive(Clone, Copy)]
+pub struct CycleDelay {
+    sysclk: Hertz,
+}
 
-        assert!(rvr < (1 << 24));
-        self.syst.set_reload(rvr);
-        self.syst.clear_current();
-        self.syst.enable_counter();
-        while !self.syst.has_wrapped() {}
-        self.syst.disable_counter();
+impl CycleDelay {
+    /// Makes sure the DWT cycle counter is running (enabling tracing in the
+    /// DCB first, which it needs), and returns a delay provider based on it.
+    ///
+    /// The counter is never stopped or reset, so this is safe to use
+    /// alongside a [`MonoTimer`].
+    pub fn new(dwt: &mut DWT, dcb: &mut DCB, clocks: &Clocks) -> Self {
+        if !DWT::cycle_counter_enabled() {
+            dcb.enable_trace();
+            DWT::unlock();
+            dwt.enable_cycle_counter();
+        }
+
+        CycleDelay {
+            sysclk: clocks.sysclk,
+        }
+    }
+}
+
+/// A `MonoTimer` has already started the cycle counter
+impl From<MonoTimer> for CycleDelay {
+    fn from(timer: MonoTimer) -> Self {
+        CycleDelay {
+            sysclk: timer.frequency(),
+        }
+    }
+}
+
+impl DelayNs for CycleDelay {
+    fn delay_ns(&mut self, ns: u32) {
+        let mut remaining = cycles(ns, self.sysclk);
+        // The counter is only 32 bits wide, so wait in chunks that are
+        // comfortably shorter than a wrap
+        while remaining > 0 {
+            let chunk = remaining.min(1 << 31) as u32;
+            let start = DWT::get_cycle_count();
+            while DWT::get_cycle_count().wrapping_sub(start) < chunk {}
+            remaining -= u64::from(chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycles_round_up() {
+        assert_eq!(cycles(0, Hertz(80_000_000)), 0);
+        // 12.5 ns per cycle at 80 MHz
+        assert_eq!(cycles(1, Hertz(80_000_000)), 1);
+        assert_eq!(cycles(12, Hertz(80_000_000)), 1);
+        assert_eq!(cycles(13, Hertz(80_000_000)), 2);
+        assert_eq!(cycles(25, Hertz(80_000_000)), 2);
+        assert_eq!(cycles(1_000, Hertz(80_000_000)), 80);
+        // 1/120 MHz isn't a whole number of nanoseconds
+        assert_eq!(cycles(1_000, Hertz(120_000_000)), 120);
+        assert_eq!(cycles(1_001, Hertz(120_000_000)), 121);
+    }
+
+    #[test]
+    fn cycles_do_not_overflow() {
+        assert_eq!(cycles(u32::MAX, Hertz(120_000_000)), 515_396_076);
+        assert_eq!(cycles(u32::MAX, Hertz(u32::MAX)), 18_446_744_066);
+    }
+
+    #[test]
+    fn short_delays_are_one_period() {
+        assert_eq!(periods(0), (0, 0));
+        assert_eq!(periods(1), (0, 1));
+        assert_eq!(periods(u64::from(MAX_PERIOD) - 1), (0, MAX_PERIOD - 1));
+    }
+
+    #[test]
+    fn long_delays_are_chained() {
+        assert_eq!(periods(u64::from(MAX_PERIOD)), (1, 0));
+        assert_eq!(periods(u64::from(MAX_PERIOD) + 1), (1, 1));
+        // One second at 80 MHz, which used to panic
+        let (full, partial) = periods(cycles(1_000_000_000, Hertz(80_000_000)));
+        assert_eq!((full, partial), (4, 12_891_136));
+        assert_eq!(
+            full * u64::from(MAX_PERIOD) + u64::from(partial),
+            80_000_000
+        );
     }
 }
@@ -12,9 +12,46 @@
 //! This flexibility comes at the cost of efficiency, as the
 //! datasheet calls for at least 4 cycles of delay after setting the EEBLOCK
 //! register.
+//!
+//! As well as the traits here, the `Eeprom` implements the `embedded-storage`
+//! traits (blocking and async), which address the EEPROM by byte offset. For
+//! those, the EEPROM is one `ERASE_SIZE` (64 byte) sector per block, and an
+//! erased word reads as `0xFFFF_FFFF`.
+//!
+//! Blocks can be given a [`Protection`] level and a password, and hidden
+//! until the next reset. Accessing a block in a way that isn't allowed fails
+//! with `AccessDenied` or `BlockHidden`.
+//!
+//! The logic lives in [`Driver`], which does its register accesses through a
+//! [`Backend`]. Each chip's `Eeprom` is a `Driver` over the EEPROM registers,
+//! and over the RAM-simulated [`sim::SimBackend`] it can be tested on a host.
+//!
+//! `start_write` writes in the background, a word at a time from the EEPROM
+//! done interrupt, which arrives through the flash controller's interrupt.
+//! The async `NorFlash` methods work that way too. Forward the interrupt to
+//! `Eeprom::on_interrupt`:
+//!
+//! ```ignore
+//! #[interrupt]
+//! fn FLASH() {
+//!     Eeprom::on_interrupt();
+//! }
+//! ```
+
+use core::task::Waker;
+
+use embedded_storage::nor_flash::{NorFlashError, NorFlashErrorKind};
+
+pub mod kv;
+pub mod sim;
+
+#[doc(hidden)]
+pub use embedded_storage;
+#[doc(hidden)]
+pub use embedded_storage_async;
 
 /// Possible errors for the Flash memory module
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum EepromError {
     /// Eeprom is not finished
     Busy,
@@ -30,6 +67,22 @@ pub enum EepromError {
     ReadWouldOverflow,
     /// Requesting to read more data than the provided buffer can hold
     ReadBufferTooSmall,
+    /// An `embedded-storage` offset or length isn't aligned as it must be
+    NotAligned,
+    /// The block's protection doesn't allow this access while it's locked
+    AccessDenied,
+    /// The block has been hidden until the next reset
+    BlockHidden,
+    /// The password didn't unlock the block
+    WrongPassword,
+    /// A password must be one to three words long
+    InvalidPassword,
+    /// A background write can be at most [`WRITE_QUEUE_WORDS`] words long
+    WriteTooLong,
+    /// ERETRY is set in EESUPP: the EEPROM couldn't recover an erase
+    EraseRetry,
+    /// PRETRY is set in EESUPP: the EEPROM couldn't recover a program
+    ProgramRetry,
 }
 
 impl core::fmt::Display for EepromError {
@@ -46,6 +99,45 @@ impl core::fmt::Display for EepromError {
                 write!(f, "Reading this data would overflow the EEPROM")
             }
             EepromError::ReadBufferTooSmall => write!(f, "Allocated buffer too small for reading"),
+            EepromError::NotAligned => write!(f, "Offset or length is not aligned"),
+            EepromError::AccessDenied => write!(f, "Block is protected"),
+            EepromError::BlockHidden => write!(f, "Block is hidden"),
+            EepromError::WrongPassword => write!(f, "Password did not unlock the block"),
+            EepromError::InvalidPassword => write!(f, "Password must be 1 to 3 words long"),
+            EepromError::WriteTooLong => write!(f, "Too much data to write in the background"),
+            EepromError::EraseRetry => write!(f, "Eeprom ERETRY bit set"),
+            EepromError::ProgramRetry => write!(f, "Eeprom PRETRY bit set"),
+        }
+    }
+}
+
+impl NorFlashError for EepromError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            EepromError::AddressOutOfBounds
+            | EepromError::BlockOutOfBounds
+            | EepromError::OffsetOutOfBounds
+            | EepromError::WriteWouldOverflow
+            | EepromError::ReadWouldOverflow => NorFlashErrorKind::OutOfBounds,
+            EepromError::NotAligned => NorFlashErrorKind::NotAligned,
+            EepromError::Busy
+            | EepromError::ReadBufferTooSmall
+            | EepromError::AccessDenied
+            | EepromError::BlockHidden
+            | EepromError::WrongPassword
+            | EepromError::InvalidPassword
+            | EepromError::WriteTooLong
+            | EepromError::EraseRetry
+            | EepromError::ProgramRetry => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl From<NorFlashErrorKind> for EepromError {
+    fn from(kind: NorFlashErrorKind) -> Self {
+        match kind {
+            NorFlashErrorKind::NotAligned => EepromError::NotAligned,
+            _ => EepromError::AddressOutOfBounds,
         }
     }
 }
@@ -140,3 +232,1494 @@ pub trait Read {
         buffer: &mut [u8],
     ) -> Result<(), EepromError>;
 }
+
+/// `Eeprom::new` failed. This holds on to the `Eeprom`, so you can try to
+/// recover it.
+pub struct InitError<E> {
+    /// What went wrong
+    pub error: EepromError,
+    /// The EEPROM, which is powered up but won't allow any access until it
+    /// has been recovered
+    pub eeprom: E,
+}
+
+impl<E> core::fmt::Debug for InitError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("InitError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E> core::fmt::Display for InitError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "EEPROM initialisation failed: {}", self.error)
+    }
+}
+
+/// The state of the EEPROM's recovery from an interrupted operation (the
+/// EESUPP register)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SupportStatus {
+    /// An erase is needed before the EEPROM can continue (EREQ)
+    pub erase_required: bool,
+    /// An erase could not be completed (ERETRY)
+    pub erase_retry: bool,
+    /// A program could not be completed (PRETRY)
+    pub program_retry: bool,
+}
+
+impl SupportStatus {
+    /// Decodes the EESUPP register
+    #[doc(hidden)]
+    pub fn from_bits(bits: u32) -> Self {
+        SupportStatus {
+            erase_required: bits & (1 << 1) != 0,
+            erase_retry: bits & (1 << 2) != 0,
+            program_retry: bits & (1 << 3) != 0,
+        }
+    }
+
+    /// Turns a failed recovery into an error
+    #[doc(hidden)]
+    pub fn check(self) -> Result<(), EepromError> {
+        if self.erase_retry {
+            Err(EepromError::EraseRetry)
+        } else if self.program_retry {
+            Err(EepromError::ProgramRetry)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The key which must be written to EEDBGME along with the ME bit
+#[doc(hidden)]
+pub const MASS_ERASE_KEY: u32 = 0xE37B_0000;
+
+/// How a block may be accessed (the PROT field of EEPROT). What each level
+/// allows depends on whether the block (or block 0, which covers the whole
+/// EEPROM) has a password, and whether it's unlocked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protection {
+    /// Without a password, read-write. With a password, read-write when
+    /// unlocked and read-only when locked.
+    ReadWrite,
+    /// Without a password, read-write, as there's nothing to unlock. With a
+    /// password, read-write when unlocked and no access when locked.
+    ReadWriteWhenUnlocked,
+    /// Without a password, read-only. With a password, read-only when
+    /// unlocked and no access when locked.
+    ReadOnly,
+}
+
+impl Protection {
+    /// The value of the PROT field
+    #[doc(hidden)]
+    pub fn bits(self) -> u32 {
+        match self {
+            Protection::ReadWrite => 0,
+            Protection::ReadWriteWhenUnlocked => 1,
+            Protection::ReadOnly => 2,
+        }
+    }
+
+    /// Decodes the PROT field
+    #[doc(hidden)]
+    pub fn from_bits(bits: u32) -> Self {
+        match bits & 0x7 {
+            0 => Protection::ReadWrite,
+            1 => Protection::ReadWriteWhenUnlocked,
+            _ => Protection::ReadOnly,
+        }
+    }
+}
+
+/// The most words a background write can queue
+pub const WRITE_QUEUE_WORDS: usize = 32;
+
+/// The words of a background write, shared with the interrupt handler
+#[doc(hidden)]
+pub struct WriteQueue {
+    words: [u32; WRITE_QUEUE_WORDS],
+    /// Word index of the first word
+    first: usize,
+    len: usize,
+    written: usize,
+    pending: bool,
+    result: Option<Result<(), EepromError>>,
+    waker: Option<Waker>,
+}
+
+impl WriteQueue {
+    /// An empty queue
+    pub const fn new() -> Self {
+        WriteQueue {
+            words: [0; WRITE_QUEUE_WORDS],
+            first: 0,
+            len: 0,
+            written: 0,
+            pending: false,
+            result: None,
+            waker: None,
+        }
+    }
+
+    /// Queues `words`, to be written from word index `first`
+    pub fn load(&mut self, first: usize, words: &[u32]) {
+        self.words[..words.len()].copy_from_slice(words);
+        self.first = first;
+        self.len = words.len();
+        self.written = 0;
+        self.pending = true;
+        self.result = None;
+    }
+
+    /// The next word to write, and its word index
+    pub fn next_word(&mut self) -> Option<(usize, u32)> {
+        if self.written < self.len {
+            self.written += 1;
+            Some((self.first + self.written - 1, self.words[self.written - 1]))
+        } else {
+            None
+        }
+    }
+
+    /// Is a write in progress?
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Ends the write, returning the task to wake, if any
+    pub fn finish(&mut self, result: Result<(), EepromError>) -> Option<Waker> {
+        self.pending = false;
+        self.result = Some(result);
+        self.waker.take()
+    }
+
+    /// Takes the result of the last write, once it's finished
+    pub fn take_result(&mut self) -> Option<Result<(), EepromError>> {
+        self.result.take()
+    }
+
+    /// Wakes `waker` when the write finishes
+    pub fn register(&mut self, waker: &Waker) {
+        self.waker = Some(waker.clone());
+    }
+}
+
+impl Default for WriteQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Number of words in an EEPROM block
+pub const BLOCK_SIZE_WORDS: usize = 16;
+
+/// Size of the EEPROM word in bytes
+pub const BYTES_PER_WORD: usize = 4;
+
+/// The EEPROM's registers, as the [`Driver`] sees them. The chip crates
+/// implement this over the EEPROM peripheral, and [`sim::SimBackend`]
+/// simulates it in RAM.
+pub trait Backend {
+    /// The number of blocks and the number of words (EESIZE)
+    fn size(&self) -> (usize, usize);
+
+    /// Is the EEPROM working on something? (EEDONE)
+    fn is_busy(&self) -> bool;
+
+    /// Selects a block (EEBLOCK)
+    fn select_block(&self, block: usize);
+
+    /// Selects a word in the selected block (EEOFFSET)
+    fn select_offset(&self, offset: usize);
+
+    /// Reads the selected word (EERDWR)
+    fn read_word(&self) -> u32;
+
+    /// Starts writing the selected word (EERDWR)
+    fn write_word(&self, word: u32);
+
+    /// Was the last write refused, because the block can't be written?
+    /// (EEDONE)
+    fn write_refused(&self) -> bool;
+
+    /// Is the selected block locked? (EEUNLOCK)
+    fn is_locked(&self) -> bool;
+
+    /// The protection of the selected block (EEPROT)
+    fn protection(&self) -> Protection;
+
+    /// Has `block` been hidden? (EEHIDE)
+    fn is_hidden(&self, block: usize) -> bool;
+}
+
+/// The EEPROM logic - addressing, blocks, protection checks - over a
+/// [`Backend`] which does the register accesses. Each chip's `Eeprom` is one
+/// of these over the EEPROM peripheral; over a [`sim::SimBackend`] it can be
+/// tested on a host.
+pub struct Driver<B> {
+    /// Register access
+    backend: B,
+    /// Number of blocks, from EESIZE
+    num_blocks: usize,
+    /// Number of words, from EESIZE
+    num_words: usize,
+}
+
+impl<B: Backend> Driver<B> {
+    /// Creates a driver over `backend`. It has no blocks until
+    /// [`detect_size`](Driver::detect_size) is called, as the EEPROM must be
+    /// powered up before EESIZE can be read.
+    pub fn new(backend: B) -> Self {
+        Driver {
+            backend,
+            num_blocks: 0,
+            num_words: 0,
+        }
+    }
+
+    /// Returns the backend
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Reads the size of the EEPROM from the backend
+    pub fn detect_size(&mut self) {
+        let (blocks, words) = self.backend.size();
+        self.num_blocks = blocks;
+        self.num_words = words;
+    }
+
+    /// Forgets the size of the EEPROM, so that every access fails
+    pub fn forget_size(&mut self) {
+        self.num_blocks = 0;
+        self.num_words = 0;
+    }
+
+    /// Returns the number of blocks in the EEPROM
+    pub fn block_count(&self) -> usize {
+        self.num_blocks
+    }
+
+    /// Returns the size of the EEPROM, in bytes
+    pub fn capacity(&self) -> usize {
+        self.num_words * BYTES_PER_WORD
+    }
+
+    /// Set the block register
+    pub fn set_block(&self, block: usize) -> Result<(), EepromError> {
+        if block < self.num_blocks && self.backend.is_hidden(block) {
+            return Err(EepromError::BlockHidden);
+        }
+        self.select_block(block)?;
+        self.check_selected_block()
+    }
+
+    /// Checks that the selected block can be accessed at all. (If it can't be
+    /// written, the write fails with NOPERM.)
+    fn check_selected_block(&self) -> Result<(), EepromError> {
+        if self.backend.is_locked() && self.backend.protection() != Protection::ReadWrite {
+            Err(EepromError::AccessDenied)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks we may access `address`, which is selected, when it's the first
+    /// word of a block that an access has run on into
+    fn check_access(&self, address: &EepromAddress) -> Result<(), EepromError> {
+        if address.offset() != 0 {
+            return Ok(());
+        }
+        if self.backend.is_hidden(address.block()) {
+            return Err(EepromError::BlockHidden);
+        }
+        self.check_selected_block()
+    }
+
+    /// Set the block register, without checking whether we're allowed to
+    /// access it
+    pub fn select_block(&self, block: usize) -> Result<(), EepromError> {
+        if self.is_busy() {
+            return Err(EepromError::Busy);
+        }
+
+        if block < self.num_blocks {
+            self.backend.select_block(block);
+            self.wait();
+            Ok(())
+        } else {
+            Err(EepromError::BlockOutOfBounds)
+        }
+    }
+
+    /// Set the offset register
+    fn set_offset(&self, offset: usize) -> Result<(), EepromError> {
+        if self.is_busy() {
+            return Err(EepromError::Busy);
+        }
+
+        if offset < BLOCK_SIZE_WORDS {
+            self.backend.select_offset(offset);
+            self.wait();
+            Ok(())
+        } else {
+            Err(EepromError::OffsetOutOfBounds)
+        }
+    }
+
+    /// Set the block and offset registers
+    fn set_block_and_offset(&self, address: &EepromAddress) -> Result<(), EepromError> {
+        self.wait();
+        self.set_block(address.block())?;
+        self.set_offset(address.offset())?;
+        Ok(())
+    }
+
+    /// Checks if read / writing a certain number of bytes from an address is
+    /// valid. Returns true if EEPROM access is valid, false if there are any
+    /// issues (overflow or invalid address).
+    fn is_access_valid(&self, address: &EepromAddress, length_bytes: usize) -> bool {
+        // Check if the initial address is valid, then check byte length
+        match self.address_to_word_index(address) {
+            Ok(start_word_address) => {
+                start_word_address * BYTES_PER_WORD + length_bytes <= self.capacity()
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Increments the block and offset by 1 word. Will wrap both the offset
+    /// and block to 0 if an increment would cause either to exceed their
+    /// bounds.
+    ///
+    /// For example:
+    /// * Block 0, Offset, 1 -> Block 0, Offset 2
+    /// * Block 0, Offset, 15 -> Block 1, Offset 0
+    /// * Last block, Offset, 15 -> Block 0, Offset 0
+    fn increment_offset(
+        &mut self,
+        starting_address: &mut EepromAddress,
+    ) -> Result<(), EepromError> {
+        starting_address.increment(BLOCK_SIZE_WORDS, self.num_blocks);
+        // Whether we may access the new block is checked when we do
+        self.wait();
+        self.select_block(starting_address.block())?;
+        self.set_offset(starting_address.offset())?;
+        Ok(())
+    }
+
+    /// Writes the selected word, waits for the write to finish, and checks
+    /// that it was allowed
+    fn write_selected(&self, word: u32) -> Result<(), EepromError> {
+        self.backend.write_word(word);
+        self.finish_write()
+    }
+
+    /// Waits for a write to finish, and checks that it was allowed
+    pub fn finish_write(&self) -> Result<(), EepromError> {
+        self.wait();
+        if self.backend.write_refused() {
+            Err(EepromError::AccessDenied)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads the word with index `index`
+    pub fn read_word_at(&mut self, index: usize) -> Result<u32, EepromError> {
+        let address = self.word_index_to_address(index)?;
+        self.set_block_and_offset(&address)?;
+        self.wait();
+        Ok(self.backend.read_word())
+    }
+
+    /// Writes `word` to the word with index `index`
+    pub fn write_word_at(&mut self, index: usize, word: u32) -> Result<(), EepromError> {
+        let address = self.word_index_to_address(index)?;
+        self.set_block_and_offset(&address)?;
+        self.wait();
+        self.write_selected(word)
+    }
+
+    /// Checks that `length` bytes from byte `offset` fit in the EEPROM, and
+    /// returns `offset` as an index
+    pub fn check_range(&self, offset: u32, length: usize) -> Result<usize, EepromError> {
+        let offset = offset as usize;
+        match offset.checked_add(length) {
+            Some(end) if end <= self.capacity() => Ok(offset),
+            _ => Err(EepromError::AddressOutOfBounds),
+        }
+    }
+
+    /// Reads `bytes.len()` bytes from byte `offset`, which needn't be word
+    /// aligned
+    pub fn read_bytes(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), EepromError> {
+        let mut position = self.check_range(offset, bytes.len())?;
+        let mut done = 0;
+        while done < bytes.len() {
+            let skip = position % BYTES_PER_WORD;
+            let count = (BYTES_PER_WORD - skip).min(bytes.len() - done);
+            let word = self.read_word_at(position / BYTES_PER_WORD)?.to_le_bytes();
+            bytes[done..done + count].copy_from_slice(&word[skip..skip + count]);
+            position += count;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Writes `bytes` from byte `offset`, which needn't be word aligned.
+    /// Partial words are read, modified and written back.
+    pub fn write_bytes(&mut self, offset: u32, bytes: &[u8]) -> Result<(), EepromError> {
+        let mut position = self.check_range(offset, bytes.len())?;
+        let mut done = 0;
+        while done < bytes.len() {
+            let index = position / BYTES_PER_WORD;
+            let skip = position % BYTES_PER_WORD;
+            let count = (BYTES_PER_WORD - skip).min(bytes.len() - done);
+            let mut word = if count == BYTES_PER_WORD {
+                [0; BYTES_PER_WORD]
+            } else {
+                self.read_word_at(index)?.to_le_bytes()
+            };
+            word[skip..skip + count].copy_from_slice(&bytes[done..done + count]);
+            self.write_word_at(index, u32::from_le_bytes(word))?;
+            position += count;
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl<B: Backend> Busy for Driver<B> {
+    fn is_busy(&self) -> bool {
+        self.backend.is_busy()
+    }
+
+    fn wait(&self) {
+        while self.is_busy() {}
+    }
+}
+
+impl<B: Backend> Blocks for Driver<B> {
+    fn block_size(&self) -> Result<usize, EepromError> {
+        Ok(BLOCK_SIZE_WORDS)
+    }
+
+    fn word_index_to_address(&self, word_address: usize) -> Result<EepromAddress, EepromError> {
+        if word_address >= self.num_words {
+            Err(EepromError::AddressOutOfBounds)
+        } else {
+            let block = word_address / BLOCK_SIZE_WORDS;
+            let offset = word_address - (block * BLOCK_SIZE_WORDS);
+            Ok(EepromAddress::new(block, offset))
+        }
+    }
+
+    fn address_to_word_index(&self, block: &EepromAddress) -> Result<usize, EepromError> {
+        if block.block() >= self.num_blocks || block.offset() >= BLOCK_SIZE_WORDS {
+            Err(EepromError::BlockOutOfBounds)
+        } else {
+            Ok(block.block() * BLOCK_SIZE_WORDS + block.offset())
+        }
+    }
+}
+
+impl<B: Backend> Write for Driver<B> {
+    fn write(&mut self, address: &EepromAddress, data: &[u8]) -> Result<(), EepromError> {
+        if self.is_busy() {
+            return Err(EepromError::Busy);
+        }
+
+        // Check if the address is valid and if the data will fit
+        if !self.is_access_valid(address, data.len()) {
+            return Err(EepromError::WriteWouldOverflow);
+        }
+
+        self.set_block_and_offset(address)?;
+
+        let chunk_iter = data.chunks_exact(4);
+        let leftover_bytes = chunk_iter.remainder();
+        let mut address_copy = *address;
+
+        for chunk in chunk_iter {
+            let tmp = u32::from_le_bytes(core::convert::TryInto::try_into(chunk).unwrap());
+
+            self.wait();
+            self.check_access(&address_copy)?;
+            self.write_selected(tmp)?;
+
+            self.increment_offset(&mut address_copy)?;
+        }
+
+        // Buffer the leftover bytes, if any, and write
+        if !leftover_bytes.is_empty() {
+            let mut buffer = [0u8; 4];
+            buffer[..leftover_bytes.len()].copy_from_slice(leftover_bytes);
+
+            self.wait();
+            self.check_access(&address_copy)?;
+            self.write_selected(u32::from_le_bytes(buffer))?;
+        }
+
+        self.wait();
+
+        Ok(())
+    }
+}
+
+impl<B: Backend> Read for Driver<B> {
+    fn read(
+        &mut self,
+        address: &EepromAddress,
+        bytes_to_read: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), EepromError> {
+        if self.is_busy() {
+            return Err(EepromError::Busy);
+        }
+
+        if bytes_to_read > buffer.len() {
+            return Err(EepromError::ReadBufferTooSmall);
+        }
+
+        if !self.is_access_valid(address, bytes_to_read) {
+            return Err(EepromError::ReadWouldOverflow);
+        }
+
+        let num_words = bytes_to_read / BYTES_PER_WORD;
+        let leftover_bytes = bytes_to_read % BYTES_PER_WORD;
+        let mut address_copy = *address;
+
+        self.set_block_and_offset(address)?;
+
+        let mut byte_offset = 0;
+
+        for _i in 0..num_words {
+            self.wait();
+            self.check_access(&address_copy)?;
+
+            let word_as_bytes = self.backend.read_word().to_le_bytes();
+
+            self.increment_offset(&mut address_copy)?;
+
+            buffer[byte_offset..byte_offset + BYTES_PER_WORD].copy_from_slice(&word_as_bytes);
+            byte_offset += BYTES_PER_WORD;
+        }
+
+        if leftover_bytes != 0 {
+            self.wait();
+            self.check_access(&address_copy)?;
+
+            let word_as_bytes = self.backend.read_word().to_le_bytes();
+
+            self.increment_offset(&mut address_copy)?;
+
+            buffer[byte_offset..byte_offset + leftover_bytes]
+                .copy_from_slice(&word_as_bytes[..leftover_bytes]);
+        }
+
+        self.wait();
+
+        Ok(())
+    }
+}
+
+impl<B: Backend> Erase for Driver<B> {
+    fn erase(&mut self, address: &EepromAddress, length_bytes: usize) -> Result<(), EepromError> {
+        if self.is_busy() {
+            return Err(EepromError::Busy);
+        }
+
+        if !self.is_access_valid(address, length_bytes) {
+            return Err(EepromError::WriteWouldOverflow);
+        }
+
+        let num_words = length_bytes / BYTES_PER_WORD;
+        let leftover_bytes = length_bytes % BYTES_PER_WORD;
+        let mut address_copy = *address;
+
+        self.set_block_and_offset(address)?;
+
+        for _i in 0..num_words {
+            self.wait();
+            self.check_access(&address_copy)?;
+            self.write_selected(0)?;
+
+            self.increment_offset(&mut address_copy)?;
+        }
+
+        // Special case here, need to read-modify-write
+        if leftover_bytes != 0 {
+            self.wait();
+            self.check_access(&address_copy)?;
+
+            let mut word = self.backend.read_word().to_le_bytes();
+            for byte in &mut word[..leftover_bytes] {
+                *byte = 0;
+            }
+
+            self.write_selected(u32::from_le_bytes(word))?;
+        }
+
+        self.wait();
+
+        Ok(())
+    }
+
+    fn erase_block(&mut self, block: usize) -> Result<(), EepromError> {
+        if self.is_busy() {
+            return Err(EepromError::Busy);
+        }
+
+        self.set_block(block)?;
+
+        let address = EepromAddress::new(block, 0);
+
+        let zeros = [0u8; BLOCK_SIZE_WORDS * BYTES_PER_WORD];
+
+        self.write(&address, &zeros)?;
+
+        self.wait();
+
+        Ok(())
+    }
+}
+
+/// An internal macro to implement the EEPROM driver. The invoking module
+/// must have `EEPROM`, `FLASH_CTRL`, `Interrupt` and `sysctl` in scope. The `hide` registers are the
+/// EEHIDE registers, each covering 32 blocks.
+#[macro_export]
+macro_rules! eeprom_hal_macro {
+    (hide: [$($hide_index:literal: $eehide:ident),+]) => {
+        /// The EEPROM registers, as the driver's backend
+        struct Registers {
+            eeprom: EEPROM,
+        }
+
+        impl $crate::eeprom::Backend for Registers {
+            fn size(&self) -> (usize, usize) {
+                let size = self.eeprom.eesize.read().bits();
+                (((size >> 16) & 0x7FF) as usize, (size & 0xFFFF) as usize)
+            }
+
+            fn is_busy(&self) -> bool {
+                self.eeprom.eedone.read().working().bit_is_set() || Eeprom::write_pending()
+            }
+
+            fn select_block(&self, block: usize) {
+                unsafe {
+                    self.eeprom.eeblock.write(|w| w.bits(block as u32));
+                }
+                // Changing blocks requires a small delay, see Section 8.2.4.1 Timing Considerations
+                cortex_m::asm::delay(4);
+            }
+
+            fn select_offset(&self, offset: usize) {
+                unsafe {
+                    self.eeprom.eeoffset.write(|w| w.bits(offset as u32));
+                }
+            }
+
+            fn read_word(&self) -> u32 {
+                self.eeprom.eerdwr.read().bits()
+            }
+
+            fn write_word(&self, word: u32) {
+                unsafe {
+                    self.eeprom.eerdwr.write(|w| w.bits(word));
+                }
+            }
+
+            fn write_refused(&self) -> bool {
+                self.eeprom.eedone.read().noperm().bit_is_set()
+            }
+
+            fn is_locked(&self) -> bool {
+                self.eeprom.eeunlock.read().bits() & 1 == 0
+            }
+
+            fn protection(&self) -> $crate::eeprom::Protection {
+                $crate::eeprom::Protection::from_bits(self.eeprom.eeprot.read().bits())
+            }
+
+            fn is_hidden(&self, block: usize) -> bool {
+                let bits = match block / 32 {
+                    $($hide_index => self.eeprom.$eehide.read().bits(),)+
+                    _ => 0,
+                };
+                bits & (1 << (block % 32)) != 0
+            }
+        }
+
+        /// Eeprom struct
+        pub struct Eeprom {
+            /// The EEPROM logic, over the registers
+            driver: $crate::eeprom::Driver<Registers>,
+        }
+
+        impl Eeprom {
+            /// Configures a new EEPROM struct using the datasheet section 8.2.4.2.
+            ///
+            /// If the PRETRY or ERETRY bits are set in the EESUPP register, the EEPROM
+            /// was unable to recover its state. If power is stable when this occurs,
+            /// this indicates a fatal error and is likely an indication that the EEPROM
+            /// memory has exceeded its specified lifetime write/erase specification.
+            /// If the supply voltage is unstable when this return code is observed,
+            /// retrying the operation once the voltage is stabilized may clear the
+            /// error.
+            ///
+            /// So rather than failing outright, this returns the `Eeprom` in the
+            /// error, so you can [`recover`](Eeprom::recover) it, or as a last resort
+            /// [`mass_erase`](Eeprom::mass_erase) it.
+            pub fn new(
+                eeprom: EEPROM,
+                pc: &sysctl::PowerControl,
+            ) -> Result<Self, $crate::eeprom::InitError<Self>> {
+                let mut final_eeprom = Eeprom {
+                    driver: $crate::eeprom::Driver::new(Registers { eeprom }),
+                };
+
+                // See Section 8.2.4.2 EEPROM Initialization and Configuration
+                // in the datasheet:
+
+                // 0. Power on the EEPROM peripheral
+                sysctl::control_power(
+                    pc,
+                    sysctl::Domain::Eeprom,
+                    sysctl::RunMode::Run,
+                    sysctl::PowerState::On,
+                );
+
+                match final_eeprom.initialise(pc) {
+                    Ok(()) => Ok(final_eeprom),
+                    Err(error) => Err($crate::eeprom::InitError {
+                        error,
+                        eeprom: final_eeprom,
+                    }),
+                }
+            }
+
+            /// Steps 1 to 8 of the initialisation, once the EEPROM is powered
+            fn initialise(&mut self, pc: &sysctl::PowerControl) -> Result<(), EepromError> {
+                // Until we know the EEPROM is healthy, don't allow any access
+                self.driver.forget_size();
+
+                // 1. The datasheet calls for at least a 6 cycle delay before polling
+                // the working register. Need to make sure the loop isn't optimized
+                // out.
+                cortex_m::asm::delay(20);
+
+                // 2. Poll busy
+                self.wait();
+
+                // 3. Read PRETRY and ERETRY
+                // Note: If either bit is set, the data sheet indicates this is a pretty severe
+                // error with the EEPROM. There could be issues with the chip, core voltage,
+                // or EEPROM; regardless, it probably should be investigated further.
+                // See section 8.2.4.2
+                self.support_status().check()?;
+
+                // 4. Software reset
+                sysctl::reset(pc, sysctl::Domain::Eeprom);
+
+                // 5. Another delay
+                cortex_m::asm::delay(20);
+
+                // 6. Poll busy
+                self.wait();
+
+                // 7. Recheck PRETRY and ERETRY
+                self.support_status().check()?;
+
+                // 8. The size depends on the part, so ask the EEPROM
+                self.driver.detect_size();
+
+                Ok(())
+            }
+
+            /// Returns the EEPROM's recovery status (the EESUPP register)
+            pub fn support_status(&self) -> $crate::eeprom::SupportStatus {
+                $crate::eeprom::SupportStatus::from_bits(self.regs().eesupp.read().bits())
+            }
+
+            /// Runs the initialisation again, up to `attempts` times, for
+            /// when `new` failed. This may work once the supply voltage has
+            /// stabilised.
+            pub fn recover(
+                &mut self,
+                pc: &sysctl::PowerControl,
+                attempts: usize,
+            ) -> Result<(), EepromError> {
+                let mut result = self.initialise(pc);
+                for _ in 1..attempts {
+                    if result.is_ok() {
+                        break;
+                    }
+                    result = self.initialise(pc);
+                }
+                result
+            }
+
+            /// Erases the whole EEPROM, including all passwords and
+            /// protection settings, and initialises it again. This is the
+            /// last resort if [`recover`](Eeprom::recover) fails.
+            ///
+            /// If the EEPROM is locked with a password, it must be unlocked
+            /// first.
+            pub fn mass_erase(&mut self, pc: &sysctl::PowerControl) -> Result<(), EepromError> {
+                if Self::write_pending() {
+                    return Err(EepromError::Busy);
+                }
+                self.wait();
+                unsafe {
+                    self.regs()
+                        .eedbgme
+                        .write(|w| w.bits($crate::eeprom::MASS_ERASE_KEY | 1));
+                }
+                self.driver.finish_write()?;
+                self.initialise(pc)
+            }
+
+            /// Returns the number of blocks in the EEPROM
+            pub fn block_count(&self) -> usize {
+                self.driver.block_count()
+            }
+
+            /// Returns the size of the EEPROM, in bytes
+            pub fn capacity(&self) -> usize {
+                self.driver.capacity()
+            }
+
+            /// The EEPROM registers
+            fn regs(&self) -> &EEPROM {
+                &self.driver.backend().eeprom
+            }
+        }
+
+        impl Busy for Eeprom {
+            fn is_busy(&self) -> bool {
+                self.driver.is_busy()
+            }
+
+            fn wait(&self) {
+                self.driver.wait()
+            }
+        }
+
+        impl Blocks for Eeprom {
+            fn block_size(&self) -> Result<usize, EepromError> {
+                self.driver.block_size()
+            }
+
+            fn word_index_to_address(&self, word_address: usize) -> Result<EepromAddress, EepromError> {
+                self.driver.word_index_to_address(word_address)
+            }
+
+            fn address_to_word_index(&self, block: &EepromAddress) -> Result<usize, EepromError> {
+                self.driver.address_to_word_index(block)
+            }
+        }
+
+        impl Write for Eeprom {
+            fn write(&mut self, address: &EepromAddress, data: &[u8]) -> Result<(), EepromError> {
+                Write::write(&mut self.driver, address, data)
+            }
+        }
+
+        impl Read for Eeprom {
+            fn read(
+                &mut self,
+                address: &EepromAddress,
+                bytes_to_read: usize,
+                buffer: &mut [u8],
+            ) -> Result<(), EepromError> {
+                Read::read(&mut self.driver, address, bytes_to_read, buffer)
+            }
+        }
+
+        impl Erase for Eeprom {
+            fn erase(&mut self, address: &EepromAddress, length_bytes: usize) -> Result<(), EepromError> {
+                self.driver.erase(address, length_bytes)
+            }
+
+            fn erase_block(&mut self, block: usize) -> Result<(), EepromError> {
+                self.driver.erase_block(block)
+            }
+        }
+
+        impl Eeprom {
+            /// Returns the protection level of `block`, and whether it can
+            /// only be accessed in supervisor (privileged) mode
+            pub fn protection(
+                &self,
+                block: usize,
+            ) -> Result<($crate::eeprom::Protection, bool), EepromError> {
+                self.driver.select_block(block)?;
+                let bits = self.regs().eeprot.read().bits();
+                Ok(($crate::eeprom::Protection::from_bits(bits), bits & (1 << 3) != 0))
+            }
+
+            /// Sets the protection level of `block`. If `supervisor_only`
+            /// is set, it can only be accessed in supervisor (privileged)
+            /// mode.
+            ///
+            /// If the block has a password, it must be unlocked first.
+            pub fn set_protection(
+                &mut self,
+                block: usize,
+                protection: $crate::eeprom::Protection,
+                supervisor_only: bool,
+            ) -> Result<(), EepromError> {
+                self.driver.select_block(block)?;
+                let bits = protection.bits() | if supervisor_only { 1 << 3 } else { 0 };
+                unsafe {
+                    self.regs().eeprot.write(|w| w.bits(bits));
+                }
+                self.driver.finish_write()
+            }
+
+            /// Sets the password for `block`, which is one to three words
+            /// long. Block 0's password covers the whole EEPROM. The block
+            /// is left unlocked until the next reset or [`Eeprom::lock`].
+            ///
+            /// A password can only be set once, and only a mass erase
+            /// removes it.
+            pub fn set_password(&mut self, block: usize, password: &[u32]) -> Result<(), EepromError> {
+                if password.is_empty() || password.len() > 3 {
+                    return Err(EepromError::InvalidPassword);
+                }
+                self.driver.select_block(block)?;
+                for (index, &word) in password.iter().enumerate() {
+                    unsafe {
+                        match index {
+                            0 => self.regs().eepass0.write(|w| w.bits(word)),
+                            1 => self.regs().eepass1.write(|w| w.bits(word)),
+                            _ => self.regs().eepass2.write(|w| w.bits(word)),
+                        }
+                    }
+                    self.driver.finish_write()?;
+                }
+                Ok(())
+            }
+
+            /// Unlocks `block` with `password`, which must be the whole
+            /// password it was given. Unlocking block 0 unlocks the whole
+            /// EEPROM.
+            pub fn unlock(&mut self, block: usize, password: &[u32]) -> Result<(), EepromError> {
+                if password.is_empty() || password.len() > 3 {
+                    return Err(EepromError::InvalidPassword);
+                }
+                self.driver.select_block(block)?;
+                // Lock first, so a half-entered password from before doesn't
+                // get in the way. The words then go in last first.
+                unsafe {
+                    self.regs().eeunlock.write(|w| w.bits(0xFFFF_FFFF));
+                }
+                for &word in password.iter().rev() {
+                    unsafe {
+                        self.regs().eeunlock.write(|w| w.bits(word));
+                    }
+                }
+                if $crate::eeprom::Backend::is_locked(self.driver.backend()) {
+                    Err(EepromError::WrongPassword)
+                } else {
+                    Ok(())
+                }
+            }
+
+            /// Locks `block` again. Locking block 0 locks the whole EEPROM.
+            pub fn lock(&mut self, block: usize) -> Result<(), EepromError> {
+                self.driver.select_block(block)?;
+                unsafe {
+                    self.regs().eeunlock.write(|w| w.bits(0xFFFF_FFFF));
+                }
+                Ok(())
+            }
+
+            /// Is `block` locked? A block without a password is never
+            /// locked.
+            pub fn is_locked(&self, block: usize) -> Result<bool, EepromError> {
+                self.driver.select_block(block)?;
+                Ok($crate::eeprom::Backend::is_locked(self.driver.backend()))
+            }
+
+            /// Hides `block` until the next reset, after which any access to
+            /// it fails with `BlockHidden`. Block 0 can't be hidden.
+            pub fn hide(&mut self, block: usize) -> Result<(), EepromError> {
+                if block == 0 || block >= self.block_count() {
+                    return Err(EepromError::BlockOutOfBounds);
+                }
+                let bit = 1 << (block % 32);
+                match block / 32 {
+                    $(
+                        $hide_index => self.regs().$eehide.modify(|r, w| unsafe {
+                            w.bits(r.bits() | bit)
+                        }),
+                    )+
+                    _ => return Err(EepromError::BlockOutOfBounds),
+                }
+                Ok(())
+            }
+
+            /// Has `block` been hidden?
+            pub fn is_hidden(&self, block: usize) -> bool {
+                $crate::eeprom::Backend::is_hidden(self.driver.backend(), block)
+            }
+        }
+
+        impl Eeprom {
+            /// The EEPROM interrupt's bit in the flash controller's interrupt
+            /// registers (EMASK, EMISC)
+            const FLASH_EEPROM_INTERRUPT: u32 = 1 << 2;
+
+            /// The background write, shared with the interrupt handler
+            fn write_queue(
+            ) -> &'static cortex_m::interrupt::Mutex<core::cell::RefCell<$crate::eeprom::WriteQueue>>
+            {
+                static QUEUE: cortex_m::interrupt::Mutex<core::cell::RefCell<$crate::eeprom::WriteQueue>> =
+                    cortex_m::interrupt::Mutex::new(core::cell::RefCell::new(
+                        $crate::eeprom::WriteQueue::new(),
+                    ));
+                &QUEUE
+            }
+
+            /// Is a background write in progress?
+            fn write_pending() -> bool {
+                cortex_m::interrupt::free(|cs| Self::write_queue().borrow(cs).borrow().is_pending())
+            }
+
+            /// Starts writing `data` from byte `offset` in the background,
+            /// one word per EEPROM done interrupt. `data` is copied, so it
+            /// can be at most [`WRITE_QUEUE_WORDS`]($crate::eeprom::WRITE_QUEUE_WORDS)
+            /// words long (allowing for partial words at either end).
+            ///
+            /// The EEPROM is busy until the write finishes; check with
+            /// `write_status`. Only one background write can be in
+            /// progress at a time.
+            pub fn start_write(&mut self, offset: u32, data: &[u8]) -> Result<(), EepromError> {
+                const WORD: usize = $crate::eeprom::BYTES_PER_WORD;
+
+                if self.is_busy() {
+                    return Err(EepromError::Busy);
+                }
+                let start = self.driver.check_range(offset, data.len())?;
+                if data.is_empty() {
+                    return Ok(());
+                }
+                let first = start / WORD;
+                let last = (start + data.len() - 1) / WORD;
+                let count = last - first + 1;
+                if count > $crate::eeprom::WRITE_QUEUE_WORDS {
+                    return Err(EepromError::WriteTooLong);
+                }
+
+                // Check every block we'll touch now, as the interrupt handler
+                // can only report NOPERM
+                for block in first / $crate::eeprom::BLOCK_SIZE_WORDS
+                    ..=last / $crate::eeprom::BLOCK_SIZE_WORDS
+                {
+                    self.driver.set_block(block)?;
+                }
+
+                let mut words = [0; $crate::eeprom::WRITE_QUEUE_WORDS];
+                if start % WORD != 0 {
+                    words[0] = self.driver.read_word_at(first)?;
+                }
+                if (start + data.len()) % WORD != 0 {
+                    words[count - 1] = self.driver.read_word_at(last)?;
+                }
+                for (i, &byte) in data.iter().enumerate() {
+                    let position = start + i - first * WORD;
+                    let shift = (position % WORD) * 8;
+                    let word = &mut words[position / WORD];
+                    *word = (*word & !(0xFF << shift)) | (u32::from(byte) << shift);
+                }
+
+                // We only touch the EEPROM interrupt's bit in the flash
+                // controller, so this is safe.
+                let flash = unsafe { &*FLASH_CTRL::ptr() };
+                cortex_m::interrupt::free(|cs| {
+                    Self::write_queue().borrow(cs).borrow_mut().load(first, &words[..count]);
+                    unsafe {
+                        self.regs().eeint.write(|w| w.bits(1));
+                        flash
+                            .fcim
+                            .modify(|r, w| w.bits(r.bits() | Self::FLASH_EEPROM_INTERRUPT));
+                    }
+                    Self::write_next(cs);
+                });
+                unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::FLASH) };
+                Ok(())
+            }
+
+            /// Checks on the background write: `WouldBlock` while it's in
+            /// progress, then its result (once).
+            pub fn write_status(&mut self) -> nb::Result<(), EepromError> {
+                cortex_m::interrupt::free(|cs| {
+                    let mut queue = Self::write_queue().borrow(cs).borrow_mut();
+                    if queue.is_pending() {
+                        Err(nb::Error::WouldBlock)
+                    } else {
+                        queue.take_result().unwrap_or(Ok(())).map_err(nb::Error::Other)
+                    }
+                })
+            }
+
+            /// Writes `data` from byte `offset`, sleeping on the EEPROM done
+            /// interrupt between words
+            async fn write_in_background(&mut self, offset: u32, data: &[u8]) -> Result<(), EepromError> {
+                const WORD: usize = $crate::eeprom::BYTES_PER_WORD;
+                let mut done = 0;
+                while done < data.len() {
+                    let at = offset as usize + done;
+                    // As much as fits in the queue, allowing for where in a
+                    // word we start
+                    let room = $crate::eeprom::WRITE_QUEUE_WORDS * WORD - at % WORD;
+                    let count = room.min(data.len() - done);
+                    self.start_write(at as u32, &data[done..done + count])?;
+                    core::future::poll_fn(|cx| {
+                        cortex_m::interrupt::free(|cs| {
+                            let mut queue = Self::write_queue().borrow(cs).borrow_mut();
+                            if queue.is_pending() {
+                                queue.register(cx.waker());
+                                core::task::Poll::Pending
+                            } else {
+                                core::task::Poll::Ready(queue.take_result().unwrap_or(Ok(())))
+                            }
+                        })
+                    })
+                    .await?;
+                    done += count;
+                }
+                Ok(())
+            }
+
+            /// Starts writing the next queued word, if there is one. Returns
+            /// `false` once they've all been written.
+            fn write_next(cs: &cortex_m::interrupt::CriticalSection) -> bool {
+                // A write is pending, so nothing else is using the EEPROM
+                let eeprom = unsafe { &*EEPROM::ptr() };
+                let next = Self::write_queue().borrow(cs).borrow_mut().next_word();
+                match next {
+                    Some((index, word)) => {
+                        let block = index / $crate::eeprom::BLOCK_SIZE_WORDS;
+                        let offset = index % $crate::eeprom::BLOCK_SIZE_WORDS;
+                        unsafe {
+                            eeprom.eeblock.write(|w| w.bits(block as u32));
+                        }
+                        // See Section 8.2.4.1 Timing Considerations
+                        cortex_m::asm::delay(4);
+                        while eeprom.eedone.read().working().bit_is_set() {}
+                        unsafe {
+                            eeprom.eeoffset.write(|w| w.bits(offset as u32));
+                            eeprom.eerdwr.write(|w| w.bits(word));
+                        }
+                        true
+                    }
+                    None => false,
+                }
+            }
+
+            /// Moves the background write on. Call this from the `FLASH`
+            /// interrupt handler.
+            pub fn on_interrupt() {
+                // Write-one-to-clear, so this is safe
+                let flash = unsafe { &*FLASH_CTRL::ptr() };
+                flash
+                    .fcmisc
+                    .write(|w| unsafe { w.bits(Self::FLASH_EEPROM_INTERRUPT) });
+
+                // A write is pending, so nothing else is using the EEPROM
+                let eeprom = unsafe { &*EEPROM::ptr() };
+                let waker = cortex_m::interrupt::free(|cs| {
+                    if !Self::write_queue().borrow(cs).borrow().is_pending()
+                        || eeprom.eedone.read().working().bit_is_set()
+                    {
+                        return None;
+                    }
+                    let result = if eeprom.eedone.read().noperm().bit_is_set() {
+                        Err(EepromError::AccessDenied)
+                    } else if Self::write_next(cs) {
+                        return None;
+                    } else {
+                        Ok(())
+                    };
+                    unsafe {
+                        eeprom.eeint.write(|w| w.bits(0));
+                    }
+                    Self::write_queue().borrow(cs).borrow_mut().finish(result)
+                });
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+        }
+
+        impl $crate::eeprom::embedded_storage::ReadStorage for Eeprom {
+            type Error = EepromError;
+
+            fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), EepromError> {
+                self.driver.read_bytes(offset, bytes)
+            }
+
+            fn capacity(&self) -> usize {
+                Eeprom::capacity(self)
+            }
+        }
+
+        impl $crate::eeprom::embedded_storage::Storage for Eeprom {
+            fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), EepromError> {
+                self.driver.write_bytes(offset, bytes)
+            }
+        }
+
+        impl $crate::eeprom::embedded_storage::nor_flash::ErrorType for Eeprom {
+            type Error = EepromError;
+        }
+
+        impl $crate::eeprom::embedded_storage::nor_flash::ReadNorFlash for Eeprom {
+            const READ_SIZE: usize = 1;
+
+            fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), EepromError> {
+                $crate::eeprom::embedded_storage::nor_flash::check_read(self, offset, bytes.len())?;
+                self.driver.read_bytes(offset, bytes)
+            }
+
+            fn capacity(&self) -> usize {
+                Eeprom::capacity(self)
+            }
+        }
+
+        impl $crate::eeprom::embedded_storage::nor_flash::NorFlash for Eeprom {
+            const WRITE_SIZE: usize = $crate::eeprom::BYTES_PER_WORD;
+            const ERASE_SIZE: usize =
+                $crate::eeprom::BLOCK_SIZE_WORDS * $crate::eeprom::BYTES_PER_WORD;
+
+            fn erase(&mut self, from: u32, to: u32) -> Result<(), EepromError> {
+                $crate::eeprom::embedded_storage::nor_flash::check_erase(self, from, to)?;
+                let first = from as usize / $crate::eeprom::BYTES_PER_WORD;
+                let last = to as usize / $crate::eeprom::BYTES_PER_WORD;
+                for index in first..last {
+                    self.driver.write_word_at(index, 0xFFFF_FFFF)?;
+                }
+                Ok(())
+            }
+
+            fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), EepromError> {
+                $crate::eeprom::embedded_storage::nor_flash::check_write(self, offset, bytes.len())?;
+                self.driver.write_bytes(offset, bytes)
+            }
+        }
+
+        /// Any word can be rewritten at any time, without erasing it first
+        impl $crate::eeprom::embedded_storage::nor_flash::MultiwriteNorFlash for Eeprom {}
+
+        impl $crate::eeprom::embedded_storage_async::nor_flash::ReadNorFlash for Eeprom {
+            const READ_SIZE: usize = 1;
+
+            async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), EepromError> {
+                $crate::eeprom::embedded_storage::nor_flash::ReadNorFlash::read(self, offset, bytes)
+            }
+
+            fn capacity(&self) -> usize {
+                Eeprom::capacity(self)
+            }
+        }
+
+        impl $crate::eeprom::embedded_storage_async::nor_flash::NorFlash for Eeprom {
+            const WRITE_SIZE: usize = $crate::eeprom::BYTES_PER_WORD;
+            const ERASE_SIZE: usize =
+                $crate::eeprom::BLOCK_SIZE_WORDS * $crate::eeprom::BYTES_PER_WORD;
+
+            async fn erase(&mut self, from: u32, to: u32) -> Result<(), EepromError> {
+                $crate::eeprom::embedded_storage::nor_flash::check_erase(self, from, to)?;
+                const ERASED: [u8; $crate::eeprom::WRITE_QUEUE_WORDS * $crate::eeprom::BYTES_PER_WORD] =
+                    [0xFF; $crate::eeprom::WRITE_QUEUE_WORDS * $crate::eeprom::BYTES_PER_WORD];
+                let mut at = from as usize;
+                while at < to as usize {
+                    let count = ERASED.len().min(to as usize - at);
+                    self.write_in_background(at as u32, &ERASED[..count]).await?;
+                    at += count;
+                }
+                Ok(())
+            }
+
+            async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), EepromError> {
+                $crate::eeprom::embedded_storage::nor_flash::check_write(self, offset, bytes.len())?;
+                self.write_in_background(offset, bytes).await
+            }
+        }
+
+        impl $crate::eeprom::embedded_storage_async::nor_flash::MultiwriteNorFlash for Eeprom {}
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sim::SimBackend;
+    use super::*;
+
+    /// A driver over a simulated EEPROM of four blocks
+    fn driver() -> Driver<SimBackend<4>> {
+        let mut driver = Driver::new(SimBackend::new());
+        driver.detect_size();
+        driver
+    }
+
+    #[test]
+    fn increment_wraps() {
+        let mut address = EepromAddress::new(0, 14);
+        address.increment(16, 4);
+        assert_eq!((address.block(), address.offset()), (0, 15));
+        address.increment(16, 4);
+        assert_eq!((address.block(), address.offset()), (1, 0));
+
+        let mut address = EepromAddress::new(3, 15);
+        address.increment(16, 4);
+        assert_eq!((address.block(), address.offset()), (0, 0));
+    }
+
+    #[test]
+    fn addresses() {
+        let driver = driver();
+        let address = driver.word_index_to_address(17).unwrap();
+        assert_eq!((address.block(), address.offset()), (1, 1));
+        assert_eq!(driver.address_to_word_index(&address), Ok(17));
+        assert_eq!(driver.word_index_to_address(63).unwrap().block(), 3);
+
+        assert_eq!(
+            driver.word_index_to_address(64).err(),
+            Some(EepromError::AddressOutOfBounds)
+        );
+        assert_eq!(
+            driver.address_to_word_index(&EepromAddress::new(4, 0)),
+            Err(EepromError::BlockOutOfBounds)
+        );
+        assert_eq!(
+            driver.address_to_word_index(&EepromAddress::new(0, 16)),
+            Err(EepromError::BlockOutOfBounds)
+        );
+    }
+
+    #[test]
+    fn write_and_read_across_blocks() {
+        let mut driver = driver();
+        let data = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let address = EepromAddress::new(1, 15);
+        Write::write(&mut driver, &address, &data).unwrap();
+
+        assert_eq!(driver.backend().word(31), 0x0403_0201);
+        assert_eq!(driver.backend().word(32), 0x0807_0605);
+        // The leftover bytes are padded with zeros
+        assert_eq!(driver.backend().word(33), 0x0000_0A09);
+        assert_eq!(driver.backend().word(34), 0xFFFF_FFFF);
+
+        let mut buffer = [0; 10];
+        Read::read(&mut driver, &address, 10, &mut buffer).unwrap();
+        assert_eq!(buffer, data);
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let mut driver = driver();
+        let last = EepromAddress::new(3, 15);
+        let mut buffer = [0; 8];
+
+        assert_eq!(Write::write(&mut driver, &last, &[0; 4]), Ok(()));
+        assert_eq!(
+            Write::write(&mut driver, &last, &[0; 5]),
+            Err(EepromError::WriteWouldOverflow)
+        );
+        assert_eq!(
+            Read::read(&mut driver, &last, 8, &mut buffer),
+            Err(EepromError::ReadWouldOverflow)
+        );
+        assert_eq!(
+            Read::read(&mut driver, &EepromAddress::new(4, 0), 4, &mut buffer),
+            Err(EepromError::ReadWouldOverflow)
+        );
+        assert_eq!(
+            Read::read(&mut driver, &last, 9, &mut buffer),
+            Err(EepromError::ReadBufferTooSmall)
+        );
+        assert_eq!(driver.erase(&last, 5), Err(EepromError::WriteWouldOverflow));
+        assert_eq!(driver.erase_block(4), Err(EepromError::BlockOutOfBounds));
+        assert_eq!(
+            driver.read_bytes(255, &mut buffer[..2]),
+            Err(EepromError::AddressOutOfBounds)
+        );
+    }
+
+    #[test]
+    fn erase() {
+        let mut driver = driver();
+        // Zeroes the first word and the first two bytes of the second
+        driver.erase(&EepromAddress::new(0, 0), 6).unwrap();
+        assert_eq!(driver.backend().word(0), 0);
+        assert_eq!(driver.backend().word(1), 0xFFFF_0000);
+        assert_eq!(driver.backend().word(2), 0xFFFF_FFFF);
+
+        driver.erase_block(2).unwrap();
+        assert!((32..48).all(|index| driver.backend().word(index) == 0));
+        assert_eq!(driver.backend().word(31), 0xFFFF_FFFF);
+        assert_eq!(driver.backend().word(48), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn unaligned_bytes() {
+        let mut driver = driver();
+        driver.write_bytes(62, &[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(driver.backend().word(15), 0x0201_FFFF);
+        assert_eq!(driver.backend().word(16), 0xFF05_0403);
+
+        let mut buffer = [0; 3];
+        driver.read_bytes(63, &mut buffer).unwrap();
+        assert_eq!(buffer, [2, 3, 4]);
+    }
+
+    #[test]
+    fn protection() {
+        let mut driver = driver();
+        driver.backend().hide(2);
+        driver.backend().protect(3, Protection::ReadOnly, false);
+        driver
+            .backend()
+            .protect(1, Protection::ReadWriteWhenUnlocked, true);
+
+        let mut buffer = [0; 8];
+        assert_eq!(
+            Read::read(&mut driver, &EepromAddress::new(2, 0), 4, &mut buffer),
+            Err(EepromError::BlockHidden)
+        );
+        assert_eq!(
+            Read::read(&mut driver, &EepromAddress::new(1, 0), 4, &mut buffer),
+            Err(EepromError::AccessDenied)
+        );
+        // Running on into a locked block
+        assert_eq!(
+            Read::read(&mut driver, &EepromAddress::new(0, 15), 8, &mut buffer),
+            Err(EepromError::AccessDenied)
+        );
+        // Running on into a hidden block
+        driver.backend().protect(1, Protection::ReadWrite, false);
+        assert_eq!(
+            Read::read(&mut driver, &EepromAddress::new(1, 15), 8, &mut buffer),
+            Err(EepromError::BlockHidden)
+        );
+
+        // A read-only block can be read, but writes are refused
+        assert_eq!(
+            Read::read(&mut driver, &EepromAddress::new(3, 0), 8, &mut buffer),
+            Ok(())
+        );
+        assert_eq!(
+            Write::write(&mut driver, &EepromAddress::new(3, 0), &[0; 4]),
+            Err(EepromError::AccessDenied)
+        );
+        assert_eq!(driver.backend().word(48), 0xFFFF_FFFF);
+    }
+}
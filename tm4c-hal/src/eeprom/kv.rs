@@ -0,0 +1,822 @@
+//! A power-fail-safe key-value store, for configuration and the like.
+//!
+//! The store works on any [`Storage`] which can rewrite a word in place, such
+//! as the on-chip `Eeprom`, and lives in a word-aligned region of it. Every
+//! change is appended to a log as a CRC-protected record, and a record only
+//! counts once its CRC (the last word written) is in place, so a reset in the
+//! middle of `set` or `remove` leaves the previous value in force.
+//!
+//! ```ignore
+//! let mut store = KvStore::new(eeprom, 0, 1024)?;
+//! store.set(b"baud", &115_200u32.to_le_bytes())?;
+//! let mut buffer = [0; 4];
+//! if let Some(len) = store.get(b"baud", &mut buffer)? {
+//!     // ...
+//! }
+//! ```
+//!
+//! # Format
+//!
+//! The region is split into two equal banks. Only one is active; the other
+//! is used by compaction. All values are little-endian and all records are
+//! word aligned.
+//!
+//! A bank starts with a 12 byte header:
+//!
+//! | Offset | Size | Contents                                   |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 4    | Magic number `0x3156_4B54` (`"TKV1"`)      |
+//! | 4      | 4    | Generation, one more than the last bank's  |
+//! | 8      | 4    | CRC-32 of bytes 0 to 7                     |
+//!
+//! The bank with a valid header and the newer generation is active. The log
+//! of records follows the header, up to the first word which isn't the start
+//! of a valid record (normally `0xFFFF_FFFF`, which is written after each
+//! record). A record is:
+//!
+//! | Offset        | Size      | Contents                              |
+//! |---------------|-----------|---------------------------------------|
+//! | 0             | 1         | Key length, 1 to [`MAX_KEY_LEN`]      |
+//! | 1             | 1         | Flags: `0x00`, or `0x01` for removed  |
+//! | 2             | 2         | Value length                          |
+//! | 4             | key len   | Key                                   |
+//! | 4 + key len   | value len | Value                                 |
+//! | ...           | 0 to 3    | Padding (`0xFF`) to a word boundary   |
+//! | ...           | 4         | CRC-32 of everything before padding   |
+//!
+//! The last record for a key holds its value. When the active bank is full,
+//! [`KvStore::compact`] copies just those records into the other bank, and
+//! then writes its header (magic number last), which switches banks in a
+//! single word write.
+//!
+//! The CRC is the standard CRC-32 (as used by Ethernet and zlib).
+
+use core::fmt;
+
+use embedded_storage::Storage;
+
+use crate::crc::crc32_update;
+
+/// The longest key, in bytes
+pub const MAX_KEY_LEN: usize = 32;
+
+/// Marks a valid bank header
+const MAGIC: u32 = 0x3156_4B54;
+
+/// The size of a bank header, in bytes
+const BANK_HEADER_LEN: u32 = 12;
+
+/// What an erased (or never written) word reads as, which ends the log
+const END: u32 = 0xFFFF_FFFF;
+
+/// The record flag for a removed key
+const FLAG_REMOVED: u8 = 0x01;
+
+/// How much we read or copy at once
+const CHUNK: usize = 16;
+
+/// Errors from the key-value store
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    /// The underlying storage returned an error
+    Storage(E),
+    /// The region isn't word aligned, is too small, or doesn't fit in the
+    /// storage
+    BadRegion,
+    /// The key is empty, or longer than [`MAX_KEY_LEN`]
+    BadKey,
+    /// The value is too long to ever fit in a bank
+    ValueTooLong,
+    /// The value doesn't fit in the buffer; it needs this many bytes
+    BufferTooSmall(usize),
+    /// There is no room for the record, even after compaction
+    Full,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Storage(e) => write!(f, "storage error: {:?}", e),
+            Error::BadRegion => write!(f, "region is unaligned or out of bounds"),
+            Error::BadKey => write!(f, "key is empty or too long"),
+            Error::ValueTooLong => write!(f, "value is too long"),
+            Error::BufferTooSmall(len) => write!(f, "buffer too small, need {} bytes", len),
+            Error::Full => write!(f, "store is full"),
+        }
+    }
+}
+
+/// The total size of a record, including padding and CRC
+fn record_len(key_len: usize, value_len: usize) -> u32 {
+    let unpadded = 4 + key_len + value_len;
+    (unpadded.div_ceil(4) * 4 + 4) as u32
+}
+
+/// Is generation `a` newer than generation `b`, allowing for wrapping?
+fn is_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// A record found in the active bank
+#[derive(Clone, Copy)]
+struct Record {
+    /// Offset of the record within the bank
+    position: u32,
+    key_len: u8,
+    flags: u8,
+    value_len: u16,
+}
+
+impl Record {
+    fn len(&self) -> u32 {
+        record_len(usize::from(self.key_len), usize::from(self.value_len))
+    }
+
+    fn is_removed(&self) -> bool {
+        self.flags & FLAG_REMOVED != 0
+    }
+}
+
+/// A key-value store over a region of `S`
+pub struct KvStore<S> {
+    storage: S,
+    start: u32,
+    bank_len: u32,
+    active: u32,
+    generation: u32,
+    /// Offset of the end of the log within the active bank
+    end: u32,
+}
+
+impl<S> KvStore<S>
+where
+    S: Storage,
+{
+    /// Opens the store in the `len` bytes of `storage` from byte `start`,
+    /// formatting it if there isn't a valid store there already.
+    ///
+    /// `start` and `len` must be multiples of 8 bytes.
+    pub fn new(storage: S, start: u32, len: u32) -> Result<Self, Error<S::Error>> {
+        if !start.is_multiple_of(8)
+            || !len.is_multiple_of(8)
+            || len / 2 < BANK_HEADER_LEN + 8
+            || u64::from(start) + u64::from(len) > storage.capacity() as u64
+        {
+            return Err(Error::BadRegion);
+        }
+
+        let mut store = KvStore {
+            storage,
+            start,
+            bank_len: len / 2,
+            active: 0,
+            generation: 0,
+            end: BANK_HEADER_LEN,
+        };
+
+        match (store.bank_generation(0)?, store.bank_generation(1)?) {
+            (Some(a), Some(b)) if is_newer(b, a) => store.open_bank(1, b)?,
+            (Some(a), _) => store.open_bank(0, a)?,
+            (None, Some(b)) => store.open_bank(1, b)?,
+            (None, None) => store.format()?,
+        }
+        Ok(store)
+    }
+
+    /// Releases the storage
+    pub fn free(self) -> S {
+        self.storage
+    }
+
+    /// Throws away every key and starts again with an empty store
+    pub fn format(&mut self) -> Result<(), Error<S::Error>> {
+        self.write_word(self.bank_base(1), END)?;
+        self.write_bank(0, 1, BANK_HEADER_LEN)?;
+        self.open_bank(0, 1)
+    }
+
+    /// Looks up `key`, copying its value into `buffer` and returning its
+    /// length, or `None` if it isn't in the store
+    pub fn get(&mut self, key: &[u8], buffer: &mut [u8]) -> Result<Option<usize>, Error<S::Error>> {
+        check_key(key)?;
+        match self.find(key)? {
+            Some(record) if !record.is_removed() => {
+                let len = usize::from(record.value_len);
+                if len > buffer.len() {
+                    return Err(Error::BufferTooSmall(len));
+                }
+                let at =
+                    self.bank_base(self.active) + record.position + 4 + u32::from(record.key_len);
+                self.storage.read(at, &mut buffer[..len])?;
+                Ok(Some(len))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Is `key` in the store?
+    pub fn contains(&mut self, key: &[u8]) -> Result<bool, Error<S::Error>> {
+        check_key(key)?;
+        Ok(matches!(self.find(key)?, Some(record) if !record.is_removed()))
+    }
+
+    /// Sets `key` to `value`. Either the new value is stored, or (if there's
+    /// a reset part way through) the old one stays.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error<S::Error>> {
+        check_key(key)?;
+        if value.len() > usize::from(u16::MAX)
+            || record_len(key.len(), value.len()) > self.bank_len - BANK_HEADER_LEN
+        {
+            return Err(Error::ValueTooLong);
+        }
+        self.append(key, 0, value)
+    }
+
+    /// Removes `key`, returning whether it was in the store
+    pub fn remove(&mut self, key: &[u8]) -> Result<bool, Error<S::Error>> {
+        if !self.contains(key)? {
+            return Ok(false);
+        }
+        self.append(key, FLAG_REMOVED, &[])?;
+        Ok(true)
+    }
+
+    /// Copies the current value of every key into the other bank, and
+    /// switches to it, freeing the space used by old values and removed
+    /// keys. `set` does this itself when the active bank is full.
+    pub fn compact(&mut self) -> Result<(), Error<S::Error>> {
+        let from = self.active;
+        let to = 1 - from;
+        let from_base = self.bank_base(from);
+        let to_base = self.bank_base(to);
+
+        // Until the new header is written, the old bank stays active
+        self.write_word(to_base, END)?;
+
+        let mut position = BANK_HEADER_LEN;
+        let mut dest = BANK_HEADER_LEN;
+        while let Some(record) = self.record_at(from, position)? {
+            position += record.len();
+            if record.is_removed() || self.is_superseded(&record)? {
+                continue;
+            }
+            let mut copied = 0;
+            while copied < record.len() {
+                let mut chunk = [0; CHUNK];
+                let count = (record.len() - copied).min(CHUNK as u32);
+                let chunk = &mut chunk[..count as usize];
+                self.storage
+                    .read(from_base + record.position + copied, chunk)?;
+                self.storage.write(to_base + dest + copied, chunk)?;
+                copied += count;
+            }
+            dest += record.len();
+        }
+
+        self.write_bank(to, self.generation.wrapping_add(1), dest)?;
+        self.open_bank(to, self.generation.wrapping_add(1))
+    }
+
+    /// Returns an iterator over the keys in the store, in the order they
+    /// were last set. Use [`Entries::read_value`] to read a value.
+    pub fn entries(&mut self) -> Entries<'_, S> {
+        Entries {
+            store: self,
+            position: BANK_HEADER_LEN,
+        }
+    }
+
+    /// The number of bytes free for new records before the next compaction
+    pub fn free_space(&self) -> u32 {
+        self.bank_len - self.end
+    }
+
+    /// The byte address of the start of `bank`
+    fn bank_base(&self, bank: u32) -> u32 {
+        self.start + bank * self.bank_len
+    }
+
+    fn read_word(&mut self, address: u32) -> Result<u32, Error<S::Error>> {
+        let mut word = [0; 4];
+        self.storage.read(address, &mut word)?;
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn write_word(&mut self, address: u32, word: u32) -> Result<(), Error<S::Error>> {
+        self.storage.write(address, &word.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// The generation of `bank`, or `None` if its header isn't valid
+    fn bank_generation(&mut self, bank: u32) -> Result<Option<u32>, Error<S::Error>> {
+        let base = self.bank_base(bank);
+        let magic = self.read_word(base)?;
+        let generation = self.read_word(base + 4)?;
+        let crc = self.read_word(base + 8)?;
+        let mut header = [0; 8];
+        header[..4].copy_from_slice(&magic.to_le_bytes());
+        header[4..].copy_from_slice(&generation.to_le_bytes());
+        if magic == MAGIC && crc == !crc32_update(!0, &header) {
+            Ok(Some(generation))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Ends the log in `bank` at `end`, then writes its header, with the
+    /// magic number last so the bank only becomes valid once it's complete
+    fn write_bank(&mut self, bank: u32, generation: u32, end: u32) -> Result<(), Error<S::Error>> {
+        let base = self.bank_base(bank);
+        if end + 4 <= self.bank_len {
+            self.write_word(base + end, END)?;
+        }
+        let mut header = [0; 8];
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&generation.to_le_bytes());
+        self.write_word(base + 4, generation)?;
+        self.write_word(base + 8, !crc32_update(!0, &header))?;
+        self.write_word(base, MAGIC)
+    }
+
+    /// Makes `bank` the active bank, and finds the end of its log
+    fn open_bank(&mut self, bank: u32, generation: u32) -> Result<(), Error<S::Error>> {
+        self.active = bank;
+        self.generation = generation;
+        let mut position = BANK_HEADER_LEN;
+        while let Some(record) = self.record_at(bank, position)? {
+            position += record.len();
+        }
+        self.end = position;
+        Ok(())
+    }
+
+    /// Reads and checks the record at `position` in `bank`. Returns `None`
+    /// at the end of the log, which is wherever there isn't a valid record.
+    fn record_at(&mut self, bank: u32, position: u32) -> Result<Option<Record>, Error<S::Error>> {
+        if position + 4 > self.bank_len {
+            return Ok(None);
+        }
+        let base = self.bank_base(bank);
+        let header = self.read_word(base + position)?.to_le_bytes();
+        let record = Record {
+            position,
+            key_len: header[0],
+            flags: header[1],
+            value_len: u16::from_le_bytes([header[2], header[3]]),
+        };
+        if record.key_len == 0
+            || usize::from(record.key_len) > MAX_KEY_LEN
+            || record.flags & !FLAG_REMOVED != 0
+            || u64::from(position) + u64::from(record.len()) > u64::from(self.bank_len)
+        {
+            return Ok(None);
+        }
+
+        let data_len = 4 + u32::from(record.key_len) + u32::from(record.value_len);
+        let mut crc = !0;
+        let mut done = 0;
+        while done < data_len {
+            let mut chunk = [0; CHUNK];
+            let count = (data_len - done).min(CHUNK as u32);
+            let chunk = &mut chunk[..count as usize];
+            self.storage.read(base + position + done, chunk)?;
+            crc = crc32_update(crc, chunk);
+            done += count;
+        }
+        let stored = self.read_word(base + position + record.len() - 4)?;
+        if stored == !crc {
+            Ok(Some(record))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Reads the key of `record` in the active bank
+    fn read_key(&mut self, record: &Record) -> Result<[u8; MAX_KEY_LEN], Error<S::Error>> {
+        let mut key = [0; MAX_KEY_LEN];
+        let at = self.bank_base(self.active) + record.position + 4;
+        self.storage
+            .read(at, &mut key[..usize::from(record.key_len)])?;
+        Ok(key)
+    }
+
+    /// Does `record` in the active bank have the key `key`?
+    fn has_key(&mut self, record: &Record, key: &[u8]) -> Result<bool, Error<S::Error>> {
+        if usize::from(record.key_len) != key.len() {
+            return Ok(false);
+        }
+        Ok(&self.read_key(record)?[..key.len()] == key)
+    }
+
+    /// Finds the last record for `key` in the active bank
+    fn find(&mut self, key: &[u8]) -> Result<Option<Record>, Error<S::Error>> {
+        let mut found = None;
+        let mut position = BANK_HEADER_LEN;
+        while let Some(record) = self.record_at(self.active, position)? {
+            if self.has_key(&record, key)? {
+                found = Some(record);
+            }
+            position += record.len();
+        }
+        Ok(found)
+    }
+
+    /// Is there a later record in the active bank with the same key as
+    /// `record`?
+    fn is_superseded(&mut self, record: &Record) -> Result<bool, Error<S::Error>> {
+        let key = self.read_key(record)?;
+        let key = &key[..usize::from(record.key_len)];
+        let mut position = record.position + record.len();
+        while let Some(later) = self.record_at(self.active, position)? {
+            if self.has_key(&later, key)? {
+                return Ok(true);
+            }
+            position += later.len();
+        }
+        Ok(false)
+    }
+
+    /// Appends a record to the log, compacting first if it doesn't fit
+    fn append(&mut self, key: &[u8], flags: u8, value: &[u8]) -> Result<(), Error<S::Error>> {
+        let len = record_len(key.len(), value.len());
+        if self.end + len > self.bank_len {
+            self.compact()?;
+            if self.end + len > self.bank_len {
+                return Err(Error::Full);
+            }
+        }
+
+        let base = self.bank_base(self.active);
+        let position = self.end;
+
+        // End the log after the new record first, so whatever was there
+        // before can't be mistaken for a record
+        if position + len + 4 <= self.bank_len {
+            self.write_word(base + position + len, END)?;
+        }
+
+        let mut header = [0; 4];
+        header[0] = key.len() as u8;
+        header[1] = flags;
+        header[2..].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        let crc = crc32_update(crc32_update(crc32_update(!0, &header), key), value);
+
+        let key_at = base + position + 4;
+        let value_at = key_at + key.len() as u32;
+        let padding_at = value_at + value.len() as u32;
+        let crc_at = base + position + len - 4;
+
+        self.storage.write(base + position, &header)?;
+        self.storage.write(key_at, key)?;
+        self.storage.write(value_at, value)?;
+        if crc_at > padding_at {
+            self.storage
+                .write(padding_at, &[0xFF; 3][..(crc_at - padding_at) as usize])?;
+        }
+        // The record only counts once this is written
+        self.write_word(crc_at, !crc)?;
+
+        self.end += len;
+        Ok(())
+    }
+}
+
+fn check_key<E>(key: &[u8]) -> Result<(), Error<E>> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        Err(Error::BadKey)
+    } else {
+        Ok(())
+    }
+}
+
+/// A key in the store, from [`KvStore::entries`]
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    key: [u8; MAX_KEY_LEN],
+    key_len: u8,
+    value_len: u16,
+    /// Byte address of the value
+    value_at: u32,
+}
+
+impl Entry {
+    /// The key
+    pub fn key(&self) -> &[u8] {
+        &self.key[..usize::from(self.key_len)]
+    }
+
+    /// The length of the value, in bytes
+    pub fn value_len(&self) -> usize {
+        usize::from(self.value_len)
+    }
+}
+
+/// An iterator over the keys in a [`KvStore`]
+pub struct Entries<'a, S> {
+    store: &'a mut KvStore<S>,
+    position: u32,
+}
+
+impl<S> Entries<'_, S>
+where
+    S: Storage,
+{
+    /// Copies the value of `entry` into `buffer`, returning its length
+    pub fn read_value(
+        &mut self,
+        entry: &Entry,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error<S::Error>> {
+        let len = entry.value_len();
+        if len > buffer.len() {
+            return Err(Error::BufferTooSmall(len));
+        }
+        self.store
+            .storage
+            .read(entry.value_at, &mut buffer[..len])?;
+        Ok(len)
+    }
+}
+
+impl<S> Iterator for Entries<'_, S>
+where
+    S: Storage,
+{
+    type Item = Result<Entry, Error<S::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let step = self
+                .store
+                .record_at(self.store.active, self.position)
+                .and_then(|record| match record {
+                    Some(record) => {
+                        self.position += record.len();
+                        if record.is_removed() || self.store.is_superseded(&record)? {
+                            Ok(Some(None))
+                        } else {
+                            Ok(Some(Some(Entry {
+                                key: self.store.read_key(&record)?,
+                                key_len: record.key_len,
+                                value_len: record.value_len,
+                                value_at: self.store.bank_base(self.store.active)
+                                    + record.position
+                                    + 4
+                                    + u32::from(record.key_len),
+                            })))
+                        }
+                    }
+                    None => Ok(None),
+                });
+            match step {
+                Ok(Some(Some(entry))) => return Some(Ok(entry)),
+                Ok(Some(None)) => continue,
+                Ok(None) => return None,
+                Err(e) => {
+                    // Don't go round again
+                    self.position = self.store.bank_len;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::ReadStorage;
+
+    /// Simulated storage which can lose power part way through a write
+    struct Ram {
+        data: [u8; 512],
+        /// Bytes that can be written before the power fails
+        budget: Option<usize>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct PowerFailed;
+
+    impl Ram {
+        fn new() -> Self {
+            Ram {
+                data: [0xFF; 512],
+                budget: None,
+            }
+        }
+    }
+
+    impl ReadStorage for Ram {
+        type Error = PowerFailed;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerFailed> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl Storage for Ram {
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerFailed> {
+            let offset = offset as usize;
+            for (i, &byte) in bytes.iter().enumerate() {
+                if let Some(budget) = self.budget.as_mut() {
+                    if *budget == 0 {
+                        return Err(PowerFailed);
+                    }
+                    *budget -= 1;
+                }
+                self.data[offset + i] = byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn value(store: &mut KvStore<Ram>, key: &[u8]) -> Option<u32> {
+        let mut buffer = [0; 4];
+        store
+            .get(key, &mut buffer)
+            .unwrap()
+            .map(|_| u32::from_le_bytes(buffer))
+    }
+
+    /// Re-opens the store, as after a reset
+    fn reopen(store: KvStore<Ram>) -> KvStore<Ram> {
+        let (start, len) = (store.start, store.bank_len * 2);
+        let mut ram = store.free();
+        ram.budget = None;
+        KvStore::new(ram, start, len).unwrap()
+    }
+
+    #[test]
+    fn crc_is_standard_crc32() {
+        assert_eq!(!crc32_update(!0, b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn record_format_is_as_documented() {
+        let mut store = KvStore::new(Ram::new(), 0, 512).unwrap();
+        store.set(b"key", b"val").unwrap();
+        let ram = store.free();
+
+        assert_eq!(&ram.data[0..4], &MAGIC.to_le_bytes());
+        assert_eq!(&ram.data[4..8], &1u32.to_le_bytes());
+        let record = &ram.data[12..24];
+        assert_eq!(&record[..4], &[3, 0, 3, 0]);
+        assert_eq!(&record[4..10], b"keyval");
+        assert_eq!(&record[10..12], &[0xFF, 0xFF]);
+        let crc = !crc32_update(!0, &record[..10]);
+        assert_eq!(&ram.data[24..28], &crc.to_le_bytes());
+        // The log is terminated
+        assert_eq!(&ram.data[28..32], &[0xFF; 4]);
+    }
+
+    #[test]
+    fn set_get_remove() {
+        let mut store = KvStore::new(Ram::new(), 0, 512).unwrap();
+        assert_eq!(value(&mut store, b"a"), None);
+        store.set(b"a", &1u32.to_le_bytes()).unwrap();
+        store.set(b"b", &2u32.to_le_bytes()).unwrap();
+        store.set(b"a", &3u32.to_le_bytes()).unwrap();
+        assert_eq!(value(&mut store, b"a"), Some(3));
+        assert_eq!(value(&mut store, b"b"), Some(2));
+        assert!(store.remove(b"a").unwrap());
+        assert!(!store.remove(b"a").unwrap());
+        assert_eq!(value(&mut store, b"a"), None);
+
+        let mut store = reopen(store);
+        assert_eq!(value(&mut store, b"a"), None);
+        assert_eq!(value(&mut store, b"b"), Some(2));
+    }
+
+    #[test]
+    fn bad_arguments() {
+        let mut store = KvStore::new(Ram::new(), 0, 512).unwrap();
+        assert_eq!(store.set(b"", b"x"), Err(Error::BadKey));
+        assert_eq!(
+            store.set(&[b'k'; MAX_KEY_LEN + 1], b"x"),
+            Err(Error::BadKey)
+        );
+        assert_eq!(store.set(b"k", &[0; 256]), Err(Error::ValueTooLong));
+        store.set(b"k", b"long value").unwrap();
+        assert_eq!(store.get(b"k", &mut [0; 4]), Err(Error::BufferTooSmall(10)));
+        assert!(matches!(
+            KvStore::new(Ram::new(), 4, 256),
+            Err(Error::BadRegion)
+        ));
+        assert!(matches!(
+            KvStore::new(Ram::new(), 256, 512),
+            Err(Error::BadRegion)
+        ));
+    }
+
+    #[test]
+    fn entries_are_the_live_keys() {
+        let mut store = KvStore::new(Ram::new(), 0, 512).unwrap();
+        store.set(b"a", b"1").unwrap();
+        store.set(b"b", b"2").unwrap();
+        store.set(b"c", b"3").unwrap();
+        store.set(b"a", b"4").unwrap();
+        store.remove(b"b").unwrap();
+
+        let mut entries = store.entries();
+        let mut seen = [(0u8, 0u8); 4];
+        let mut count = 0;
+        while let Some(entry) = entries.next() {
+            let entry = entry.unwrap();
+            let mut buffer = [0; 1];
+            entries.read_value(&entry, &mut buffer).unwrap();
+            seen[count] = (entry.key()[0], buffer[0]);
+            count += 1;
+        }
+        assert_eq!(&seen[..count], &[(b'c', b'3'), (b'a', b'4')]);
+    }
+
+    #[test]
+    fn compacts_when_full() {
+        let mut store = KvStore::new(Ram::new(), 0, 512).unwrap();
+        store.set(b"fixed", &7u32.to_le_bytes()).unwrap();
+        for i in 0..100u32 {
+            store.set(b"counter", &i.to_le_bytes()).unwrap();
+        }
+        assert!(store.generation > 1);
+        assert_eq!(value(&mut store, b"counter"), Some(99));
+        assert_eq!(value(&mut store, b"fixed"), Some(7));
+
+        let mut store = reopen(store);
+        assert_eq!(value(&mut store, b"counter"), Some(99));
+        assert_eq!(value(&mut store, b"fixed"), Some(7));
+    }
+
+    #[test]
+    fn full_store_is_an_error() {
+        let mut store = KvStore::new(Ram::new(), 0, 128).unwrap();
+        let mut key = *b"key0";
+        let result = loop {
+            if let Err(e) = store.set(&key, &[0; 16]) {
+                break e;
+            }
+            key[3] += 1;
+        };
+        assert_eq!(result, Error::Full);
+        // What was stored is still there
+        assert!(store.contains(b"key0").unwrap());
+    }
+
+    #[test]
+    fn interrupted_set_keeps_old_value() {
+        // Lose power after every possible number of bytes
+        for budget in 0..12 {
+            let mut store = KvStore::new(Ram::new(), 0, 512).unwrap();
+            store.set(b"key", &1u32.to_le_bytes()).unwrap();
+            store.storage.budget = Some(budget);
+            assert_eq!(
+                store.set(b"key", &2u32.to_le_bytes()),
+                Err(Error::Storage(PowerFailed))
+            );
+
+            let mut store = reopen(store);
+            assert_eq!(value(&mut store, b"key"), Some(1));
+            // And the store still works
+            store.set(b"key", &3u32.to_le_bytes()).unwrap();
+            store.set(b"other", &4u32.to_le_bytes()).unwrap();
+            let mut store = reopen(store);
+            assert_eq!(value(&mut store, b"key"), Some(3));
+            assert_eq!(value(&mut store, b"other"), Some(4));
+        }
+    }
+
+    #[test]
+    fn interrupted_compaction_loses_nothing() {
+        let mut budget = 0;
+        loop {
+            let mut store = KvStore::new(Ram::new(), 0, 256).unwrap();
+            store.set(b"a", &1u32.to_le_bytes()).unwrap();
+            store.set(b"b", &2u32.to_le_bytes()).unwrap();
+            store.remove(b"b").unwrap();
+            store.set(b"c", &3u32.to_le_bytes()).unwrap();
+            store.storage.budget = Some(budget);
+            let finished = store.compact().is_ok();
+
+            let mut store = reopen(store);
+            assert_eq!(value(&mut store, b"a"), Some(1));
+            assert_eq!(value(&mut store, b"b"), None);
+            assert_eq!(value(&mut store, b"c"), Some(3));
+            if finished {
+                assert_eq!(store.generation, 2);
+                break;
+            }
+            budget += 1;
+        }
+    }
+}
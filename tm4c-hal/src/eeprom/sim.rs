@@ -0,0 +1,140 @@
+//! A simulated EEPROM, held in RAM, for running the EEPROM logic on a host.
+//!
+//! ```ignore
+//! let mut eeprom = Driver::new(SimBackend::<4>::new());
+//! eeprom.detect_size();
+//! eeprom.write(&EepromAddress::new(1, 15), &[1, 2, 3, 4, 5])?;
+//! ```
+//!
+//! Every operation completes at once, so the simulated EEPROM is never busy.
+//! Writes to a block that can't be written are refused, as they are by the
+//! hardware.
+
+use core::cell::{Cell, RefCell};
+
+use super::{Backend, Protection, BLOCK_SIZE_WORDS};
+
+/// The state of one simulated block
+#[derive(Clone, Copy)]
+struct Block {
+    words: [u32; BLOCK_SIZE_WORDS],
+    protection: Protection,
+    /// The block has a password, and is locked
+    locked: bool,
+    hidden: bool,
+}
+
+impl Block {
+    const ERASED: Block = Block {
+        words: [0xFFFF_FFFF; BLOCK_SIZE_WORDS],
+        protection: Protection::ReadWrite,
+        locked: false,
+        hidden: false,
+    };
+
+    /// Can this block be written just now?
+    fn is_writable(&self) -> bool {
+        !self.hidden && self.protection != Protection::ReadOnly && !self.locked
+    }
+}
+
+/// A [`Backend`] simulating an EEPROM of `BLOCKS` blocks, which start out
+/// erased (all ones) and unprotected
+pub struct SimBackend<const BLOCKS: usize> {
+    blocks: RefCell<[Block; BLOCKS]>,
+    /// The EEBLOCK register
+    block: Cell<usize>,
+    /// The EEOFFSET register
+    offset: Cell<usize>,
+    /// The NOPERM bit of EEDONE
+    refused: Cell<bool>,
+}
+
+impl<const BLOCKS: usize> SimBackend<BLOCKS> {
+    /// Creates an erased EEPROM
+    pub fn new() -> Self {
+        SimBackend {
+            blocks: RefCell::new([Block::ERASED; BLOCKS]),
+            block: Cell::new(0),
+            offset: Cell::new(0),
+            refused: Cell::new(false),
+        }
+    }
+
+    /// Returns the word with index `index`
+    pub fn word(&self, index: usize) -> u32 {
+        self.blocks.borrow()[index / BLOCK_SIZE_WORDS].words[index % BLOCK_SIZE_WORDS]
+    }
+
+    /// Sets the protection of `block`. There are no passwords: if `locked`
+    /// is set, the block acts as if it has one and hasn't been unlocked,
+    /// and otherwise as if it has none.
+    pub fn protect(&self, block: usize, protection: Protection, locked: bool) {
+        let mut blocks = self.blocks.borrow_mut();
+        blocks[block].protection = protection;
+        blocks[block].locked = locked;
+    }
+
+    /// Hides `block`
+    pub fn hide(&self, block: usize) {
+        self.blocks.borrow_mut()[block].hidden = true;
+    }
+}
+
+impl<const BLOCKS: usize> Default for SimBackend<BLOCKS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const BLOCKS: usize> Backend for SimBackend<BLOCKS> {
+    fn size(&self) -> (usize, usize) {
+        (BLOCKS, BLOCKS * BLOCK_SIZE_WORDS)
+    }
+
+    fn is_busy(&self) -> bool {
+        false
+    }
+
+    fn select_block(&self, block: usize) {
+        self.block.set(block);
+    }
+
+    fn select_offset(&self, offset: usize) {
+        self.offset.set(offset);
+    }
+
+    fn read_word(&self) -> u32 {
+        let block = &self.blocks.borrow()[self.block.get()];
+        if block.hidden {
+            0
+        } else {
+            block.words[self.offset.get()]
+        }
+    }
+
+    fn write_word(&self, word: u32) {
+        let block = &mut self.blocks.borrow_mut()[self.block.get()];
+        let writable = block.is_writable();
+        if writable {
+            block.words[self.offset.get()] = word;
+        }
+        self.refused.set(!writable);
+    }
+
+    fn write_refused(&self) -> bool {
+        self.refused.get()
+    }
+
+    fn is_locked(&self) -> bool {
+        self.blocks.borrow()[self.block.get()].locked
+    }
+
+    fn protection(&self) -> Protection {
+        self.blocks.borrow()[self.block.get()].protection
+    }
+
+    fn is_hidden(&self, block: usize) -> bool {
+        self.blocks.borrow()[block].hidden
+    }
+}
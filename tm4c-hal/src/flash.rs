@@ -0,0 +1,893 @@
+//! Code for programming the internal flash.
+//!
+//! The flash controller erases a page at a time (1 KB on the TM4C123, 16 KB
+//! on the TM4C129), and programs a word at a time, or up to
+//! [`WRITE_BUFFER_WORDS`] words at once through its write buffer. Programming
+//! can only clear bits, so a word must be erased before it's programmed again.
+//!
+//! The processor stalls while the flash is busy, so the driver doesn't need
+//! to be run from RAM, but anything else (interrupt handlers included) that
+//! runs from flash waits for it.
+//!
+//! `Flash::protection` reads the flash protection (which blocks can be read
+//! and programmed) and the boot configuration (BOOTCFG), as a
+//! [`ProtectionConfig`]. Change it and pass it to `Flash::set_protection`,
+//! with [`ProtectionMode::DryRun`] to see what would change before making
+//! the changes for real. Committed changes can't be undone:
+//!
+//! ```ignore
+//! let mut config = flash.protection();
+//! config.set_read_enabled(config.block_of(0x1_0000), false)?;
+//! for change in flash.set_protection(&config, ProtectionMode::DryRun)? {
+//!     // ...
+//! }
+//! flash.set_protection(&config, ProtectionMode::Commit)?;
+//! ```
+//!
+//! The `Flash` is given the address range of the running image, and refuses
+//! to erase or program it. With `cortex-m-rt`, that's the start of flash up
+//! to the end of the initial values of `.data`:
+//!
+//! ```ignore
+//! extern "C" {
+//!     static __sidata: u32;
+//!     static __sdata: u32;
+//!     static __edata: u32;
+//! }
+//! let image_end = unsafe {
+//!     let data = &__edata as *const u32 as usize - &__sdata as *const u32 as usize;
+//!     &__sidata as *const u32 as usize + data
+//! };
+//! let mut flash = Flash::new(p.FLASH_CTRL, 0..image_end);
+//! flash.erase_page(0x3_0000)?;
+//! flash.program(0x3_0000, &[0x1234_5678, 0x9ABC_DEF0])?;
+//! ```
+
+use embedded_storage::nor_flash::{NorFlashError, NorFlashErrorKind};
+
+#[doc(hidden)]
+pub use embedded_storage;
+
+/// Possible errors for the flash controller
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashError {
+    /// Address is out of bounds
+    OutOfBounds,
+    /// Address or length isn't aligned as it must be
+    NotAligned,
+    /// The operation would erase or program the running image
+    RunningImage,
+    /// The flash is protected against this operation (an access violation)
+    Protected,
+    /// The flash controller reported an error while erasing or programming
+    Failed,
+    /// Protection can only be tightened, and BOOTCFG only committed once
+    Irreversible,
+    /// BOOTCFG can only be changed by committing it
+    CommitRequired,
+}
+
+impl core::fmt::Display for FlashError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FlashError::OutOfBounds => write!(f, "Address is out of bounds"),
+            FlashError::NotAligned => write!(f, "Address or length is not aligned"),
+            FlashError::RunningImage => write!(f, "Would change the running image"),
+            FlashError::Protected => write!(f, "Flash is protected"),
+            FlashError::Failed => write!(f, "Flash operation failed"),
+            FlashError::Irreversible => write!(f, "Protection can't be loosened"),
+            FlashError::CommitRequired => write!(f, "BOOTCFG must be committed"),
+        }
+    }
+}
+
+impl NorFlashError for FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            FlashError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            FlashError::NotAligned => NorFlashErrorKind::NotAligned,
+            FlashError::RunningImage
+            | FlashError::Protected
+            | FlashError::Failed
+            | FlashError::Irreversible
+            | FlashError::CommitRequired => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl From<NorFlashErrorKind> for FlashError {
+    fn from(kind: NorFlashErrorKind) -> Self {
+        match kind {
+            NorFlashErrorKind::NotAligned => FlashError::NotAligned,
+            _ => FlashError::OutOfBounds,
+        }
+    }
+}
+
+/// Number of words in the flash write buffer (FWBn)
+pub const WRITE_BUFFER_WORDS: usize = 32;
+
+/// Size of a flash word in bytes
+pub const BYTES_PER_WORD: usize = 4;
+
+/// FMC: write a word
+#[doc(hidden)]
+pub const FMC_WRITE: u32 = 1 << 0;
+/// FMC: erase a page
+#[doc(hidden)]
+pub const FMC_ERASE: u32 = 1 << 1;
+/// FMC: mass erase
+#[doc(hidden)]
+pub const FMC_MERASE: u32 = 1 << 2;
+/// FMC2: write the buffer
+#[doc(hidden)]
+pub const FMC2_WRBUF: u32 = 1 << 0;
+/// FCRIS: access violation
+#[doc(hidden)]
+pub const FCRIS_ARIS: u32 = 1 << 0;
+/// FCRIS: the errors the TM4C129 also reports (invalid data, pump voltage,
+/// erase verify, program verify). These bits are reserved, and read as zero,
+/// on the TM4C123.
+#[doc(hidden)]
+pub const FCRIS_ERRORS: u32 = (1 << 9) | (1 << 10) | (1 << 11) | (1 << 13);
+/// Offset of the first write buffer register, FWB0, in the flash controller
+#[doc(hidden)]
+pub const FWB_OFFSET: usize = 0x100;
+
+/// FMC: commit a protection register or BOOTCFG
+#[doc(hidden)]
+pub const FMC_COMT: u32 = 1 << 3;
+/// Offset of FMPRE0 in the flash controller
+#[doc(hidden)]
+pub const FMPRE_OFFSET: usize = 0x1200;
+/// Offset of FMPPE0 in the flash controller
+#[doc(hidden)]
+pub const FMPPE_OFFSET: usize = 0x1400;
+/// Offset of BOOTCFG in the flash controller
+#[doc(hidden)]
+pub const BOOTCFG_OFFSET: usize = 0x11D0;
+/// The FMA value which commits BOOTCFG
+#[doc(hidden)]
+pub const BOOTCFG_COMMIT_ADDRESS: u32 = 0x7510_0000;
+
+/// The ROM bootloader's GPIO trigger: if the pin is at the given level at
+/// reset, the ROM bootloader runs instead of the application
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootPin {
+    /// The port, 0 for A to 7 for H
+    pub port: u8,
+    /// The pin, 0 to 7
+    pub pin: u8,
+    /// Whether a high level (rather than a low level) triggers the bootloader
+    pub active_high: bool,
+}
+
+/// The boot configuration (BOOTCFG). It can only be changed by clearing
+/// bits, and only committed once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootConfig {
+    bits: u32,
+}
+
+impl BootConfig {
+    const DBG0: u32 = 1 << 0;
+    const DBG1: u32 = 1 << 1;
+    const EN: u32 = 1 << 8;
+    const POL: u32 = 1 << 9;
+    const PIN_SHIFT: u32 = 10;
+    const PORT_SHIFT: u32 = 13;
+    const NW: u32 = 1 << 31;
+
+    /// Decodes the BOOTCFG register
+    pub fn from_bits(bits: u32) -> Self {
+        BootConfig { bits }
+    }
+
+    /// The value of the BOOTCFG register
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Has BOOTCFG been committed? After that, it can't be changed.
+    pub fn is_committed(&self) -> bool {
+        self.bits & Self::NW == 0
+    }
+
+    /// Returns the GPIO trigger for the ROM bootloader, if it's enabled
+    pub fn boot_pin(&self) -> Option<BootPin> {
+        if self.bits & Self::EN != 0 {
+            return None;
+        }
+        Some(BootPin {
+            port: ((self.bits >> Self::PORT_SHIFT) & 0x7) as u8,
+            pin: ((self.bits >> Self::PIN_SHIFT) & 0x7) as u8,
+            active_high: self.bits & Self::POL != 0,
+        })
+    }
+
+    /// Sets the GPIO trigger for the ROM bootloader
+    pub fn with_boot_pin(self, boot_pin: BootPin) -> Self {
+        let mask = Self::EN | Self::POL | (0x7 << Self::PIN_SHIFT) | (0x7 << Self::PORT_SHIFT);
+        let mut bits = self.bits & !mask;
+        bits |= u32::from(boot_pin.port & 0x7) << Self::PORT_SHIFT;
+        bits |= u32::from(boot_pin.pin & 0x7) << Self::PIN_SHIFT;
+        if boot_pin.active_high {
+            bits |= Self::POL;
+        }
+        BootConfig { bits }
+    }
+
+    /// Is the debug port enabled?
+    pub fn debug_enabled(&self) -> bool {
+        self.bits & (Self::DBG0 | Self::DBG1) == Self::DBG1
+    }
+
+    /// Disables the debug port, for good once committed
+    pub fn with_debug_disabled(self) -> Self {
+        BootConfig {
+            bits: self.bits & !Self::DBG1,
+        }
+    }
+
+    /// Can BOOTCFG be changed from `self` to `to`?
+    fn can_become(&self, to: &BootConfig) -> bool {
+        let (from, to) = (self.bits & !Self::NW, to.bits & !Self::NW);
+        from == to || (!self.is_committed() && to & !from == 0)
+    }
+}
+
+/// One change a new [`ProtectionConfig`] makes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectionChange {
+    /// Whether a block can be read (its FMPREn bit)
+    Read {
+        /// The block
+        block: usize,
+        /// Whether it can be read afterwards
+        enabled: bool,
+    },
+    /// Whether a block can be programmed and erased (its FMPPEn bit)
+    Program {
+        /// The block
+        block: usize,
+        /// Whether it can be programmed afterwards
+        enabled: bool,
+    },
+    /// BOOTCFG
+    Boot {
+        /// The current setting
+        from: BootConfig,
+        /// The new setting
+        to: BootConfig,
+    },
+}
+
+/// How far to go in applying a new [`ProtectionConfig`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectionMode {
+    /// Check the new configuration and report the changes, but change nothing
+    DryRun,
+    /// Write the protection registers, which lasts until the next power-on
+    /// reset. BOOTCFG can't be changed like this.
+    UntilReset,
+    /// Write the protection registers and BOOTCFG, and commit them. This
+    /// can't be undone.
+    Commit,
+}
+
+/// The flash protection: which blocks can be read and programmed
+/// (FMPREn and FMPPEn), and the boot configuration (BOOTCFG).
+///
+/// A block that can't be read can still be executed, so clearing both read
+/// and program enable makes a block execute-only. Protection can only be
+/// tightened: re-enabling a block needs a power-on reset, or a mass erase if
+/// the change was committed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProtectionConfig<const N: usize> {
+    read: [u32; N],
+    program: [u32; N],
+    block_size: usize,
+    block_count: usize,
+    boot: BootConfig,
+}
+
+impl<const N: usize> ProtectionConfig<N> {
+    /// Builds a configuration from the register values
+    #[doc(hidden)]
+    pub fn from_registers(
+        read: [u32; N],
+        program: [u32; N],
+        block_size: usize,
+        block_count: usize,
+        boot: BootConfig,
+    ) -> Self {
+        ProtectionConfig {
+            read,
+            program,
+            block_size,
+            block_count: block_count.min(N * 32),
+            boot,
+        }
+    }
+
+    /// The FMPREn values
+    #[doc(hidden)]
+    pub fn read_registers(&self) -> &[u32; N] {
+        &self.read
+    }
+
+    /// The FMPPEn values
+    #[doc(hidden)]
+    pub fn program_registers(&self) -> &[u32; N] {
+        &self.program
+    }
+
+    /// The size of a protection block, in bytes
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The number of protection blocks
+    pub fn block_count(&self) -> usize {
+        self.block_count
+    }
+
+    /// The block which holds `address`
+    pub fn block_of(&self, address: usize) -> usize {
+        address / self.block_size
+    }
+
+    /// Can `block` be read?
+    pub fn read_enabled(&self, block: usize) -> Result<bool, FlashError> {
+        self.check_block(block)?;
+        Ok(self.read[block / 32] & (1 << (block % 32)) != 0)
+    }
+
+    /// Can `block` be programmed and erased?
+    pub fn program_enabled(&self, block: usize) -> Result<bool, FlashError> {
+        self.check_block(block)?;
+        Ok(self.program[block / 32] & (1 << (block % 32)) != 0)
+    }
+
+    /// Sets whether `block` can be read
+    pub fn set_read_enabled(&mut self, block: usize, enabled: bool) -> Result<(), FlashError> {
+        self.check_block(block)?;
+        Self::set_bit(&mut self.read, block, enabled);
+        Ok(())
+    }
+
+    /// Sets whether `block` can be programmed and erased
+    pub fn set_program_enabled(&mut self, block: usize, enabled: bool) -> Result<(), FlashError> {
+        self.check_block(block)?;
+        Self::set_bit(&mut self.program, block, enabled);
+        Ok(())
+    }
+
+    /// Returns the boot configuration
+    pub fn boot(&self) -> BootConfig {
+        self.boot
+    }
+
+    /// Sets the boot configuration
+    pub fn set_boot(&mut self, boot: BootConfig) {
+        self.boot = boot;
+    }
+
+    /// The changes needed to go from `self` to `to`. Fails with
+    /// `Irreversible` if any can't be made.
+    pub fn changes(&self, to: &ProtectionConfig<N>) -> Result<ProtectionChanges<N>, FlashError> {
+        let loosened = self
+            .read
+            .iter()
+            .zip(&to.read)
+            .chain(self.program.iter().zip(&to.program))
+            .any(|(from, to)| to & !from != 0);
+        if loosened || !self.boot.can_become(&to.boot) {
+            return Err(FlashError::Irreversible);
+        }
+        Ok(ProtectionChanges {
+            from: *self,
+            to: *to,
+            position: 0,
+        })
+    }
+
+    fn check_block(&self, block: usize) -> Result<(), FlashError> {
+        if block < self.block_count {
+            Ok(())
+        } else {
+            Err(FlashError::OutOfBounds)
+        }
+    }
+
+    fn set_bit(words: &mut [u32; N], block: usize, value: bool) {
+        if value {
+            words[block / 32] |= 1 << (block % 32);
+        } else {
+            words[block / 32] &= !(1 << (block % 32));
+        }
+    }
+}
+
+/// An iterator over the changes between two [`ProtectionConfig`]s
+#[derive(Clone, Debug)]
+pub struct ProtectionChanges<const N: usize> {
+    from: ProtectionConfig<N>,
+    to: ProtectionConfig<N>,
+    /// Read blocks, then program blocks, then BOOTCFG
+    position: usize,
+}
+
+impl<const N: usize> ProtectionChanges<N> {
+    /// Are there no changes?
+    pub fn is_empty(&self) -> bool {
+        self.clone().next().is_none()
+    }
+
+    /// Does BOOTCFG change?
+    pub fn changes_boot(&self) -> bool {
+        self.from.boot.bits & !BootConfig::NW != self.to.boot.bits & !BootConfig::NW
+    }
+}
+
+impl<const N: usize> Iterator for ProtectionChanges<N> {
+    type Item = ProtectionChange;
+
+    fn next(&mut self) -> Option<ProtectionChange> {
+        let blocks = self.from.block_count;
+        while self.position < 2 * blocks {
+            let (block, program) = (self.position % blocks, self.position >= blocks);
+            self.position += 1;
+            let (from, to) = if program {
+                (&self.from.program, &self.to.program)
+            } else {
+                (&self.from.read, &self.to.read)
+            };
+            let bit = 1 << (block % 32);
+            let enabled = to[block / 32] & bit != 0;
+            if (from[block / 32] & bit != 0) != enabled {
+                return Some(if program {
+                    ProtectionChange::Program { block, enabled }
+                } else {
+                    ProtectionChange::Read { block, enabled }
+                });
+            }
+        }
+        if self.position == 2 * blocks {
+            self.position += 1;
+            if self.changes_boot() {
+                return Some(ProtectionChange::Boot {
+                    from: self.from.boot,
+                    to: self.to.boot,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Checks that `length` bytes from `address` are in a flash of `size` bytes,
+/// and don't touch `image`
+#[doc(hidden)]
+pub fn check_range(
+    address: usize,
+    length: usize,
+    size: usize,
+    image: &core::ops::Range<usize>,
+) -> Result<(), FlashError> {
+    let end = match address.checked_add(length) {
+        Some(end) if end <= size => end,
+        _ => return Err(FlashError::OutOfBounds),
+    };
+    if length != 0 && address < image.end && image.start < end {
+        Err(FlashError::RunningImage)
+    } else {
+        Ok(())
+    }
+}
+
+/// An internal macro to implement the flash driver. The invoking module
+/// must have `FLASH_CTRL` in scope, plus functions
+/// `write_key(&FLASH_CTRL) -> u32`, giving the key for FMC and FMC2 writes,
+/// and `flash_size(&FLASH_CTRL) -> usize`, giving the size of the flash in
+/// bytes. `page_size` is the erase size, in bytes, `protection_block` the size
+/// covered by each FMPREn/FMPPEn bit, and `protection_registers` the number of
+/// FMPREn (and FMPPEn) registers.
+#[macro_export]
+macro_rules! flash_hal_macro {
+    (
+        page_size: $page_size:expr,
+        protection_block: $protection_block:expr,
+        protection_registers: $protection_registers:expr
+    ) => {
+        /// The flash protection configuration for this chip
+        pub type ProtectionConfig = $crate::flash::ProtectionConfig<$protection_registers>;
+
+        /// The changes a new [`ProtectionConfig`] makes
+        pub type ProtectionChanges = $crate::flash::ProtectionChanges<$protection_registers>;
+
+        /// The flash controller
+        pub struct Flash {
+            /// Flash controller registers
+            flash: FLASH_CTRL,
+            /// Key for FMC and FMC2 writes, already shifted into place
+            key: u32,
+            /// Size of the flash, in bytes
+            size: usize,
+            /// The running image, which we mustn't touch
+            image: core::ops::Range<usize>,
+        }
+
+        impl Flash {
+            /// The size of a page, which is the smallest area that can be
+            /// erased
+            pub const PAGE_SIZE: usize = $page_size;
+
+            /// Takes the flash controller. `image` is the address range of the
+            /// running image, which will never be erased or programmed. Pass
+            /// an empty range if running from RAM.
+            pub fn new(flash: FLASH_CTRL, image: core::ops::Range<usize>) -> Self {
+                let key = write_key(&flash) << 16;
+                let size = flash_size(&flash);
+                Flash {
+                    flash,
+                    key,
+                    size,
+                    image,
+                }
+            }
+
+            /// Releases the flash controller
+            pub fn free(self) -> FLASH_CTRL {
+                self.flash
+            }
+
+            /// Returns the size of the flash, in bytes
+            pub fn capacity(&self) -> usize {
+                self.size
+            }
+
+            /// Erases the page starting at `address`, which must be page
+            /// aligned. Erased flash reads as `0xFF`.
+            pub fn erase_page(&mut self, address: usize) -> Result<(), FlashError> {
+                if address % Self::PAGE_SIZE != 0 {
+                    return Err(FlashError::NotAligned);
+                }
+                $crate::flash::check_range(address, Self::PAGE_SIZE, self.size, &self.image)?;
+                self.clear_errors();
+                unsafe {
+                    self.flash.fma.write(|w| w.bits(address as u32));
+                    self.flash
+                        .fmc
+                        .write(|w| w.bits(self.key | $crate::flash::FMC_ERASE));
+                }
+                while self.flash.fmc.read().bits() & $crate::flash::FMC_ERASE != 0 {}
+                self.check_errors()
+            }
+
+            /// Erases the whole flash. This would erase the running image, so
+            /// it's refused unless `image` was empty.
+            pub fn mass_erase(&mut self) -> Result<(), FlashError> {
+                if !self.image.is_empty() {
+                    return Err(FlashError::RunningImage);
+                }
+                self.clear_errors();
+                unsafe {
+                    self.flash
+                        .fmc
+                        .write(|w| w.bits(self.key | $crate::flash::FMC_MERASE));
+                }
+                while self.flash.fmc.read().bits() & $crate::flash::FMC_MERASE != 0 {}
+                self.check_errors()
+            }
+
+            /// Programs `word` at `address`, which must be word aligned
+            pub fn program_word(&mut self, address: usize, word: u32) -> Result<(), FlashError> {
+                if address % $crate::flash::BYTES_PER_WORD != 0 {
+                    return Err(FlashError::NotAligned);
+                }
+                $crate::flash::check_range(
+                    address,
+                    $crate::flash::BYTES_PER_WORD,
+                    self.size,
+                    &self.image,
+                )?;
+                self.clear_errors();
+                unsafe {
+                    self.flash.fma.write(|w| w.bits(address as u32));
+                    self.flash.fmd.write(|w| w.bits(word));
+                    self.flash
+                        .fmc
+                        .write(|w| w.bits(self.key | $crate::flash::FMC_WRITE));
+                }
+                while self.flash.fmc.read().bits() & $crate::flash::FMC_WRITE != 0 {}
+                self.check_errors()
+            }
+
+            /// Programs `words` from `address`, which must be word aligned,
+            /// through the write buffer, up to
+            /// [`WRITE_BUFFER_WORDS`]($crate::flash::WRITE_BUFFER_WORDS) words
+            /// at a time
+            pub fn program(&mut self, address: usize, words: &[u32]) -> Result<(), FlashError> {
+                self.program_with(address, words.len(), |index| words[index])
+            }
+
+            /// Programs `count` words from `address`, getting each one by its
+            /// index from `word`
+            fn program_with(
+                &mut self,
+                address: usize,
+                count: usize,
+                word: impl Fn(usize) -> u32,
+            ) -> Result<(), FlashError> {
+                const WORD: usize = $crate::flash::BYTES_PER_WORD;
+                const BUFFER: usize = $crate::flash::WRITE_BUFFER_WORDS * WORD;
+
+                if address % WORD != 0 {
+                    return Err(FlashError::NotAligned);
+                }
+                $crate::flash::check_range(address, count * WORD, self.size, &self.image)?;
+
+                let mut done = 0;
+                while done < count {
+                    // The buffer covers one aligned block of 32 words, and
+                    // only the words we load into it are programmed
+                    let at = address + done * WORD;
+                    let base = at - at % BUFFER;
+                    let first = (at - base) / WORD;
+                    let last = ($crate::flash::WRITE_BUFFER_WORDS).min(first + count - done);
+
+                    self.clear_errors();
+                    unsafe {
+                        self.flash.fma.write(|w| w.bits(base as u32));
+                        let buffer = Self::register($crate::flash::FWB_OFFSET);
+                        for slot in first..last {
+                            core::ptr::write_volatile(buffer.add(slot), word(done + slot - first));
+                        }
+                        self.flash
+                            .fmc2
+                            .write(|w| w.bits(self.key | $crate::flash::FMC2_WRBUF));
+                    }
+                    while self.flash.fmc2.read().bits() & $crate::flash::FMC2_WRBUF != 0 {}
+                    self.check_errors()?;
+                    done += last - first;
+                }
+                Ok(())
+            }
+
+            /// Reads the flash protection and boot configuration. This is what's
+            /// in force now, which may include changes that haven't been
+            /// committed.
+            pub fn protection(&self) -> ProtectionConfig {
+                let mut read = [0; $protection_registers];
+                let mut program = [0; $protection_registers];
+                for n in 0..$protection_registers {
+                    unsafe {
+                        read[n] = core::ptr::read_volatile(Self::register(
+                            $crate::flash::FMPRE_OFFSET + n * $crate::flash::BYTES_PER_WORD,
+                        ));
+                        program[n] = core::ptr::read_volatile(Self::register(
+                            $crate::flash::FMPPE_OFFSET + n * $crate::flash::BYTES_PER_WORD,
+                        ));
+                    }
+                }
+                let boot = unsafe {
+                    core::ptr::read_volatile(Self::register($crate::flash::BOOTCFG_OFFSET))
+                };
+                ProtectionConfig::from_registers(
+                    read,
+                    program,
+                    $protection_block,
+                    self.size / $protection_block,
+                    $crate::flash::BootConfig::from_bits(boot),
+                )
+            }
+
+            /// Changes the flash protection and boot configuration to `config`,
+            /// and returns the changes that makes. Protection can only be
+            /// tightened, so this fails with `Irreversible` if `config` would
+            /// loosen it.
+            ///
+            /// Use `DryRun` first to see what would change. `UntilReset` makes
+            /// the changes until the next power-on reset, and `Commit` makes
+            /// them permanent, along with any earlier `UntilReset` changes.
+            pub fn set_protection(
+                &mut self,
+                config: &ProtectionConfig,
+                mode: $crate::flash::ProtectionMode,
+            ) -> Result<ProtectionChanges, FlashError> {
+                let changes = self.protection().changes(config)?;
+                match mode {
+                    $crate::flash::ProtectionMode::DryRun => return Ok(changes),
+                    $crate::flash::ProtectionMode::UntilReset if changes.changes_boot() => {
+                        return Err(FlashError::CommitRequired)
+                    }
+                    _ => {}
+                }
+
+                for n in 0..$protection_registers {
+                    unsafe {
+                        core::ptr::write_volatile(
+                            Self::register(
+                                $crate::flash::FMPRE_OFFSET + n * $crate::flash::BYTES_PER_WORD,
+                            ),
+                            config.read_registers()[n],
+                        );
+                        core::ptr::write_volatile(
+                            Self::register(
+                                $crate::flash::FMPPE_OFFSET + n * $crate::flash::BYTES_PER_WORD,
+                            ),
+                            config.program_registers()[n],
+                        );
+                    }
+                }
+
+                if mode == $crate::flash::ProtectionMode::Commit {
+                    // FMPREn is committed through FMA 2n, and FMPPEn through 2n + 1
+                    for n in 0..(2 * $protection_registers) {
+                        self.commit(n as u32)?;
+                    }
+                    if changes.changes_boot() {
+                        // Committing BOOTCFG clears NW, which marks it as written
+                        let boot = config.boot().bits() & !(1 << 31);
+                        unsafe {
+                            self.flash.fmd.write(|w| w.bits(boot));
+                        }
+                        self.commit($crate::flash::BOOTCFG_COMMIT_ADDRESS)?;
+                    }
+                }
+                Ok(changes)
+            }
+
+            /// Commits the register selected by `address`
+            fn commit(&mut self, address: u32) -> Result<(), FlashError> {
+                self.clear_errors();
+                unsafe {
+                    self.flash.fma.write(|w| w.bits(address));
+                    self.flash
+                        .fmc
+                        .write(|w| w.bits(self.key | $crate::flash::FMC_COMT));
+                }
+                while self.flash.fmc.read().bits() & $crate::flash::FMC_COMT != 0 {}
+                self.check_errors()
+            }
+
+            /// A flash controller register that isn't in the PAC, or is an
+            /// array there isn't a PAC array for, at `offset`
+            fn register(offset: usize) -> *mut u32 {
+                unsafe { (FLASH_CTRL::ptr() as *mut u8).add(offset) as *mut u32 }
+            }
+
+            /// Clears the error flags, so that we see only this operation's
+            fn clear_errors(&self) {
+                // Write-one-to-clear, and this leaves the EEPROM's bit alone
+                unsafe {
+                    self.flash
+                        .fcmisc
+                        .write(|w| w.bits($crate::flash::FCRIS_ARIS | $crate::flash::FCRIS_ERRORS));
+                }
+            }
+
+            /// Checks whether the last operation failed
+            fn check_errors(&self) -> Result<(), FlashError> {
+                let status = self.flash.fcris.read().bits();
+                if status & $crate::flash::FCRIS_ARIS != 0 {
+                    Err(FlashError::Protected)
+                } else if status & $crate::flash::FCRIS_ERRORS != 0 {
+                    Err(FlashError::Failed)
+                } else {
+                    Ok(())
+                }
+            }
+
+            /// Reads the word at `address`, which is word aligned
+            fn read_word(&self, address: usize) -> u32 {
+                // The flash is mapped from address 0
+                unsafe { core::ptr::read_volatile(address as *const u32) }
+            }
+        }
+
+        impl $crate::flash::embedded_storage::nor_flash::ErrorType for Flash {
+            type Error = FlashError;
+        }
+
+        impl $crate::flash::embedded_storage::nor_flash::ReadNorFlash for Flash {
+            const READ_SIZE: usize = 1;
+
+            fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+                const WORD: usize = $crate::flash::BYTES_PER_WORD;
+
+                $crate::flash::embedded_storage::nor_flash::check_read(self, offset, bytes.len())?;
+                let mut position = offset as usize;
+                let mut done = 0;
+                while done < bytes.len() {
+                    let skip = position % WORD;
+                    let count = (WORD - skip).min(bytes.len() - done);
+                    let word = self.read_word(position - skip).to_le_bytes();
+                    bytes[done..done + count].copy_from_slice(&word[skip..skip + count]);
+                    position += count;
+                    done += count;
+                }
+                Ok(())
+            }
+
+            fn capacity(&self) -> usize {
+                self.size
+            }
+        }
+
+        impl $crate::flash::embedded_storage::nor_flash::NorFlash for Flash {
+            const WRITE_SIZE: usize = $crate::flash::BYTES_PER_WORD;
+            const ERASE_SIZE: usize = $page_size;
+
+            fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+                $crate::flash::embedded_storage::nor_flash::check_erase(self, from, to)?;
+                $crate::flash::check_range(
+                    from as usize,
+                    (to - from) as usize,
+                    self.size,
+                    &self.image,
+                )?;
+                for page in (from as usize..to as usize).step_by(Self::PAGE_SIZE) {
+                    self.erase_page(page)?;
+                }
+                Ok(())
+            }
+
+            fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+                const WORD: usize = $crate::flash::BYTES_PER_WORD;
+
+                $crate::flash::embedded_storage::nor_flash::check_write(self, offset, bytes.len())?;
+                self.program_with(offset as usize, bytes.len() / WORD, |index| {
+                    let mut word = [0; WORD];
+                    word.copy_from_slice(&bytes[index * WORD..(index + 1) * WORD]);
+                    u32::from_le_bytes(word)
+                })
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_map_to_register_bits() {
+        // A 1 MiB TM4C129x: sixteen registers of 2 KiB blocks
+        let all = [0xFFFF_FFFF; 16];
+        let mut config = ProtectionConfig::from_registers(
+            all,
+            all,
+            2048,
+            1024 * 1024 / 2048,
+            BootConfig::from_bits(!0),
+        );
+        assert_eq!(config.block_count(), 512);
+        assert_eq!(config.block_of(0x0000_07FF), 0);
+        assert_eq!(config.block_of(0x0001_0000), 32);
+        assert_eq!(config.block_of(0x000F_FFFF), 511);
+
+        config
+            .set_read_enabled(config.block_of(0x0001_0000), false)
+            .unwrap();
+        config
+            .set_program_enabled(config.block_of(0x000F_F800), false)
+            .unwrap();
+        assert_eq!(config.read_registers()[1], !1);
+        assert_eq!(config.program_registers()[15], !(1 << 31));
+        assert_eq!(
+            config.read_registers().iter().filter(|&&r| r != !0).count(),
+            1
+        );
+        assert_eq!(
+            config
+                .program_registers()
+                .iter()
+                .filter(|&&r| r != !0)
+                .count(),
+            1
+        );
+        assert_eq!(config.read_enabled(512), Err(FlashError::OutOfBounds));
+    }
+}
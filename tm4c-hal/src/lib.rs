@@ -5,13 +5,20 @@
 #![allow(deprecated)]
 
 pub mod bb;
+mod crc;
 pub mod delay;
 pub mod eeprom;
+pub mod flash;
 pub mod gpio;
 pub mod i2c;
+pub mod rom;
 pub mod serial;
 pub mod sysctl;
+pub mod systick;
 pub mod time;
+pub mod time_driver;
+pub mod udma;
+pub mod update;
 
 ///! An internal macro to implement the GPIO functionality for each port
 #[macro_export]
@@ -569,25 +576,30 @@ macro_rules! gpio_macro {
 #[macro_export]
 macro_rules! uart_hal_macro {
     ($(
-        $UARTX:ident: ($powerDomain:ident, $uartX:ident),
+        $UARTX:ident: (
+            $powerDomain:ident,
+            $uartX:ident,
+            dma: ($rx_channel:expr, $tx_channel:expr, $encoding:expr)
+        ),
     )+) => {
         $crate::uart_traits_macro!();
 
         $(
             impl<TX, RX, RTS, CTS> Serial<$UARTX, TX, RX, RTS, CTS> {
                 /// Configures a UART peripheral to provide serial communication
-                pub fn $uartX(
+                pub fn $uartX<B>(
                     mut uart: $UARTX,
                     tx_pin: TX,
                     rx_pin: RX,
                     mut rts_pin: RTS,
                     mut cts_pin: CTS,
-                    baud_rate: Bps,
+                    baud_rate: B,
                     nl_mode: NewlineMode,
                     clocks: &Clocks,
                     pc: &sysctl::PowerControl
                 ) -> Self
                 where
+                    B: Into<Bps>,
                     TX: TxPin<$UARTX>,
                     RX: RxPin<$UARTX>,
                     CTS: CtsPin<$UARTX>,
@@ -602,6 +614,8 @@ macro_rules! uart_hal_macro {
                     // Reset UART
                     uart.ctl.reset();
 
+                    let baud_rate = baud_rate.into();
+
                     // Calculate baud rate dividers
                     // baud_int = 64 * (sys_clk / (16 * baud))
                     // baud_int = 4 * (sys_clk / baud)
@@ -630,7 +644,12 @@ macro_rules! uart_hal_macro {
                 /// Change the current baud rate for the UART. We need the
                 /// `clocks` object in order to calculate the magic baud rate
                 /// register values.
-                pub fn change_baud_rate(&mut self, baud_rate: Bps, clocks: &Clocks) {
+                pub fn change_baud_rate<B>(&mut self, baud_rate: B, clocks: &Clocks)
+                where
+                    B: Into<Bps>,
+                {
+                    let baud_rate = baud_rate.into();
+
                     // Stop UART
                     self.uart.ctl.modify(|_, w| w.uarten().bit(false));
 
@@ -643,9 +662,23 @@ macro_rules! uart_hal_macro {
                     self.uart.fbrd.write(|w|
                         unsafe { w.divfrac().bits((baud_int % 64) as u8) });
 
-                    // Set data bits / parity / stop bits / enable fifo
+                    // Keep the frame format as it was
                     // If you don't write to this register, the baud rate change doesn't take effect
-                    self.uart.lcrh.write(|w| w.wlen()._8().fen().bit(true));
+                    self.uart.lcrh.modify(|_, w| w);
+
+                    // Start UART again
+                    self.uart.ctl.modify(|_, w| w.uarten().bit(true));
+                }
+
+                /// Changes the frame format (data bits, parity and stop bits)
+                /// and whether the FIFOs are enabled. Anything still being
+                /// sent is sent in the old format first.
+                pub fn set_config(&mut self, config: Config) {
+                    // Wait for the transmitter to finish, then stop UART
+                    while self.uart.fr.read().busy().bit() {}
+                    self.uart.ctl.modify(|_, w| w.uarten().bit(false));
+
+                    self.uart.lcrh.write(|w| unsafe { w.bits(config.lcrh_bits()) });
 
                     // Start UART again
                     self.uart.ctl.modify(|_, w| w.uarten().bit(true));
@@ -710,27 +743,77 @@ macro_rules! uart_hal_macro {
                 }
             }
 
+            impl<RX, CTS> Rx<$UARTX, RX, CTS> {
+                /// Reads a character, or the error it was received with.
+                /// Unless `keep_error` is set, the error is cleared from
+                /// UARTRSR; otherwise the next read reports it again. An
+                /// overrun doesn't spoil the character read along with it,
+                /// so that is returned, and UARTRSR reports the overrun on
+                /// the next read.
+                fn receive(keep_error: bool) -> nb::Result<u8, Error> {
+                    // We're only doing RX operations here so this is safe.
+                    let p = unsafe { &*$UARTX::ptr() };
+                    let base = $UARTX::ptr() as *const ();
+                    let result = match unsafe { $crate::serial::pending_error(base) } {
+                        Some(error) => Err(nb::Error::Other(error)),
+                        None if p.fr.read().rxfe().bit() => return Err(nb::Error::WouldBlock),
+                        None => {
+                            let dr = p.dr.read();
+                            match Error::from_dr_bits(dr.bits()) {
+                                None | Some(Error::Overrun) => return Ok(dr.data().bits()),
+                                Some(error) => Err(nb::Error::Other(error)),
+                            }
+                        }
+                    };
+                    if !keep_error {
+                        unsafe { $crate::serial::clear_errors(base) };
+                    }
+                    result
+                }
+
+                /// Reads at least one character into `buffer`, unless it is
+                /// empty, and then as many more as have been received. An
+                /// error after the first character ends the read early, and
+                /// is reported by the next one.
+                fn receive_into(buffer: &mut [u8]) -> Result<usize, Error> {
+                    let mut count = 0;
+                    while count < buffer.len() {
+                        match Self::receive(count > 0) {
+                            Ok(byte) => {
+                                buffer[count] = byte;
+                                count += 1;
+                            }
+                            Err(nb::Error::WouldBlock) if count > 0 => break,
+                            Err(nb::Error::WouldBlock) => {}
+                            Err(nb::Error::Other(_)) if count > 0 => break,
+                            Err(nb::Error::Other(error)) => return Err(error),
+                        }
+                    }
+                    Ok(count)
+                }
+
+                /// Has a character, or an error, been received?
+                fn receive_ready() -> bool {
+                    // We're only doing RX operations here so this is safe.
+                    let p = unsafe { &*$UARTX::ptr() };
+                    let base = $UARTX::ptr() as *const ();
+                    !p.fr.read().rxfe().bit() || unsafe { $crate::serial::pending_error(base) }.is_some()
+                }
+            }
+
             impl<TX, RX, RTS, CTS> serial::Read<u8> for Serial<$UARTX, TX, RX, RTS, CTS> {
-                type Error = Void;
+                type Error = Error;
 
                 fn read(&mut self) -> nb::Result<u8, Self::Error> {
-                    if self.uart.fr.read().rxfe().bit() {
-                        return Err(nb::Error::WouldBlock);
-                    }
-                    Ok(self.uart.dr.read().data().bits())
+                    Rx::<$UARTX, (), ()>::receive(false)
                 }
             }
 
             impl<RX, CTS> serial::Read<u8> for Rx<$UARTX, RX, CTS> {
-                type Error = Void;
+                type Error = Error;
 
                 fn read(&mut self) -> nb::Result<u8, Self::Error> {
-                    // We're only doing RX operations here so this is safe.
-                    let p = unsafe { &*$UARTX::ptr() };
-                    if p.fr.read().rxfe().bit() {
-                        return Err(nb::Error::WouldBlock);
-                    }
-                    Ok(p.dr.read().data().bits())
+                    Self::receive(false)
                 }
             }
 
@@ -810,6 +893,644 @@ macro_rules! uart_hal_macro {
                 }
             }
 
+            impl<TX, RTS> Tx<$UARTX, TX, RTS> {
+                /// Writes as many bytes of `data` as fit in the transmit
+                /// FIFO, waiting for room for the first one
+                fn transmit(data: &[u8]) -> usize {
+                    // We're only doing TX operations here so this is safe.
+                    let p = unsafe { &*$UARTX::ptr() };
+                    let mut count = 0;
+                    for &byte in data {
+                        if p.fr.read().txff().bit() {
+                            if count > 0 {
+                                break;
+                            }
+                            while p.fr.read().txff().bit() {}
+                        }
+                        p.dr.write(|w| unsafe { w.data().bits(byte) });
+                        count += 1;
+                    }
+                    count
+                }
+
+                /// Is there room in the transmit FIFO?
+                fn transmit_ready() -> bool {
+                    // We're only doing TX operations here so this is safe.
+                    let p = unsafe { &*$UARTX::ptr() };
+                    !p.fr.read().txff().bit()
+                }
+
+                /// Has everything written been sent?
+                fn transmit_done() -> bool {
+                    // We're only doing TX operations here so this is safe.
+                    let p = unsafe { &*$UARTX::ptr() };
+                    !p.fr.read().busy().bit()
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> Serial<$UARTX, TX, RX, RTS, CTS> {
+                /// Sets how full the receive FIFO must get, and how empty
+                /// the transmit FIFO must get, to raise an interrupt
+                pub fn set_fifo_levels(&mut self, rx: FifoLevel, tx: FifoLevel) {
+                    self.uart.ifls.write(|w| unsafe { w.bits(FifoLevel::ifls_bits(rx, tx)) });
+                }
+
+                /// Hands the UART over to its interrupt handler, which moves
+                /// received bytes into `rx_buffer` and sends bytes queued in
+                /// `tx_buffer`. Forward the UART interrupt to the
+                /// `on_interrupt` function in the module named after the
+                /// UART:
+                ///
+                /// ```ignore
+                /// #[interrupt]
+                /// fn UART0() {
+                ///     serial::uart0::on_interrupt();
+                /// }
+                /// ```
+                pub fn into_buffered(
+                    self,
+                    rx_buffer: &'static mut [u8],
+                    tx_buffer: &'static mut [u8],
+                ) -> BufferedSerial<$UARTX, TX, RX, RTS, CTS> {
+                    use $crate::serial::buffered::{State, INT_RT, INT_RX, INT_TX};
+
+                    let state = State::new(rx_buffer, tx_buffer);
+                    cortex_m::interrupt::free(|cs| $uartX::state().borrow(cs).replace(Some(state)));
+                    self.uart.icr.write(|w| unsafe { w.bits(INT_RX | INT_TX | INT_RT) });
+                    self.uart
+                        .im
+                        .modify(|r, w| unsafe { w.bits(r.bits() | INT_RX | INT_TX | INT_RT) });
+                    unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::$UARTX) };
+                    BufferedSerial { serial: self }
+                }
+            }
+
+            /// The interrupt handler for a [`BufferedSerial`], or uDMA
+            /// transfers, on this UART
+            pub mod $uartX {
+                use super::*;
+                use $crate::serial::buffered::{Ring, State, INT_RT, INT_RX, INT_TX};
+
+                /// The buffers, shared with the interrupt handler
+                pub(super) fn state(
+                ) -> &'static cortex_m::interrupt::Mutex<core::cell::RefCell<Option<State>>> {
+                    static STATE: cortex_m::interrupt::Mutex<core::cell::RefCell<Option<State>>> =
+                        cortex_m::interrupt::Mutex::new(core::cell::RefCell::new(None));
+                    &STATE
+                }
+
+                /// Runs `f` on the buffers, which must be installed
+                pub(super) fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+                    cortex_m::interrupt::free(|cs| {
+                        // A `BufferedSerial` exists, so the buffers do too
+                        f(state().borrow(cs).borrow_mut().as_mut().unwrap())
+                    })
+                }
+
+                /// Moves bytes from `tx` into the transmit FIFO until one of
+                /// them runs out. Returns whether any were moved.
+                pub(super) fn fill_fifo(tx: &mut Ring) -> bool {
+                    // Only called with the buffers borrowed, so nothing else
+                    // is writing to the UART
+                    let p = unsafe { &*$UARTX::ptr() };
+                    let mut sent = false;
+                    while !p.fr.read().txff().bit() {
+                        match tx.pop() {
+                            Some(byte) => p.dr.write(|w| unsafe { w.data().bits(byte) }),
+                            None => break,
+                        }
+                        sent = true;
+                    }
+                    sent
+                }
+
+                /// The uDMA receive transfer, shared with the interrupt handler
+                pub(super) fn dma_rx(
+                ) -> &'static cortex_m::interrupt::Mutex<core::cell::RefCell<$crate::udma::Transfer>> {
+                    static TRANSFER: cortex_m::interrupt::Mutex<core::cell::RefCell<$crate::udma::Transfer>> =
+                        cortex_m::interrupt::Mutex::new(core::cell::RefCell::new($crate::udma::Transfer::new()));
+                    &TRANSFER
+                }
+
+                /// The uDMA transmit transfer, shared with the interrupt handler
+                pub(super) fn dma_tx(
+                ) -> &'static cortex_m::interrupt::Mutex<core::cell::RefCell<$crate::udma::Transfer>> {
+                    static TRANSFER: cortex_m::interrupt::Mutex<core::cell::RefCell<$crate::udma::Transfer>> =
+                        cortex_m::interrupt::Mutex::new(core::cell::RefCell::new($crate::udma::Transfer::new()));
+                    &TRANSFER
+                }
+
+                /// The uDMA control structure to receive `length` bytes
+                /// into `buffer`
+                pub(super) fn rx_descriptor(buffer: usize, length: usize, ping_pong: bool) -> $crate::udma::Descriptor {
+                    // UARTDR is at the start of the UART
+                    $crate::udma::Descriptor::peripheral_to_memory($UARTX::ptr() as usize, buffer, length, ping_pong)
+                }
+
+                /// The uDMA control structure to send the `length` bytes at
+                /// `buffer`
+                pub(super) fn tx_descriptor(buffer: usize, length: usize, ping_pong: bool) -> $crate::udma::Descriptor {
+                    $crate::udma::Descriptor::memory_to_peripheral(buffer, length, $UARTX::ptr() as usize, ping_pong)
+                }
+
+                /// Moves bytes between the FIFOs and the buffers of a
+                /// buffered UART, and moves uDMA transfers on. Call this from
+                /// the UART interrupt handler.
+                pub fn on_interrupt() {
+                    // Write-one-to-clear, so this is safe
+                    let p = unsafe { &*$UARTX::ptr() };
+                    p.icr.write(|w| unsafe { w.bits(INT_RX | INT_TX | INT_RT | DMA_RX_INTERRUPT | DMA_TX_INTERRUPT) });
+
+                    let mut wakers: [Option<core::task::Waker>; 4] = Default::default();
+                    cortex_m::interrupt::free(|cs| {
+                        if let Some(state) = state().borrow(cs).borrow_mut().as_mut() {
+                            let mut received = false;
+                            loop {
+                                match Rx::<$UARTX, (), ()>::receive(false) {
+                                    Ok(byte) => state.receive(Ok(byte)),
+                                    Err(nb::Error::Other(error)) => state.receive(Err(error)),
+                                    Err(nb::Error::WouldBlock) => break,
+                                }
+                                received = true;
+                            }
+                            if received {
+                                wakers[0] = state.take_rx_waker();
+                            }
+                            if fill_fifo(&mut state.tx) {
+                                wakers[1] = state.take_tx_waker();
+                            }
+                        }
+
+                        // The channels are only used with their transfer
+                        // borrowed, so this is safe. Both control structures
+                        // can finish before we get here, so count the ones
+                        // the uDMA has stopped rather than the interrupts.
+                        let mut transfer = dma_rx().borrow(cs).borrow_mut();
+                        unsafe { $crate::udma::take_done($rx_channel) };
+                        while transfer.is_active()
+                            && unsafe { $crate::udma::is_stopped($rx_channel, transfer.next_alternate()) }
+                        {
+                            let (alternate, waker) = transfer.complete();
+                            if transfer.is_active() {
+                                // Fill the half just completed again
+                                let half = if alternate {
+                                    $crate::udma::DmaEvent::Complete
+                                } else {
+                                    $crate::udma::DmaEvent::HalfComplete
+                                };
+                                let (buffer, length) = transfer.half(half);
+                                unsafe {
+                                    $crate::udma::set_descriptor(
+                                        $rx_channel,
+                                        alternate,
+                                        &rx_descriptor(buffer, length, true),
+                                    );
+                                }
+                            }
+                            wakers[2] = waker.or(wakers[2].take());
+                        }
+
+                        let mut transfer = dma_tx().borrow(cs).borrow_mut();
+                        unsafe { $crate::udma::take_done($tx_channel) };
+                        while transfer.is_active()
+                            && unsafe { $crate::udma::is_stopped($tx_channel, transfer.next_alternate()) }
+                        {
+                            let waker = transfer.complete().1;
+                            wakers[3] = waker.or(wakers[3].take());
+                        }
+                    });
+                    for waker in wakers.iter_mut().filter_map(Option::take) {
+                        waker.wake();
+                    }
+                }
+            }
+
+            impl<RX, CTS> Rx<$UARTX, RX, CTS> {
+                /// Starts receiving into `buffer` by uDMA, one half at a time
+                /// and for ever: once the second half is full, the first is
+                /// filled again. The buffer must be of even length, at most
+                /// 2048 bytes.
+                ///
+                /// [`dma_event`](Self::dma_event) reports each half as it
+                /// fills, and [`dma_half`](Self::dma_half) gives its
+                /// contents, which must be dealt with before the other half
+                /// fills. The UART interrupt must be forwarded to the
+                /// `on_interrupt` function in the module named after the
+                /// UART.
+                pub fn read_dma(&mut self, _udma: &mut Udma, buffer: &'static mut [u8]) -> Result<(), DmaError> {
+                    let (primary, alternate) = cortex_m::interrupt::free(|cs| {
+                        $uartX::dma_rx().borrow(cs).borrow_mut().start(
+                            buffer.as_mut_ptr() as usize,
+                            buffer.len(),
+                            true,
+                            $uartX::rx_descriptor,
+                        )
+                    })?;
+                    // The transfer is ours, so the channel is too
+                    unsafe {
+                        $crate::udma::assign($rx_channel, $encoding);
+                        $crate::udma::set_descriptor($rx_channel, false, &primary);
+                        if let Some(alternate) = alternate {
+                            $crate::udma::set_descriptor($rx_channel, true, &alternate);
+                        }
+                        $crate::udma::enable($rx_channel);
+                    }
+
+                    // We're only doing RX operations here so this is safe.
+                    let p = unsafe { &*$UARTX::ptr() };
+                    p.im.modify(|r, w| unsafe { w.bits(r.bits() | DMA_RX_INTERRUPT) });
+                    p.dmactl.modify(|r, w| unsafe { w.bits(r.bits() | $crate::serial::DMACTL_RXDMAE) });
+                    unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::$UARTX) };
+                    Ok(())
+                }
+
+                /// Takes the next half of the buffer filled by
+                /// [`read_dma`](Self::read_dma), if there is one. Fails
+                /// with `Overrun` if a half was filled again before it was
+                /// taken, after which it carries on from the latest half.
+                pub fn dma_event(&mut self) -> Result<Option<DmaEvent>, DmaError> {
+                    cortex_m::interrupt::free(|cs| $uartX::dma_rx().borrow(cs).borrow_mut().take_event())
+                }
+
+                /// Waits for the next half of the buffer to be filled. Gives
+                /// `None` straight away if no transfer is running, or once it
+                /// has been stopped.
+                pub async fn wait_dma_event(&mut self) -> Result<Option<DmaEvent>, DmaError> {
+                    core::future::poll_fn(|cx| {
+                        cortex_m::interrupt::free(|cs| {
+                            $uartX::dma_rx().borrow(cs).borrow_mut().poll_event(cx.waker())
+                        })
+                    })
+                    .await
+                }
+
+                /// Returns the half of the buffer that `event` reported as
+                /// filled
+                ///
+                /// # Safety
+                ///
+                /// `event` must be the one [`dma_event`](Self::dma_event) or
+                /// [`wait_dma_event`](Self::wait_dma_event) returned last,
+                /// and the slice must be dropped before the other half
+                /// fills, as the uDMA then starts filling this half again.
+                pub unsafe fn dma_half(&self, event: DmaEvent) -> &[u8] {
+                    let (buffer, length) =
+                        cortex_m::interrupt::free(|cs| $uartX::dma_rx().borrow(cs).borrow().half(event));
+                    if length == 0 {
+                        return &[];
+                    }
+                    core::slice::from_raw_parts(buffer as *const u8, length)
+                }
+
+                /// Stops receiving by uDMA, and gives back the buffer
+                pub fn stop_dma(&mut self, _udma: &mut Udma) -> Option<&'static mut [u8]> {
+                    // We're only doing RX operations here so this is safe.
+                    let p = unsafe { &*$UARTX::ptr() };
+                    p.dmactl.modify(|r, w| unsafe { w.bits(r.bits() & !$crate::serial::DMACTL_RXDMAE) });
+                    p.im.modify(|r, w| unsafe { w.bits(r.bits() & !DMA_RX_INTERRUPT) });
+                    cortex_m::interrupt::free(|cs| {
+                        let mut transfer = $uartX::dma_rx().borrow(cs).borrow_mut();
+                        if !transfer.is_active() {
+                            return None;
+                        }
+                        unsafe { $crate::udma::disable($rx_channel) };
+                        transfer.stop();
+                        let (buffer, length) = transfer.buffer();
+                        Some(unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, length) })
+                    })
+                }
+            }
+
+            impl<TX, RTS> Tx<$UARTX, TX, RTS> {
+                /// Starts sending `data`, at most 2048 bytes, by uDMA.
+                ///
+                /// [`dma_event`](Self::dma_event) reports when the first
+                /// half, and then the rest, has been handed to the transmit
+                /// FIFO. The UART interrupt must be forwarded to the
+                /// `on_interrupt` function in the module named after the
+                /// UART.
+                pub fn write_dma(&mut self, _udma: &mut Udma, data: &'static [u8]) -> Result<(), DmaError> {
+                    let (primary, alternate) = cortex_m::interrupt::free(|cs| {
+                        $uartX::dma_tx().borrow(cs).borrow_mut().start(
+                            data.as_ptr() as usize,
+                            data.len(),
+                            false,
+                            $uartX::tx_descriptor,
+                        )
+                    })?;
+                    // The transfer is ours, so the channel is too
+                    unsafe {
+                        $crate::udma::assign($tx_channel, $encoding);
+                        $crate::udma::set_descriptor($tx_channel, false, &primary);
+                        if let Some(alternate) = alternate {
+                            $crate::udma::set_descriptor($tx_channel, true, &alternate);
+                        }
+                        $crate::udma::enable($tx_channel);
+                    }
+
+                    self.uart.im.modify(|r, w| unsafe { w.bits(r.bits() | DMA_TX_INTERRUPT) });
+                    self.uart
+                        .dmactl
+                        .modify(|r, w| unsafe { w.bits(r.bits() | $crate::serial::DMACTL_TXDMAE) });
+                    unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::$UARTX) };
+                    Ok(())
+                }
+
+                /// Takes the next half of the data sent by
+                /// [`write_dma`](Self::write_dma), if there is one
+                pub fn dma_event(&mut self) -> Option<DmaEvent> {
+                    cortex_m::interrupt::free(|cs| $uartX::dma_tx().borrow(cs).borrow_mut().take_event())
+                        .unwrap_or(None)
+                }
+
+                /// Waits for the next half of the data to be sent. Gives
+                /// `None` straight away if no transfer is running, or once
+                /// both halves have been reported.
+                pub async fn wait_dma_event(&mut self) -> Option<DmaEvent> {
+                    core::future::poll_fn(|cx| {
+                        cortex_m::interrupt::free(|cs| {
+                            $uartX::dma_tx().borrow(cs).borrow_mut().poll_event(cx.waker())
+                        })
+                    })
+                    .await
+                    .unwrap_or(None)
+                }
+
+                /// Is a uDMA transfer still going?
+                pub fn is_dma_busy(&self) -> bool {
+                    cortex_m::interrupt::free(|cs| $uartX::dma_tx().borrow(cs).borrow().is_active())
+                }
+
+                /// Stops sending by uDMA. Data already in the transmit FIFO
+                /// is still sent.
+                pub fn stop_dma(&mut self, _udma: &mut Udma) {
+                    self.uart
+                        .dmactl
+                        .modify(|r, w| unsafe { w.bits(r.bits() & !$crate::serial::DMACTL_TXDMAE) });
+                    self.uart.im.modify(|r, w| unsafe { w.bits(r.bits() & !DMA_TX_INTERRUPT) });
+                    cortex_m::interrupt::free(|cs| {
+                        let mut transfer = $uartX::dma_tx().borrow(cs).borrow_mut();
+                        if transfer.is_active() {
+                            unsafe { $crate::udma::disable($tx_channel) };
+                            transfer.stop();
+                        }
+                    });
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> BufferedSerial<$UARTX, TX, RX, RTS, CTS> {
+                /// Sets how full the receive FIFO must get, and how empty
+                /// the transmit FIFO must get, to raise an interrupt
+                pub fn set_fifo_levels(&mut self, rx: FifoLevel, tx: FifoLevel) {
+                    self.serial.set_fifo_levels(rx, tx);
+                }
+
+                /// Takes the UART back from its interrupt handler, returning
+                /// it and the buffers. Anything not yet sent or read is lost.
+                pub fn free(self) -> (Serial<$UARTX, TX, RX, RTS, CTS>, &'static mut [u8], &'static mut [u8]) {
+                    use $crate::serial::buffered::{INT_RT, INT_RX, INT_TX};
+
+                    self.serial
+                        .uart
+                        .im
+                        .modify(|r, w| unsafe { w.bits(r.bits() & !(INT_RX | INT_TX | INT_RT)) });
+                    let state = cortex_m::interrupt::free(|cs| $uartX::state().borrow(cs).take());
+                    // A `BufferedSerial` exists, so the buffers do too
+                    let (rx_buffer, tx_buffer) = state.unwrap().free();
+                    (self.serial, rx_buffer, tx_buffer)
+                }
+
+                /// Queues as much of `data` as fits, and starts sending it.
+                /// Returns how many bytes were queued.
+                fn queue(state: &mut $crate::serial::buffered::State, data: &[u8]) -> usize {
+                    let count = state.tx.push_slice(data);
+                    $uartX::fill_fifo(&mut state.tx);
+                    count
+                }
+
+                /// Has everything queued been sent?
+                fn sent() -> bool {
+                    $uartX::with_state(|state| state.tx.is_empty()) && Tx::<$UARTX, (), ()>::transmit_done()
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io::ErrorType for BufferedSerial<$UARTX, TX, RX, RTS, CTS> {
+                type Error = Error;
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io::Read for BufferedSerial<$UARTX, TX, RX, RTS, CTS> {
+                fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+                    loop {
+                        if let Some(result) = $uartX::with_state(|state| state.read(buffer)) {
+                            return result;
+                        }
+                    }
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io::ReadReady for BufferedSerial<$UARTX, TX, RX, RTS, CTS> {
+                fn read_ready(&mut self) -> Result<bool, Error> {
+                    Ok($uartX::with_state(|state| !state.rx.is_empty() || state.rx_error.is_some()))
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io::Write for BufferedSerial<$UARTX, TX, RX, RTS, CTS> {
+                fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+                    loop {
+                        let count = $uartX::with_state(|state| Self::queue(state, data));
+                        if count > 0 || data.is_empty() {
+                            return Ok(count);
+                        }
+                    }
+                }
+
+                fn flush(&mut self) -> Result<(), Error> {
+                    while !Self::sent() {}
+                    Ok(())
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io::WriteReady for BufferedSerial<$UARTX, TX, RX, RTS, CTS> {
+                fn write_ready(&mut self) -> Result<bool, Error> {
+                    Ok($uartX::with_state(|state| !state.tx.is_full()))
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io_async::Read for BufferedSerial<$UARTX, TX, RX, RTS, CTS> {
+                async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+                    core::future::poll_fn(|cx| {
+                        $uartX::with_state(|state| match state.read(buffer) {
+                            Some(result) => core::task::Poll::Ready(result),
+                            None => {
+                                state.register_rx(cx.waker());
+                                core::task::Poll::Pending
+                            }
+                        })
+                    })
+                    .await
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io_async::Write for BufferedSerial<$UARTX, TX, RX, RTS, CTS> {
+                async fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+                    core::future::poll_fn(|cx| {
+                        $uartX::with_state(|state| match Self::queue(state, data) {
+                            0 if !data.is_empty() => {
+                                state.register_tx(cx.waker());
+                                core::task::Poll::Pending
+                            }
+                            count => core::task::Poll::Ready(Ok(count)),
+                        })
+                    })
+                    .await
+                }
+
+                async fn flush(&mut self) -> Result<(), Error> {
+                    core::future::poll_fn(|cx| {
+                        $uartX::with_state(|state| {
+                            if state.tx.is_empty() {
+                                core::task::Poll::Ready(())
+                            } else {
+                                state.register_tx(cx.waker());
+                                core::task::Poll::Pending
+                            }
+                        })
+                    })
+                    .await;
+                    // The last few bytes are still in the transmit FIFO
+                    while !Tx::<$UARTX, (), ()>::transmit_done() {}
+                    Ok(())
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_hal_nb::serial::ErrorType for Serial<$UARTX, TX, RX, RTS, CTS> {
+                type Error = Error;
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_hal_nb::serial::Read<u8> for Serial<$UARTX, TX, RX, RTS, CTS> {
+                fn read(&mut self) -> nb::Result<u8, Error> {
+                    Rx::<$UARTX, (), ()>::receive(false)
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_hal_nb::serial::Write<u8> for Serial<$UARTX, TX, RX, RTS, CTS> {
+                fn write(&mut self, byte: u8) -> nb::Result<(), Error> {
+                    if !Tx::<$UARTX, (), ()>::transmit_ready() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    Tx::<$UARTX, (), ()>::transmit(&[byte]);
+                    Ok(())
+                }
+
+                fn flush(&mut self) -> nb::Result<(), Error> {
+                    if !Tx::<$UARTX, (), ()>::transmit_done() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    Ok(())
+                }
+            }
+
+            impl<RX, CTS> $crate::serial::embedded_hal_nb::serial::ErrorType for Rx<$UARTX, RX, CTS> {
+                type Error = Error;
+            }
+
+            impl<RX, CTS> $crate::serial::embedded_hal_nb::serial::Read<u8> for Rx<$UARTX, RX, CTS> {
+                fn read(&mut self) -> nb::Result<u8, Error> {
+                    Self::receive(false)
+                }
+            }
+
+            impl<TX, RTS> $crate::serial::embedded_hal_nb::serial::ErrorType for Tx<$UARTX, TX, RTS> {
+                type Error = core::convert::Infallible;
+            }
+
+            impl<TX, RTS> $crate::serial::embedded_hal_nb::serial::Write<u8> for Tx<$UARTX, TX, RTS> {
+                fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+                    if !Self::transmit_ready() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    Self::transmit(&[byte]);
+                    Ok(())
+                }
+
+                fn flush(&mut self) -> nb::Result<(), Self::Error> {
+                    if !Self::transmit_done() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    Ok(())
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io::ErrorType for Serial<$UARTX, TX, RX, RTS, CTS> {
+                type Error = Error;
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io::Read for Serial<$UARTX, TX, RX, RTS, CTS> {
+                fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+                    Rx::<$UARTX, (), ()>::receive_into(buffer)
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io::ReadReady for Serial<$UARTX, TX, RX, RTS, CTS> {
+                fn read_ready(&mut self) -> Result<bool, Error> {
+                    Ok(Rx::<$UARTX, (), ()>::receive_ready())
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io::Write for Serial<$UARTX, TX, RX, RTS, CTS> {
+                fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+                    Ok(Tx::<$UARTX, (), ()>::transmit(data))
+                }
+
+                fn flush(&mut self) -> Result<(), Error> {
+                    while !Tx::<$UARTX, (), ()>::transmit_done() {}
+                    Ok(())
+                }
+            }
+
+            impl<TX, RX, RTS, CTS> $crate::serial::embedded_io::WriteReady for Serial<$UARTX, TX, RX, RTS, CTS> {
+                fn write_ready(&mut self) -> Result<bool, Error> {
+                    Ok(Tx::<$UARTX, (), ()>::transmit_ready())
+                }
+            }
+
+            impl<RX, CTS> $crate::serial::embedded_io::ErrorType for Rx<$UARTX, RX, CTS> {
+                type Error = Error;
+            }
+
+            impl<RX, CTS> $crate::serial::embedded_io::Read for Rx<$UARTX, RX, CTS> {
+                fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+                    Self::receive_into(buffer)
+                }
+            }
+
+            impl<RX, CTS> $crate::serial::embedded_io::ReadReady for Rx<$UARTX, RX, CTS> {
+                fn read_ready(&mut self) -> Result<bool, Error> {
+                    Ok(Self::receive_ready())
+                }
+            }
+
+            impl<TX, RTS> $crate::serial::embedded_io::ErrorType for Tx<$UARTX, TX, RTS> {
+                type Error = core::convert::Infallible;
+            }
+
+            impl<TX, RTS> $crate::serial::embedded_io::Write for Tx<$UARTX, TX, RTS> {
+                fn write(&mut self, data: &[u8]) -> Result<usize, Self::Error> {
+                    Ok(Self::transmit(data))
+                }
+
+                fn flush(&mut self) -> Result<(), Self::Error> {
+                    while !Self::transmit_done() {}
+                    Ok(())
+                }
+            }
+
+            impl<TX, RTS> $crate::serial::embedded_io::WriteReady for Tx<$UARTX, TX, RTS> {
+                fn write_ready(&mut self) -> Result<bool, Self::Error> {
+                    Ok(Self::transmit_ready())
+                }
+            }
+
         )+
     }
 }
@@ -0,0 +1,154 @@
+//! Bindings for the driver and boot loader routines in ROM.
+//!
+//! The ROM holds a table of pointers at [`API_TABLE`]. Entry 0 is the ROM
+//! version, and every other entry points at the table of functions for one
+//! peripheral. The TM4C123x and TM4C129x parts ship TivaWare in ROM, and the
+//! MSP432E4 parts ship SimpleLink; the `simplelink` feature selects the
+//! SimpleLink table indices.
+//!
+//! The bindings here are those the HAL has no better way of doing: entering
+//! the ROM boot loader, and the CRC helpers. The chip crates add the entries
+//! specific to each family, and safe wrappers for the boot loaders which
+//! take the peripheral the boot loader will use.
+
+/// The address of the ROM API table
+pub const API_TABLE: usize = 0x0100_0010;
+
+/// Table indices from TivaWare's `driverlib/rom.h`
+#[cfg(not(feature = "simplelink"))]
+#[doc(hidden)]
+pub mod index {
+    pub const VERSION: usize = 0;
+    pub const UART: usize = 1;
+    pub const SSI: usize = 2;
+    pub const I2C: usize = 3;
+    pub const USB: usize = 16;
+    pub const SOFTWARE: usize = 21;
+    pub const EMAC: usize = 33;
+
+    pub const UPDATE_UART: (usize, usize) = (UART, 21);
+    pub const UPDATE_SSI: (usize, usize) = (SSI, 11);
+    pub const UPDATE_I2C: (usize, usize) = (I2C, 12);
+    pub const UPDATE_USB: (usize, usize) = (USB, 28);
+    pub const UPDATE_EMAC: (usize, usize) = (EMAC, 71);
+    pub const CRC16_ARRAY: (usize, usize) = (SOFTWARE, 1);
+    pub const CRC16_ARRAY3: (usize, usize) = (SOFTWARE, 2);
+    pub const CRC16: (usize, usize) = (SOFTWARE, 3);
+    pub const CRC8_CCITT: (usize, usize) = (SOFTWARE, 4);
+    pub const CRC32: (usize, usize) = (SOFTWARE, 5);
+}
+
+/// Table indices for the SimpleLink ROM, which must match the MSP432E4 SDK's
+/// `driverlib/rom.h`
+#[cfg(feature = "simplelink")]
+#[doc(hidden)]
+pub mod index {
+    pub const VERSION: usize = 0;
+    pub const UART: usize = 1;
+    pub const SSI: usize = 2;
+    pub const I2C: usize = 3;
+    pub const USB: usize = 16;
+    pub const SOFTWARE: usize = 21;
+    pub const EMAC: usize = 33;
+
+    pub const UPDATE_UART: (usize, usize) = (UART, 21);
+    pub const UPDATE_SSI: (usize, usize) = (SSI, 11);
+    pub const UPDATE_I2C: (usize, usize) = (I2C, 12);
+    pub const UPDATE_USB: (usize, usize) = (USB, 28);
+    pub const UPDATE_EMAC: (usize, usize) = (EMAC, 71);
+    pub const CRC16_ARRAY: (usize, usize) = (SOFTWARE, 1);
+    pub const CRC16_ARRAY3: (usize, usize) = (SOFTWARE, 2);
+    pub const CRC16: (usize, usize) = (SOFTWARE, 3);
+    pub const CRC8_CCITT: (usize, usize) = (SOFTWARE, 4);
+    pub const CRC32: (usize, usize) = (SOFTWARE, 5);
+}
+
+/// Looks up the address of a ROM function
+///
+/// # Safety
+///
+/// `(table, function)` must be an entry which this part's ROM has.
+#[doc(hidden)]
+pub unsafe fn function((table, function): (usize, usize)) -> usize {
+    let tables = API_TABLE as *const *const usize;
+    let table = core::ptr::read_volatile(tables.add(table));
+    core::ptr::read_volatile(table.add(function))
+}
+
+/// Calls the boot loader entry `entry`, which takes no arguments, with
+/// interrupts disabled
+///
+/// # Safety
+///
+/// As for [`function`]. The boot loader runs with whatever state the
+/// application left, and resets the chip when it is done.
+#[doc(hidden)]
+pub unsafe fn enter_boot_loader(entry: (usize, usize)) -> ! {
+    cortex_m::interrupt::disable();
+    let update: extern "C" fn() = core::mem::transmute(function(entry));
+    update();
+    // The boot loader never returns, but make sure of it
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+/// Returns the version of the ROM
+pub fn version() -> u32 {
+    unsafe { core::ptr::read_volatile((API_TABLE as *const u32).add(index::VERSION)) }
+}
+
+/// Continues a CRC-16 (polynomial 0x8005) calculation over `data`, starting from `crc`
+pub fn crc16(crc: u16, data: &[u8]) -> u16 {
+    unsafe {
+        let crc16: extern "C" fn(u16, *const u8, u32) -> u16 =
+            core::mem::transmute(function(index::CRC16));
+        crc16(crc, data.as_ptr(), data.len() as u32)
+    }
+}
+
+/// Returns the CRC-16 of `words`
+pub fn crc16_array(words: &[u32]) -> u16 {
+    unsafe {
+        let crc16_array: extern "C" fn(u32, *const u32) -> u16 =
+            core::mem::transmute(function(index::CRC16_ARRAY));
+        crc16_array(words.len() as u32, words.as_ptr())
+    }
+}
+
+/// Returns three CRC-16s of `words`, each over every third byte
+pub fn crc16_array3(words: &[u32]) -> [u16; 3] {
+    let mut crcs = [0; 3];
+    unsafe {
+        let crc16_array3: extern "C" fn(u32, *const u32, *mut u16) =
+            core::mem::transmute(function(index::CRC16_ARRAY3));
+        crc16_array3(words.len() as u32, words.as_ptr(), crcs.as_mut_ptr());
+    }
+    crcs
+}
+
+/// Continues a CRC-8-CCITT calculation over `data`, starting from `crc`
+pub fn crc8_ccitt(crc: u8, data: &[u8]) -> u8 {
+    unsafe {
+        let crc8_ccitt: extern "C" fn(u8, *const u8, u32) -> u8 =
+            core::mem::transmute(function(index::CRC8_CCITT));
+        crc8_ccitt(crc, data.as_ptr(), data.len() as u32)
+    }
+}
+
+/// Enters the ROM boot loader on USB0, as a Device Firmware Upgrade device
+/// with the boot loader's own descriptors
+///
+/// # Safety
+///
+/// The USB controller and its pins must be set up, and the system clock
+/// must be one the USB PLL can run from. Nothing the application has running
+/// will be stopped, other than interrupts.
+pub unsafe fn update_usb() -> ! {
+    cortex_m::interrupt::disable();
+    let update: extern "C" fn(*const u8) = core::mem::transmute(function(index::UPDATE_USB));
+    update(core::ptr::null());
+    loop {
+        cortex_m::asm::nop();
+    }
+}
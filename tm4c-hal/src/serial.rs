@@ -3,6 +3,15 @@
 // The macro is required for the "sealed trait" pattern to work:
 // the traits and the gpios have to be defined in the same crate
 
+pub mod buffered;
+
+#[doc(hidden)]
+pub use embedded_hal_nb;
+#[doc(hidden)]
+pub use embedded_io;
+#[doc(hidden)]
+pub use embedded_io_async;
+
 ///! An internal macro to generate the UART traits
 #[macro_export]
 macro_rules! uart_traits_macro {
@@ -86,8 +95,113 @@ macro_rules! uart_traits_macro {
     };
 }
 
+/// An error in a received character
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Error {
+    /// The character had no valid stop bit
+    Framing,
+    /// The character's parity bit was wrong
+    Parity,
+    /// The line was held low for longer than a whole character
+    Break,
+    /// The receive FIFO was full and a character was lost. The characters
+    /// around the lost one are still returned by the reads either side.
+    Overrun,
+}
+
+impl Error {
+    /// Returns the error flagged in the bits of UARTDR read along with a
+    /// character, if any
+    #[doc(hidden)]
+    pub fn from_dr_bits(bits: u32) -> Option<Self> {
+        const FE: u32 = 1 << 8;
+        const PE: u32 = 1 << 9;
+        const BE: u32 = 1 << 10;
+        const OE: u32 = 1 << 11;
+
+        // A break also sets FE, so check for it first
+        if bits & BE != 0 {
+            Some(Error::Break)
+        } else if bits & FE != 0 {
+            Some(Error::Framing)
+        } else if bits & PE != 0 {
+            Some(Error::Parity)
+        } else if bits & OE != 0 {
+            Some(Error::Overrun)
+        } else {
+            None
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Framing => write!(f, "Framing error"),
+            Error::Parity => write!(f, "Parity error"),
+            Error::Break => write!(f, "Break received"),
+            Error::Overrun => write!(f, "Receive FIFO overrun"),
+        }
+    }
+}
+
+impl embedded_io::Error for Error {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            Error::Framing | Error::Parity | Error::Break => embedded_io::ErrorKind::InvalidData,
+            Error::Overrun => embedded_io::ErrorKind::Other,
+        }
+    }
+}
+
+impl embedded_hal_nb::serial::Error for Error {
+    fn kind(&self) -> embedded_hal_nb::serial::ErrorKind {
+        use embedded_hal_nb::serial::ErrorKind;
+        match self {
+            Error::Framing => ErrorKind::FrameFormat,
+            Error::Parity => ErrorKind::Parity,
+            Error::Break => ErrorKind::Other,
+            Error::Overrun => ErrorKind::Overrun,
+        }
+    }
+}
+
+/// The RXDMAE bit of UARTDMACTL, which lets the receive FIFO request uDMA
+/// transfers
+#[doc(hidden)]
+pub const DMACTL_RXDMAE: u32 = 1 << 0;
+/// The TXDMAE bit of UARTDMACTL
+#[doc(hidden)]
+pub const DMACTL_TXDMAE: u32 = 1 << 1;
+
+/// Offset of UARTECR, which shares its address with UARTRSR
+const ECR_OFFSET: usize = 0x004;
+
+/// Returns the error flagged in UARTRSR of the UART at `base`, if any
+///
+/// # Safety
+///
+/// `base` must be the address of a UART.
+#[doc(hidden)]
+pub unsafe fn pending_error(base: *const ()) -> Option<Error> {
+    let rsr = core::ptr::read_volatile((base as *const u8).add(ECR_OFFSET) as *const u32);
+    // UARTRSR has the same flags as UARTDR, eight bits lower
+    Error::from_dr_bits(rsr << 8)
+}
+
+/// Clears the receive error flags of the UART at `base`
+///
+/// # Safety
+///
+/// `base` must be the address of a UART.
+#[doc(hidden)]
+pub unsafe fn clear_errors(base: *const ()) {
+    core::ptr::write_volatile((base as *mut u8).add(ECR_OFFSET) as *mut u32, 0);
+}
+
 /// writeln!() emits LF chars, so this is useful
-/// if you're writing text with your UART
+/// if you're writing text with your UART. Only `fmt::Write` applies it; the
+/// `embedded-io` and `embedded-hal` traits always send bytes as they are.
 #[derive(PartialEq, Clone, Copy)]
 pub enum NewlineMode {
     /// Emit octets as received
@@ -95,3 +209,224 @@ pub enum NewlineMode {
     /// Emit an extra CR before every LF
     SwapLFtoCRLF,
 }
+
+/// How full a FIFO must be to raise an interrupt: for the receive FIFO, the
+/// level it must fill to, and for the transmit FIFO, the level it must
+/// empty to
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FifoLevel {
+    /// 2 of the 16 bytes
+    OneEighth,
+    /// 4 of the 16 bytes
+    OneQuarter,
+    /// 8 of the 16 bytes
+    Half,
+    /// 12 of the 16 bytes
+    ThreeQuarters,
+    /// 14 of the 16 bytes
+    SevenEighths,
+}
+
+impl FifoLevel {
+    /// Returns the value of the UARTIFLS register for these levels
+    #[doc(hidden)]
+    pub fn ifls_bits(rx: FifoLevel, tx: FifoLevel) -> u32 {
+        (rx as u32) << 3 | tx as u32
+    }
+}
+
+/// The number of data bits in each character
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DataBits {
+    /// Five data bits
+    Five,
+    /// Six data bits
+    Six,
+    /// Seven data bits
+    Seven,
+    /// Eight data bits
+    Eight,
+}
+
+/// The parity bit sent after the data bits
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Parity {
+    /// No parity bit
+    None,
+    /// Even parity
+    Even,
+    /// Odd parity
+    Odd,
+    /// Stick parity: the parity bit is always one
+    Mark,
+    /// Stick parity: the parity bit is always zero
+    Space,
+}
+
+/// The number of stop bits after each character
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StopBits {
+    /// One stop bit
+    One,
+    /// Two stop bits
+    Two,
+}
+
+/// The frame format of a UART, and whether its FIFOs are enabled.
+///
+/// The default is 8N1 with the FIFOs enabled, which is what a UART is set up
+/// with when it is created.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Config {
+    /// Data bits per character
+    pub data_bits: DataBits,
+    /// The parity bit
+    pub parity: Parity,
+    /// Stop bits per character
+    pub stop_bits: StopBits,
+    /// Enables the 16 byte transmit and receive FIFOs. Without them, each
+    /// direction holds a single character.
+    pub fifo: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            fifo: true,
+        }
+    }
+}
+
+impl Config {
+    /// Returns the value of the UARTLCRH register for this configuration
+    #[doc(hidden)]
+    pub fn lcrh_bits(&self) -> u32 {
+        const PEN: u32 = 1 << 1;
+        const EPS: u32 = 1 << 2;
+        const STP2: u32 = 1 << 3;
+        const FEN: u32 = 1 << 4;
+        const SPS: u32 = 1 << 7;
+
+        let wlen = match self.data_bits {
+            DataBits::Five => 0,
+            DataBits::Six => 1,
+            DataBits::Seven => 2,
+            DataBits::Eight => 3,
+        };
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Even => PEN | EPS,
+            Parity::Odd => PEN,
+            Parity::Mark => PEN | SPS,
+            Parity::Space => PEN | EPS | SPS,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => STP2,
+        };
+        let fifo = if self.fifo { FEN } else { 0 };
+        wlen << 5 | parity | stop_bits | fifo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FE: u32 = 1 << 8;
+    const PE: u32 = 1 << 9;
+    const BE: u32 = 1 << 10;
+    const OE: u32 = 1 << 11;
+
+    #[test]
+    fn each_dr_flag() {
+        assert_eq!(Error::from_dr_bits(0x55), None);
+        assert_eq!(Error::from_dr_bits(0x55 | FE), Some(Error::Framing));
+        assert_eq!(Error::from_dr_bits(0x55 | PE), Some(Error::Parity));
+        assert_eq!(Error::from_dr_bits(BE), Some(Error::Break));
+        assert_eq!(Error::from_dr_bits(0x55 | OE), Some(Error::Overrun));
+    }
+
+    #[test]
+    fn dr_flag_priority() {
+        // A break always comes with a framing error, and often a parity one
+        assert_eq!(Error::from_dr_bits(BE | FE), Some(Error::Break));
+        assert_eq!(Error::from_dr_bits(BE | FE | PE | OE), Some(Error::Break));
+        assert_eq!(Error::from_dr_bits(FE | PE | OE), Some(Error::Framing));
+        assert_eq!(Error::from_dr_bits(PE | OE), Some(Error::Parity));
+    }
+
+    #[test]
+    fn rsr_flags() {
+        // UARTDR, then UARTRSR/UARTECR
+        let mut registers = [0u32; 2];
+        let pending = |registers: &[u32; 2]| unsafe { pending_error(registers.as_ptr().cast()) };
+        assert_eq!(pending(&registers), None);
+
+        registers[1] = (OE | FE) >> 8;
+        assert_eq!(pending(&registers), Some(Error::Framing));
+        registers[1] = OE >> 8;
+        assert_eq!(pending(&registers), Some(Error::Overrun));
+
+        unsafe { clear_errors(registers.as_mut_ptr().cast()) };
+        assert_eq!(registers[1], 0);
+    }
+
+    #[test]
+    fn lcrh_frame_formats() {
+        // WLEN is bits 5-6, FEN bit 4, STP2 bit 3, EPS bit 2, PEN bit 1 and
+        // SPS bit 7
+        assert_eq!(Config::default().lcrh_bits(), 0b0111_0000);
+
+        let even = Config {
+            parity: Parity::Even,
+            ..Config::default()
+        };
+        assert_eq!(even.lcrh_bits(), 0b0111_0110);
+
+        let seven_odd_two = Config {
+            data_bits: DataBits::Seven,
+            parity: Parity::Odd,
+            stop_bits: StopBits::Two,
+            fifo: false,
+        };
+        assert_eq!(seven_odd_two.lcrh_bits(), 0b0100_1010);
+
+        let mark = Config {
+            parity: Parity::Mark,
+            ..Config::default()
+        };
+        assert_eq!(mark.lcrh_bits(), 0b1111_0010);
+
+        let space = Config {
+            data_bits: DataBits::Five,
+            parity: Parity::Space,
+            ..Config::default()
+        };
+        assert_eq!(space.lcrh_bits(), 0b1001_0110);
+    }
+
+    #[test]
+    fn ifls_packing() {
+        // RXIFLSEL is bits 3-5 and TXIFLSEL bits 0-2
+        assert_eq!(
+            FifoLevel::ifls_bits(FifoLevel::OneEighth, FifoLevel::OneEighth),
+            0b000_000
+        );
+        assert_eq!(
+            FifoLevel::ifls_bits(FifoLevel::Half, FifoLevel::Half),
+            0b010_010
+        );
+        assert_eq!(
+            FifoLevel::ifls_bits(FifoLevel::SevenEighths, FifoLevel::OneQuarter),
+            0b100_001
+        );
+        assert_eq!(
+            FifoLevel::ifls_bits(FifoLevel::OneEighth, FifoLevel::ThreeQuarters),
+            0b000_011
+        );
+    }
+}
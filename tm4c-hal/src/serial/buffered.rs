@@ -0,0 +1,222 @@
+//! The state shared between a buffered UART and its interrupt handler.
+//!
+//! Received bytes are moved from the receive FIFO into one ring buffer by
+//! the receive and receive timeout interrupts, and bytes to send are moved
+//! from another into the transmit FIFO by the transmit interrupt. Both ring
+//! buffers are supplied by the application, so they can be as big as it
+//! likes.
+
+use core::task::Waker;
+
+use super::Error;
+
+/// The receive interrupt, in UARTIM, UARTMIS and UARTICR
+pub const INT_RX: u32 = 1 << 4;
+/// The transmit interrupt
+pub const INT_TX: u32 = 1 << 5;
+/// The receive timeout interrupt
+pub const INT_RT: u32 = 1 << 6;
+
+/// A ring buffer of bytes over borrowed storage
+pub struct Ring {
+    storage: &'static mut [u8],
+    /// Index of the oldest byte
+    head: usize,
+    len: usize,
+}
+
+impl Ring {
+    /// A ring buffer using all of `storage`
+    pub fn new(storage: &'static mut [u8]) -> Self {
+        Ring {
+            storage,
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Gives back the storage. Anything still in the buffer is lost.
+    pub fn free(self) -> &'static mut [u8] {
+        self.storage
+    }
+
+    /// How many bytes are in the buffer
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Is the buffer empty?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Is the buffer full?
+    pub fn is_full(&self) -> bool {
+        self.len == self.storage.len()
+    }
+
+    /// Adds `byte` to the buffer, unless it's full. Returns whether it was
+    /// added.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        let tail = (self.head + self.len) % self.storage.len();
+        self.storage[tail] = byte;
+        self.len += 1;
+        true
+    }
+
+    /// Takes the oldest byte from the buffer
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.storage[self.head];
+        self.head = (self.head + 1) % self.storage.len();
+        self.len -= 1;
+        Some(byte)
+    }
+
+    /// Adds as much of `data` as fits, returning how many bytes were added
+    pub fn push_slice(&mut self, data: &[u8]) -> usize {
+        data.iter().take_while(|&&byte| self.push(byte)).count()
+    }
+
+    /// Takes as many bytes as there are, up to the length of `buffer`, and
+    /// returns how many were taken
+    pub fn pop_slice(&mut self, buffer: &mut [u8]) -> usize {
+        let mut count = 0;
+        for slot in buffer.iter_mut() {
+            match self.pop() {
+                Some(byte) => *slot = byte,
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
+/// The buffers of one UART, and the tasks waiting on them
+#[doc(hidden)]
+pub struct State {
+    /// Received bytes
+    pub rx: Ring,
+    /// Bytes to send
+    pub tx: Ring,
+    /// An error in a received character, or a receive buffer overflow. It
+    /// is reported once the receive buffer has been emptied.
+    pub rx_error: Option<Error>,
+    rx_waker: Option<Waker>,
+    tx_waker: Option<Waker>,
+}
+
+impl State {
+    /// The state for a UART using `rx` and `tx` as its buffers
+    pub fn new(rx: &'static mut [u8], tx: &'static mut [u8]) -> Self {
+        State {
+            rx: Ring::new(rx),
+            tx: Ring::new(tx),
+            rx_error: None,
+            rx_waker: None,
+            tx_waker: None,
+        }
+    }
+
+    /// Stores a received character, or the error it was received with
+    pub fn receive(&mut self, result: Result<u8, Error>) {
+        match result {
+            Ok(byte) => {
+                if !self.rx.push(byte) {
+                    self.rx_error = Some(Error::Overrun);
+                }
+            }
+            Err(error) => self.rx_error = Some(error),
+        }
+    }
+
+    /// Reads received bytes into `buffer`. Returns `None` if there is
+    /// nothing to read yet.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Option<Result<usize, Error>> {
+        if buffer.is_empty() {
+            return Some(Ok(0));
+        }
+        match self.rx.pop_slice(buffer) {
+            0 => self.rx_error.take().map(Err),
+            count => Some(Ok(count)),
+        }
+    }
+
+    /// Wakes `waker` when something is received
+    pub fn register_rx(&mut self, waker: &Waker) {
+        self.rx_waker = Some(waker.clone());
+    }
+
+    /// Wakes `waker` when there is room in the transmit buffer
+    pub fn register_tx(&mut self, waker: &Waker) {
+        self.tx_waker = Some(waker.clone());
+    }
+
+    /// Returns the task waiting to read, if any
+    pub fn take_rx_waker(&mut self) -> Option<Waker> {
+        self.rx_waker.take()
+    }
+
+    /// Returns the task waiting to write, if any
+    pub fn take_tx_waker(&mut self) -> Option<Waker> {
+        self.tx_waker.take()
+    }
+
+    /// Gives back the storage of the buffers
+    pub fn free(self) -> (&'static mut [u8], &'static mut [u8]) {
+        (self.rx.free(), self.tx.free())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Storage for a ring buffer, used by one test only
+    macro_rules! storage {
+        ($len:expr) => {{
+            static mut STORAGE: [u8; $len] = [0; $len];
+            unsafe { &mut *core::ptr::addr_of_mut!(STORAGE) }
+        }};
+    }
+
+    #[test]
+    fn ring_wraps() {
+        let mut ring = Ring::new(storage!(4));
+        assert_eq!(ring.push_slice(&[1, 2, 3]), 3);
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.push_slice(&[4, 5, 6, 7]), 3);
+        assert!(ring.is_full());
+        assert!(!ring.push(8));
+
+        let mut buffer = [0; 8];
+        assert_eq!(ring.pop_slice(&mut buffer), 4);
+        assert_eq!(&buffer[..4], &[3, 4, 5, 6]);
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn errors_follow_data() {
+        let mut state = State::new(storage!(2), storage!(2));
+        state.receive(Ok(1));
+        state.receive(Err(Error::Parity));
+        state.receive(Ok(2));
+        state.receive(Ok(3));
+
+        let mut buffer = [0; 4];
+        assert_eq!(state.read(&mut buffer), Some(Ok(2)));
+        assert_eq!(&buffer[..2], &[1, 2]);
+        // The overflow replaced the parity error
+        assert_eq!(state.read(&mut buffer), Some(Err(Error::Overrun)));
+        assert_eq!(state.read(&mut buffer), None);
+        assert_eq!(state.read(&mut []), Some(Ok(0)));
+    }
+}
@@ -0,0 +1,209 @@
+//! A system tick, kept by the SysTick exception.
+//!
+//! Unlike [`Delay`](crate::delay::Delay), which busy-waits on SysTick and so
+//! can't share it, this runs SysTick continuously and counts its exceptions.
+//! That gives an uptime counter, timeouts and a delay which sleeps (with
+//! `WFI`) between ticks.
+//!
+//! You must forward the SysTick exception to [`on_interrupt`]:
+//!
+//! ```ignore
+//! #[exception]
+//! fn SysTick() {
+//!     tm4c123x_hal::systick::on_interrupt();
+//! }
+//! ```
+//!
+//! The counter is global, so [`ticks`], [`uptime`] and [`Timeout`] can be
+//! used from anywhere, including other interrupt handlers.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::{scb::VectActive, syst::SystClkSource, SCB, SYST};
+use embedded_hal::delay::DelayNs;
+
+use crate::{
+    sysctl::Clocks,
+    time::{DurationTicks, Hertz, MillisDurationU64, NanosDurationU32},
+};
+
+static TICKS: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+static TICK_HZ: AtomicU32 = AtomicU32::new(0);
+static SYSCLK_HZ: AtomicU32 = AtomicU32::new(0);
+
+/// Advances the tick counter. Call this from the SysTick exception handler.
+pub fn on_interrupt() {
+    interrupt::free(|cs| {
+        let ticks = TICKS.borrow(cs);
+        ticks.set(ticks.get() + 1);
+    })
+}
+
+/// The number of ticks since the `SysTickService` was started
+pub fn ticks() -> u64 {
+    interrupt::free(|cs| TICKS.borrow(cs).get())
+}
+
+/// The rate at which the tick counter advances. Zero if the `SysTickService`
+/// hasn't been started.
+pub fn tick_rate() -> Hertz {
+    Hertz(TICK_HZ.load(Ordering::Relaxed))
+}
+
+/// The time since the `SysTickService` was started, to the nearest tick
+pub fn uptime() -> MillisDurationU64 {
+    let rate = u64::from(tick_rate().0);
+    if rate == 0 {
+        return MillisDurationU64::from_ticks(0);
+    }
+    MillisDurationU64::from_ticks(ticks() * 1_000 / rate)
+}
+
+/// Are we somewhere the SysTick exception can't get in to advance the
+/// counter, i.e. in a handler or with interrupts masked? BASEPRI is checked
+/// too, as an RTIC lock held in `idle` masks SysTick that way.
+fn ticks_are_stalled() -> bool {
+    SCB::vect_active() != VectActive::ThreadMode
+        || cortex_m::register::primask::read().is_inactive()
+        || cortex_m::register::basepri::read() != 0
+}
+
+/// SysTick, running continuously as a tick source
+pub struct SysTickService {
+    syst: SYST,
+}
+
+impl SysTickService {
+    /// Starts SysTick with a 1 kHz (millisecond) tick
+    pub fn new(syst: SYST, clocks: &Clocks) -> Self {
+        Self::with_rate(syst, clocks, Hertz(1_000))
+    }
+
+    /// Starts SysTick ticking at `rate`.
+    ///
+    /// SysTick is only 24 bits wide, so `rate` can't be lower than the
+    /// system clock divided by 2<sup>24</sup> (about 5 Hz at 80 MHz).
+    pub fn with_rate<F>(mut syst: SYST, clocks: &Clocks, rate: F) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        let rate = rate.into();
+        let reload = clocks.sysclk.0 / rate.0;
+        assert!(reload > 0 && reload <= (1 << 24));
+
+        syst.disable_counter();
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(reload - 1);
+        syst.clear_current();
+
+        interrupt::free(|cs| TICKS.borrow(cs).set(0));
+        TICK_HZ.store(clocks.sysclk.0 / reload, Ordering::Relaxed);
+        SYSCLK_HZ.store(clocks.sysclk.0, Ordering::Relaxed);
+
+        syst.enable_interrupt();
+        syst.enable_counter();
+
+        SysTickService { syst }
+    }
+
+    /// The number of ticks since the service was started
+    pub fn now(&self) -> u64 {
+        ticks()
+    }
+
+    /// Returns a `Timeout` which expires once `duration` has passed
+    pub fn timeout<D: DurationTicks>(&self, duration: D) -> Timeout {
+        Timeout::new(duration)
+    }
+
+    /// Returns a delay provider that uses this tick. You can have as many of
+    /// these as you like, but they can't outlive the service.
+    pub fn delay(&self) -> SysTickDelay<'_> {
+        SysTickDelay {
+            _service: PhantomData,
+        }
+    }
+
+    /// Stops SysTick and releases it
+    pub fn free(mut self) -> SYST {
+        self.syst.disable_interrupt();
+        self.syst.disable_counter();
+        TICK_HZ.store(0, Ordering::Relaxed);
+        self.syst
+    }
+}
+
+/// A deadline on the tick counter
+#[derive(Clone, Copy, Debug)]
+pub struct Timeout {
+    deadline: u64,
+}
+
+impl Timeout {
+    /// Returns a `Timeout` which expires once `duration` has passed.
+    ///
+    /// The current tick is already partly over, so this waits for one more
+    /// tick than `duration` needs, so it never expires early.
+    pub fn new<D: DurationTicks>(duration: D) -> Self {
+        Timeout {
+            deadline: ticks() + duration.ticks_at(tick_rate()) + 1,
+        }
+    }
+
+    /// Has the deadline passed?
+    pub fn is_expired(&self) -> bool {
+        ticks() >= self.deadline
+    }
+
+    /// Ticks remaining until the deadline (zero if it has passed)
+    pub fn remaining(&self) -> u64 {
+        self.deadline.saturating_sub(ticks())
+    }
+}
+
+/// A delay provider which sleeps until the SysTick exception has moved the
+/// tick counter on far enough.
+///
+/// If it's used from an interrupt handler, or with interrupts masked, the
+/// tick counter can't move, so it busy-waits on the SysTick counter instead.
+#[derive(Clone, Copy)]
+pub struct SysTickDelay<'a> {
+    _service: PhantomData<&'a SysTickService>,
+}
+
+impl SysTickDelay<'_> {
+    /// Busy-waits for `cycles` system clock cycles, by watching the SysTick
+    /// current value register.
+    fn spin(cycles: u64) {
+        let period = u64::from(SYST::get_reload()) + 1;
+        let mut last = u64::from(SYST::get_current());
+        let mut elapsed = 0;
+        while elapsed < cycles {
+            let now = u64::from(SYST::get_current());
+            elapsed += if now <= last {
+                last - now
+            } else {
+                // SysTick counts down, so it wrapped
+                last + period - now
+            };
+            last = now;
+        }
+    }
+}
+
+impl DelayNs for SysTickDelay<'_> {
+    fn delay_ns(&mut self, ns: u32) {
+        let duration = NanosDurationU32::from_ticks(ns);
+        if ticks_are_stalled() {
+            Self::spin(duration.ticks_at(Hertz(SYSCLK_HZ.load(Ordering::Relaxed))));
+        } else {
+            let timeout = Timeout::new(duration);
+            while !timeout.is_expired() {
+                cortex_m::asm::wfi();
+            }
+        }
+    }
+}
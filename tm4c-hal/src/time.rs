@@ -1,23 +1,155 @@
 //! Time units
+//!
+//! The rate types in here are simple newtypes over `u32`, so that existing
+//! code can keep using `Hertz(x)` and `x.0`. They convert to and from the
+//! [`fugit`] rate types, so any driver that takes an `Into<Hertz>` will also
+//! take a `fugit::HertzU32` (or `KilohertzU32`, `MegahertzU32`, ...).
+//! Durations are the [`fugit`] duration types.
 
-use cortex_m::peripheral::DWT;
+use core::fmt;
+use core::ops::{Add, Div, Mul, Sub};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::interrupt;
+use cortex_m::peripheral::{DCB, DWT};
+
+use crate::sysctl::Clocks;
+
+pub use fugit::{
+    Duration, HertzU32, KilohertzU32, MegahertzU32, MicrosDurationU32, MicrosDurationU64,
+    MillisDurationU32, MillisDurationU64, NanosDurationU32, NanosDurationU64, Rate,
+    SecsDurationU32, SecsDurationU64,
+};
 
 /// Bits per second
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Bps(pub u32);
 
 /// Hertz
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Hertz(pub u32);
 
 /// KiloHertz
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct KiloHertz(pub u32);
 
 /// MegaHertz
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct MegaHertz(pub u32);
 
+macro_rules! rate_impls {
+    ($($Rate:ident: $unit:expr,)+) => {
+        $(
+            impl $Rate {
+                /// Adds two values, returning `None` on overflow
+                pub const fn checked_add(self, other: Self) -> Option<Self> {
+                    match self.0.checked_add(other.0) {
+                        Some(v) => Some($Rate(v)),
+                        None => None,
+                    }
+                }
+
+                /// Subtracts two values, returning `None` on underflow
+                pub const fn checked_sub(self, other: Self) -> Option<Self> {
+                    match self.0.checked_sub(other.0) {
+                        Some(v) => Some($Rate(v)),
+                        None => None,
+                    }
+                }
+
+                /// Multiplies by a scalar, returning `None` on overflow
+                pub const fn checked_mul(self, rhs: u32) -> Option<Self> {
+                    match self.0.checked_mul(rhs) {
+                        Some(v) => Some($Rate(v)),
+                        None => None,
+                    }
+                }
+
+                /// Divides by a scalar, returning `None` if `rhs` is zero
+                pub const fn checked_div(self, rhs: u32) -> Option<Self> {
+                    match self.0.checked_div(rhs) {
+                        Some(v) => Some($Rate(v)),
+                        None => None,
+                    }
+                }
+            }
+
+            impl Add for $Rate {
+                type Output = $Rate;
+
+                fn add(self, rhs: Self) -> Self {
+                    $Rate(self.0 + rhs.0)
+                }
+            }
+
+            impl Sub for $Rate {
+                type Output = $Rate;
+
+                fn sub(self, rhs: Self) -> Self {
+                    $Rate(self.0 - rhs.0)
+                }
+            }
+
+            impl Mul<u32> for $Rate {
+                type Output = $Rate;
+
+                fn mul(self, rhs: u32) -> Self {
+                    $Rate(self.0 * rhs)
+                }
+            }
+
+            impl Div<u32> for $Rate {
+                type Output = $Rate;
+
+                fn div(self, rhs: u32) -> Self {
+                    $Rate(self.0 / rhs)
+                }
+            }
+
+            impl fmt::Display for $Rate {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    write!(f, "{} {}", self.0, $unit)
+                }
+            }
+        )+
+    };
+}
+
+rate_impls! {
+    Bps: "bps",
+    Hertz: "Hz",
+    KiloHertz: "kHz",
+    MegaHertz: "MHz",
+}
+
+impl Hertz {
+    /// Returns the length of one period at this frequency. Returns `None`
+    /// for a frequency of zero.
+    pub const fn period(self) -> Option<NanosDurationU32> {
+        match 1_000_000_000u32.checked_div(self.0) {
+            Some(ns) => Some(NanosDurationU32::from_ticks(ns)),
+            None => None,
+        }
+    }
+
+    /// Returns the number of cycles of this frequency that fit in the given
+    /// duration, rounded down. Saturates at `u32::MAX`, which is under a
+    /// minute at 80 MHz. The product is worked out in 128 bits, so it can't
+    /// overflow on the way, whatever the duration's units.
+    pub fn cycles_in<const NOM: u32, const DENOM: u32>(
+        self,
+        duration: Duration<u32, NOM, DENOM>,
+    ) -> u32 {
+        let cycles =
+            u128::from(duration.ticks()) * u128::from(self.0) * u128::from(NOM) / u128::from(DENOM);
+        if cycles > u128::from(u32::MAX) {
+            u32::MAX
+        } else {
+            cycles as u32
+        }
+    }
+}
+
 /// Extension trait that adds convenience methods to the `u32` type
 pub trait U32Ext {
     /// Wrap in `Bps`
@@ -31,6 +163,18 @@ pub trait U32Ext {
 
     /// Wrap in `MegaHertz`
     fn mhz(self) -> MegaHertz;
+
+    /// A duration of this many nanoseconds
+    fn nanos(self) -> NanosDurationU32;
+
+    /// A duration of this many microseconds
+    fn micros(self) -> MicrosDurationU32;
+
+    /// A duration of this many milliseconds
+    fn millis(self) -> MillisDurationU32;
+
+    /// A duration of this many seconds
+    fn secs(self) -> SecsDurationU32;
 }
 
 impl U32Ext for u32 {
@@ -49,33 +193,150 @@ impl U32Ext for u32 {
     fn mhz(self) -> MegaHertz {
         MegaHertz(self)
     }
+
+    fn nanos(self) -> NanosDurationU32 {
+        NanosDurationU32::from_ticks(self)
+    }
+
+    fn micros(self) -> MicrosDurationU32 {
+        MicrosDurationU32::from_ticks(self)
+    }
+
+    fn millis(self) -> MillisDurationU32 {
+        MillisDurationU32::from_ticks(self)
+    }
+
+    fn secs(self) -> SecsDurationU32 {
+        SecsDurationU32::from_ticks(self)
+    }
+}
+
+impl From<KiloHertz> for Hertz {
+    fn from(f: KiloHertz) -> Hertz {
+        Hertz(f.0 * 1_000)
+    }
+}
+
+impl From<MegaHertz> for Hertz {
+    fn from(f: MegaHertz) -> Hertz {
+        Hertz(f.0 * 1_000_000)
+    }
+}
+
+impl From<MegaHertz> for KiloHertz {
+    fn from(f: MegaHertz) -> KiloHertz {
+        KiloHertz(f.0 * 1_000)
+    }
 }
 
-impl Into<Hertz> for KiloHertz {
-    fn into(self) -> Hertz {
-        Hertz(self.0 * 1_000)
+/// Any `u32` based `fugit` rate can be used wherever a `Hertz` is wanted
+impl<const NOM: u32, const DENOM: u32> From<Rate<u32, NOM, DENOM>> for Hertz {
+    fn from(f: Rate<u32, NOM, DENOM>) -> Hertz {
+        Hertz(f.to_Hz())
     }
 }
 
-impl Into<Hertz> for MegaHertz {
-    fn into(self) -> Hertz {
-        Hertz(self.0 * 1_000_000)
+/// Any `u32` based `fugit` rate can be used as a baud rate, as one bit per
+/// second is one hertz
+impl<const NOM: u32, const DENOM: u32> From<Rate<u32, NOM, DENOM>> for Bps {
+    fn from(f: Rate<u32, NOM, DENOM>) -> Bps {
+        Bps(f.to_Hz())
     }
 }
 
-impl Into<KiloHertz> for MegaHertz {
-    fn into(self) -> KiloHertz {
-        KiloHertz(self.0 * 1_000)
+impl From<Hertz> for HertzU32 {
+    fn from(f: Hertz) -> HertzU32 {
+        HertzU32::from_raw(f.0)
     }
 }
 
-/// A monotonic nondecreasing timer
+impl From<KiloHertz> for KilohertzU32 {
+    fn from(f: KiloHertz) -> KilohertzU32 {
+        KilohertzU32::from_raw(f.0)
+    }
+}
+
+impl From<MegaHertz> for MegahertzU32 {
+    fn from(f: MegaHertz) -> MegahertzU32 {
+        MegahertzU32::from_raw(f.0)
+    }
+}
+
+/// A duration that can be turned into a number of clock cycles
+pub trait DurationTicks {
+    /// Returns the number of cycles of a clock running at `frequency` that
+    /// make up this duration, rounded up so that a timeout based on it never
+    /// fires early.
+    fn ticks_at(self, frequency: Hertz) -> u64;
+}
+
+impl<const NOM: u32, const DENOM: u32> DurationTicks for Duration<u32, NOM, DENOM> {
+    fn ticks_at(self, frequency: Hertz) -> u64 {
+        Duration::<u64, NOM, DENOM>::from(self).ticks_at(frequency)
+    }
+}
+
+impl<const NOM: u32, const DENOM: u32> DurationTicks for Duration<u64, NOM, DENOM> {
+    fn ticks_at(self, frequency: Hertz) -> u64 {
+        let num = u128::from(self.ticks()) * u128::from(frequency.0) * u128::from(NOM);
+        let ticks = num.div_ceil(u128::from(DENOM));
+        if ticks > u128::from(u64::MAX) {
+            u64::MAX
+        } else {
+            ticks as u64
+        }
+    }
+}
+
+// The DWT cycle counter is only 32 bits wide. We extend it to 64 bits by
+// remembering the last value we saw and counting the number of times it has
+// gone backwards.
+static LAST_CYCLES: AtomicU32 = AtomicU32::new(0);
+static WRAPS: AtomicU32 = AtomicU32::new(0);
+
+/// Read the cycle counter, extended to 64 bits
+fn cycle_count_64() -> u64 {
+    interrupt::free(|_| {
+        let low = DWT::get_cycle_count();
+        let mut high = WRAPS.load(Ordering::Relaxed);
+        if low < LAST_CYCLES.load(Ordering::Relaxed) {
+            high = high.wrapping_add(1);
+            WRAPS.store(high, Ordering::Relaxed);
+        }
+        LAST_CYCLES.store(low, Ordering::Relaxed);
+        (u64::from(high) << 32) | u64::from(low)
+    })
+}
+
+/// A monotonic nondecreasing timer, based on the DWT cycle counter.
+///
+/// The 32-bit cycle counter is extended to 64 bits in software. This only
+/// works if the timer is read (with [`MonoTimer::now`], [`Instant::elapsed`]
+/// or [`Timeout::is_expired`]) at least once per wrap of the hardware counter,
+/// which is about 53 seconds at 80 MHz or 35 seconds at 120 MHz.
 #[derive(Clone, Copy)]
 pub struct MonoTimer {
     frequency: Hertz,
 }
 
 impl MonoTimer {
+    /// Creates a new `Monotonic` timer.
+    ///
+    /// This enables tracing in the DCB (which the DWT needs to count) and
+    /// then starts the DWT cycle counter. The `DWT` is consumed so the counter
+    /// can't be stopped or reset later.
+    pub fn new(mut dwt: DWT, dcb: &mut DCB, clocks: &Clocks) -> Self {
+        dcb.enable_trace();
+        DWT::unlock();
+        dwt.enable_cycle_counter();
+        // `dwt` goes out of scope here, so the CYCCNT counter can't be
+        // stopped or reset
+
+        MonoTimer {
+            frequency: clocks.sysclk,
+        }
+    }
+
     /// Returns the frequency at which the monotonic timer is operating at
     pub fn frequency(self) -> Hertz {
         self.frequency
@@ -84,20 +345,210 @@ impl MonoTimer {
     /// Returns an `Instant` corresponding to "now"
     pub fn now(self) -> Instant {
         Instant {
-            now: DWT::get_cycle_count(),
+            ticks: cycle_count_64(),
+        }
+    }
+
+    /// Converts a number of ticks of this timer into a `fugit` duration,
+    /// rounding down.
+    pub fn duration<const NOM: u32, const DENOM: u32>(
+        self,
+        ticks: u64,
+    ) -> Duration<u64, NOM, DENOM> {
+        let num = u128::from(ticks) * u128::from(DENOM);
+        let den = u128::from(self.frequency.0) * u128::from(NOM);
+        let value = num / den;
+        Duration::<u64, NOM, DENOM>::from_ticks(if value > u128::from(u64::MAX) {
+            u64::MAX
+        } else {
+            value as u64
+        })
+    }
+
+    /// Converts a duration into a number of ticks of this timer, rounding up.
+    pub fn ticks<D: DurationTicks>(self, duration: D) -> u64 {
+        duration.ticks_at(self.frequency)
+    }
+
+    /// Returns the time that has passed since `since`, as a `fugit` duration
+    pub fn elapsed<const NOM: u32, const DENOM: u32>(
+        self,
+        since: Instant,
+    ) -> Duration<u64, NOM, DENOM> {
+        self.duration(since.elapsed())
+    }
+
+    /// Returns a `Timeout` which expires once `duration` has passed
+    pub fn timeout<D: DurationTicks>(self, duration: D) -> Timeout {
+        Timeout {
+            deadline: self.now() + self.ticks(duration),
         }
     }
 }
 
 /// A measurement of a monotonically nondecreasing clock
-#[derive(Clone, Copy)]
+///
+/// Subtracting two `Instant`s gives the number of ticks between them. Use
+/// [`MonoTimer::duration`] to turn that into a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Instant {
-    now: u32,
+    ticks: u64,
 }
 
 impl Instant {
+    /// Ticks since the timer was started
+    pub fn ticks(self) -> u64 {
+        self.ticks
+    }
+
     /// Ticks elapsed since the `Instant` was created
-    pub fn elapsed(self) -> u32 {
-        DWT::get_cycle_count().wrapping_sub(self.now)
+    pub fn elapsed(self) -> u64 {
+        cycle_count_64().saturating_sub(self.ticks)
+    }
+
+    /// Ticks from `earlier` to this `Instant`, or `None` if `earlier` is
+    /// actually later
+    pub fn checked_duration_since(self, earlier: Instant) -> Option<u64> {
+        self.ticks.checked_sub(earlier.ticks)
+    }
+
+    /// The `Instant` this many ticks later, or `None` on overflow
+    pub fn checked_add(self, ticks: u64) -> Option<Instant> {
+        self.ticks.checked_add(ticks).map(|ticks| Instant { ticks })
+    }
+
+    /// The `Instant` this many ticks earlier, or `None` on underflow
+    pub fn checked_sub(self, ticks: u64) -> Option<Instant> {
+        self.ticks.checked_sub(ticks).map(|ticks| Instant { ticks })
+    }
+}
+
+impl Add<u64> for Instant {
+    type Output = Instant;
+
+    fn add(self, ticks: u64) -> Instant {
+        Instant {
+            ticks: self.ticks + ticks,
+        }
+    }
+}
+
+impl Sub<u64> for Instant {
+    type Output = Instant;
+
+    fn sub(self, ticks: u64) -> Instant {
+        Instant {
+            ticks: self.ticks - ticks,
+        }
+    }
+}
+
+impl Sub for Instant {
+    type Output = u64;
+
+    fn sub(self, earlier: Instant) -> u64 {
+        self.ticks - earlier.ticks
+    }
+}
+
+/// A deadline on a `MonoTimer`, created with [`MonoTimer::timeout`]
+#[derive(Clone, Copy, Debug)]
+pub struct Timeout {
+    deadline: Instant,
+}
+
+impl Timeout {
+    /// The `Instant` at which this timeout expires
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Has the deadline passed?
+    pub fn is_expired(&self) -> bool {
+        cycle_count_64() >= self.deadline.ticks
+    }
+
+    /// Ticks remaining until the deadline (zero if it has passed)
+    pub fn remaining(&self) -> u64 {
+        self.deadline.ticks.saturating_sub(cycle_count_64())
+    }
+
+    /// Busy-waits until the deadline has passed
+    pub fn wait(&self) {
+        while !self.is_expired() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::{HoursDurationU32, HoursDurationU64, MinutesDurationU32, RateExtU32};
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(Hertz(3) + Hertz(4), Hertz(7));
+        assert_eq!(KiloHertz(7) - KiloHertz(4), KiloHertz(3));
+        assert_eq!(MegaHertz(5) * 3, MegaHertz(15));
+        assert_eq!(Bps(115_200) / 2, Bps(57_600));
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        assert_eq!(Hertz(u32::MAX).checked_add(Hertz(1)), None);
+        assert_eq!(Hertz(1).checked_add(Hertz(2)), Some(Hertz(3)));
+        assert_eq!(Bps(1).checked_sub(Bps(2)), None);
+        assert_eq!(Bps(2).checked_sub(Bps(1)), Some(Bps(1)));
+        assert_eq!(KiloHertz(u32::MAX).checked_mul(2), None);
+        assert_eq!(KiloHertz(2).checked_mul(3), Some(KiloHertz(6)));
+        assert_eq!(MegaHertz(6).checked_div(0), None);
+        assert_eq!(MegaHertz(6).checked_div(4), Some(MegaHertz(1)));
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Hertz::from(KiloHertz(16)), Hertz(16_000));
+        assert_eq!(Hertz::from(MegaHertz(80)), Hertz(80_000_000));
+        assert_eq!(KiloHertz::from(MegaHertz(16)), KiloHertz(16_000));
+
+        assert_eq!(Hertz::from(120.MHz::<1, 1>()), Hertz(120_000_000));
+        assert_eq!(Hertz::from(400.kHz::<1, 1>()), Hertz(400_000));
+        assert_eq!(Bps::from(115_200.Hz::<1, 1>()), Bps(115_200));
+        assert_eq!(Bps::from(1.MHz::<1, 1>()), Bps(1_000_000));
+
+        assert_eq!(HertzU32::from(Hertz(25)), 25.Hz::<1, 1>());
+        assert_eq!(KilohertzU32::from(KiloHertz(25)), 25.kHz::<1, 1>());
+        assert_eq!(MegahertzU32::from(MegaHertz(25)), 25.MHz::<1, 1>());
+    }
+
+    #[test]
+    fn periods_and_cycles() {
+        assert_eq!(Hertz(0).period(), None);
+        assert_eq!(
+            Hertz(1_000).period(),
+            Some(NanosDurationU32::from_ticks(1_000_000))
+        );
+
+        let clock = Hertz(80_000_000);
+        assert_eq!(clock.cycles_in(1_000u32.nanos()), 80);
+        assert_eq!(clock.cycles_in(12u32.nanos()), 0);
+        assert_eq!(clock.cycles_in(53u32.secs()), 4_240_000_000);
+        // Saturates rather than overflowing, even in units bigger than a second
+        assert_eq!(clock.cycles_in(54u32.secs()), u32::MAX);
+        assert_eq!(
+            clock.cycles_in(MinutesDurationU32::minutes(u32::MAX)),
+            u32::MAX
+        );
+        assert_eq!(
+            Hertz(u32::MAX).cycles_in(HoursDurationU32::hours(u32::MAX)),
+            u32::MAX
+        );
+    }
+
+    #[test]
+    fn ticks_round_up() {
+        let clock = Hertz(120_000_000);
+        assert_eq!(1u32.nanos().ticks_at(clock), 1);
+        assert_eq!(1u32.micros().ticks_at(clock), 120);
+        assert_eq!(HoursDurationU64::hours(u64::MAX).ticks_at(clock), u64::MAX);
     }
 }
@@ -0,0 +1,370 @@
+//! Common parts of the `embassy-time` driver, which runs on a general purpose
+//! or wide timer
+
+/// An internal macro to implement the `embassy-time` driver. `wide` lists the
+/// 32/64-bit timers, which are concatenated into a 64-bit counter, and
+/// `timers` the 16/32-bit ones, which are extended to 64 bits in software.
+/// Each timer is given with its power domain and A-half interrupt.
+///
+/// The invoking module must import the timers, `Interrupt`, `sysctl` and
+/// `Clocks`, and the crate must depend on `embassy-time-driver` and
+/// `embassy-time-queue-utils`.
+#[macro_export]
+macro_rules! time_driver_macro {
+    (
+        wide: [$($WTIM:ident: ($wide_domain:ident, $wide_interrupt:ident),)*],
+        timers: [$($TIM:ident: ($powerDomain:ident, $interrupt:ident),)*],
+    ) => {
+        use core::cell::RefCell;
+        use core::marker::PhantomData;
+        use core::sync::atomic::{AtomicU32, Ordering};
+        use core::task::Waker;
+
+        use cortex_m::interrupt::{self, CriticalSection, Mutex};
+        use cortex_m::peripheral::NVIC;
+        use embassy_time_driver::{Driver, TICK_HZ};
+        use embassy_time_queue_utils::Queue;
+
+        #[doc(hidden)]
+        pub use embassy_time_driver;
+
+        /// A timer which can run the `embassy-time` driver
+        pub trait DriverTimer: crate::Sealed + 'static {
+            #[doc(hidden)]
+            fn _start(self, pc: &sysctl::PowerControl, clocks: &Clocks);
+
+            /// The number of timer cycles per `embassy-time` tick, or zero if the
+            /// timer hasn't been started
+            #[doc(hidden)]
+            fn _divider() -> &'static AtomicU32;
+
+            /// The number of times the 32-bit counter has wrapped. The 64-bit
+            /// counters of the wide timers don't wrap, so this stays at zero for
+            /// them.
+            #[doc(hidden)]
+            fn _overflows() -> &'static AtomicU32;
+
+            #[doc(hidden)]
+            fn _now() -> u64;
+
+            /// Arms the alarm for `at`. Returns `false` if `at` has already passed,
+            /// in which case the alarm is not armed.
+            #[doc(hidden)]
+            fn _set_alarm(at: u64) -> bool;
+
+            /// Clears the alarm flag and counts any overflow
+            #[doc(hidden)]
+            fn _clear_alarm();
+        }
+
+        /// Powers up `tim` and starts it counting. Call this once, after the clocks
+        /// are set up and before anything uses `embassy-time`.
+        pub fn start<TIM: DriverTimer>(tim: TIM, pc: &sysctl::PowerControl, clocks: &Clocks) {
+            tim._start(pc, clocks);
+        }
+
+        /// The `embassy-time` driver. Use the
+        /// [`timer_time_driver!`](crate::timer_time_driver) macro rather than this
+        /// type directly.
+        pub struct TimerDriver<TIM> {
+            queue: Mutex<RefCell<Queue>>,
+            _tim: PhantomData<fn() -> TIM>,
+        }
+
+        impl<TIM> TimerDriver<TIM> {
+            #[doc(hidden)]
+            pub const fn new() -> Self {
+                TimerDriver {
+                    queue: Mutex::new(RefCell::new(Queue::new())),
+                    _tim: PhantomData,
+                }
+            }
+        }
+
+        impl<TIM: DriverTimer> TimerDriver<TIM> {
+            /// Handles the timer interrupt, waking any expired tasks
+            #[doc(hidden)]
+            pub fn _on_interrupt(&self) {
+                TIM::_clear_alarm();
+                interrupt::free(|cs| self.arm_next(cs));
+            }
+
+            /// Arms the alarm for the earliest waker in the queue, waking anything
+            /// that has expired while we were doing so
+            fn arm_next(&self, cs: &CriticalSection) {
+                let mut queue = self.queue.borrow(cs).borrow_mut();
+                let mut next = queue.next_expiration(TIM::_now());
+                while !TIM::_set_alarm(next) {
+                    next = queue.next_expiration(TIM::_now());
+                }
+            }
+        }
+
+        impl<TIM: DriverTimer> Driver for TimerDriver<TIM> {
+            fn now(&self) -> u64 {
+                TIM::_now()
+            }
+
+            fn schedule_wake(&self, at: u64, waker: &Waker) {
+                interrupt::free(|cs| {
+                    if self.queue.borrow(cs).borrow_mut().schedule_wake(at, waker) {
+                        self.arm_next(cs);
+                    }
+                })
+            }
+        }
+
+        $(
+            impl crate::Sealed for $WTIM {}
+
+            impl DriverTimer for $WTIM {
+                fn _divider() -> &'static AtomicU32 {
+                    static DIVIDER: AtomicU32 = AtomicU32::new(0);
+                    &DIVIDER
+                }
+
+                fn _overflows() -> &'static AtomicU32 {
+                    static OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+                    &OVERFLOWS
+                }
+
+                fn _start(self, pc: &sysctl::PowerControl, clocks: &Clocks) {
+                    let tim = self;
+                    assert!(
+                        TICK_HZ != 0 && u64::from(clocks.sysclk.0) % TICK_HZ == 0,
+                        "embassy-time tick rate must divide the system clock"
+                    );
+
+                    // power up
+                    sysctl::control_power(
+                        pc, sysctl::Domain::$wide_domain,
+                        sysctl::RunMode::Run, sysctl::PowerState::On);
+                    sysctl::reset(pc, sysctl::Domain::$wide_domain);
+
+                    // Stop Timers
+                    tim.ctl.write(|w|
+                                  w.taen().clear_bit()
+                                  .tben().clear_bit()
+                                  .tastall().set_bit()
+                    );
+
+                    // 64-bit concatenated, periodic, counting up, with the
+                    // match interrupt enabled
+                    tim.cfg.write(|w| w.cfg()._32_bit_timer());
+                    tim.tamr.write(|w| w.tamr().period().tacdir().set_bit().tamie().set_bit());
+
+                    tim.tailr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+                    tim.tbilr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+                    tim.tbmatchr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+                    tim.tamatchr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+
+                    // The alarm is armed by `_set_alarm`
+                    tim.icr.write(|w| w.tamcint().set_bit());
+                    tim.imr.write(|w| w.tamim().clear_bit());
+
+                    // start counter
+                    tim.ctl.modify(|_, w| w.taen().set_bit());
+
+                    // Once this is non-zero, `_now` will read the timer
+                    Self::_divider().store(
+                        (u64::from(clocks.sysclk.0) / TICK_HZ) as u32,
+                        Ordering::Release,
+                    );
+
+                    unsafe { NVIC::unmask(Interrupt::$wide_interrupt) };
+                }
+
+                fn _now() -> u64 {
+                    let divider = Self::_divider().load(Ordering::Acquire);
+                    if divider == 0 {
+                        // Not started yet, and the timer might not even be
+                        // powered up, so don't touch it.
+                        return 0;
+                    }
+                    // We only ever read the counter here, so this is safe.
+                    let tim = unsafe { &*$WTIM::ptr() };
+                    // Read the top half either side of the bottom half, in
+                    // case the bottom half wrapped in between
+                    let counter = loop {
+                        let high = tim.tbv.read().bits();
+                        let low = tim.tav.read().bits();
+                        if tim.tbv.read().bits() == high {
+                            break (u64::from(high) << 32) | u64::from(low);
+                        }
+                    };
+                    counter / u64::from(divider)
+                }
+
+                fn _set_alarm(at: u64) -> bool {
+                    let divider = Self::_divider().load(Ordering::Acquire);
+                    if divider == 0 {
+                        // Not started yet; `_start` doesn't know about the
+                        // queue, so there's nothing we can do.
+                        return true;
+                    }
+                    // We only touch the match registers here, so this is safe.
+                    let tim = unsafe { &*$WTIM::ptr() };
+                    if at == u64::MAX {
+                        // Nothing to wait for
+                        tim.imr.modify(|_, w| w.tamim().clear_bit());
+                        return true;
+                    }
+
+                    let target = at.saturating_mul(u64::from(divider));
+                    tim.tbmatchr.write(|w| unsafe { w.bits((target >> 32) as u32) });
+                    tim.tamatchr.write(|w| unsafe { w.bits(target as u32) });
+                    tim.imr.modify(|_, w| w.tamim().set_bit());
+
+                    // The match only fires when the counter equals the
+                    // target, so if we've already gone past it, say so.
+                    if Self::_now() >= at {
+                        tim.imr.modify(|_, w| w.tamim().clear_bit());
+                        return false;
+                    }
+                    true
+                }
+
+                fn _clear_alarm() {
+                    // Write-one-to-clear, so this is safe
+                    let tim = unsafe { &*$WTIM::ptr() };
+                    tim.icr.write(|w| w.tamcint().set_bit());
+                }
+            }
+        )*
+
+        $(
+            impl crate::Sealed for $TIM {}
+
+            impl DriverTimer for $TIM {
+                fn _divider() -> &'static AtomicU32 {
+                    static DIVIDER: AtomicU32 = AtomicU32::new(0);
+                    &DIVIDER
+                }
+
+                fn _overflows() -> &'static AtomicU32 {
+                    static OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+                    &OVERFLOWS
+                }
+
+                fn _start(self, pc: &sysctl::PowerControl, clocks: &Clocks) {
+                    let tim = self;
+                    assert!(
+                        TICK_HZ != 0 && u64::from(clocks.sysclk.0) % TICK_HZ == 0,
+                        "embassy-time tick rate must divide the system clock"
+                    );
+
+                    // power up
+                    sysctl::control_power(
+                        pc, sysctl::Domain::$powerDomain,
+                        sysctl::RunMode::Run, sysctl::PowerState::On);
+                    sysctl::reset(pc, sysctl::Domain::$powerDomain);
+
+                    // Stop Timers
+                    tim.ctl.write(|w|
+                                  w.taen().clear_bit()
+                                  .tben().clear_bit()
+                                  .tastall().set_bit()
+                    );
+
+                    // 32-bit, periodic, counting up, with the match interrupt
+                    // enabled
+                    tim.cfg.write(|w| w.cfg()._32_bit_timer());
+                    tim.tamr.write(|w| w.tamr().period().tacdir().set_bit().tamie().set_bit());
+
+                    tim.tailr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+                    tim.tamatchr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+
+                    // Overflows always interrupt; the alarm is armed by
+                    // `_set_alarm`
+                    tim.icr.write(|w| w.tatocint().set_bit().tamcint().set_bit());
+                    tim.imr.write(|w| w.tatoim().set_bit().tamim().clear_bit());
+
+                    Self::_overflows().store(0, Ordering::Relaxed);
+
+                    // start counter
+                    tim.ctl.modify(|_, w| w.taen().set_bit());
+
+                    // Once this is non-zero, `_now` will read the timer
+                    Self::_divider().store(
+                        (u64::from(clocks.sysclk.0) / TICK_HZ) as u32,
+                        Ordering::Release,
+                    );
+
+                    unsafe { NVIC::unmask(Interrupt::$interrupt) };
+                }
+
+                fn _now() -> u64 {
+                    let divider = Self::_divider().load(Ordering::Acquire);
+                    if divider == 0 {
+                        // Not started yet, and the timer might not even be
+                        // powered up, so don't touch it.
+                        return 0;
+                    }
+                    // We only ever read the counter here, so this is safe.
+                    let tim = unsafe { &*$TIM::ptr() };
+                    let counter = interrupt::free(|_| {
+                        let mut high = Self::_overflows().load(Ordering::Relaxed);
+                        let low = tim.tav.read().bits();
+                        // If the counter has wrapped but the interrupt
+                        // hasn't run yet, account for it here.
+                        if tim.ris.read().tatoris().bit_is_set() && low < 0x8000_0000 {
+                            high += 1;
+                        }
+                        (u64::from(high) << 32) | u64::from(low)
+                    });
+                    counter / u64::from(divider)
+                }
+
+                fn _set_alarm(at: u64) -> bool {
+                    let divider = Self::_divider().load(Ordering::Acquire);
+                    if divider == 0 {
+                        // Not started yet; `_start` doesn't know about the
+                        // queue, so there's nothing we can do.
+                        return true;
+                    }
+                    // We only touch the match registers here, so this is safe.
+                    let tim = unsafe { &*$TIM::ptr() };
+                    if at == u64::MAX {
+                        // Nothing to wait for
+                        tim.imr.modify(|_, w| w.tamim().clear_bit());
+                        return true;
+                    }
+
+                    let target = at.saturating_mul(u64::from(divider));
+                    let now = Self::_now().saturating_mul(u64::from(divider));
+                    if (target >> 32) == (now >> 32) {
+                        tim.tamatchr.write(|w| unsafe { w.bits(target as u32) });
+                        tim.imr.modify(|_, w| w.tamim().set_bit());
+                    } else {
+                        // Not in this period; the next overflow re-arms us
+                        tim.imr.modify(|_, w| w.tamim().clear_bit());
+                    }
+
+                    // The match only fires when the counter equals the
+                    // target, so if we've already gone past it, say so.
+                    if Self::_now() >= at {
+                        tim.imr.modify(|_, w| w.tamim().clear_bit());
+                        return false;
+                    }
+                    true
+                }
+
+                fn _clear_alarm() {
+                    // Write-one-to-clear, so this is safe
+                    let tim = unsafe { &*$TIM::ptr() };
+                    interrupt::free(|_| {
+                        tim.icr.write(|w| w.tamcint().set_bit());
+                        if tim.ris.read().tatoris().bit_is_set() {
+                            tim.icr.write(|w| w.tatocint().set_bit());
+                            let overflows = Self::_overflows();
+                            overflows.store(
+                                overflows.load(Ordering::Relaxed).wrapping_add(1),
+                                Ordering::Relaxed,
+                            );
+                        }
+                    });
+                }
+            }
+        )*
+    };
+}
@@ -0,0 +1,596 @@
+//! Common code for the Micro Direct Memory Access (uDMA) controller.
+//!
+//! The controller has 32 channels, each with a primary and an alternate
+//! control structure in a [`ControlTable`] the application provides. Each
+//! peripheral's requests are routed to a channel by an encoding in the
+//! DMACHMAPn registers; the drivers which use the uDMA know which channel
+//! and encoding they need.
+//!
+//! The registers are the same on the TM4C123 and TM4C129, and are reached
+//! through their addresses, so that a driver's interrupt handler can move a
+//! transfer on without owning the controller.
+//!
+//! When a channel's transfer completes, the interrupt of the peripheral
+//! which requested it is raised. A [`Transfer`] keeps track of the halves
+//! of a transfer completed, for the peripheral's interrupt handler and the
+//! application to share.
+
+use core::task::{Poll, Waker};
+
+/// Base address of the uDMA controller
+#[doc(hidden)]
+pub const BASE: usize = 0x400F_F000;
+
+/// Offset of DMACFG
+#[doc(hidden)]
+pub const CFG: usize = 0x004;
+/// Offset of DMACTLBASE
+#[doc(hidden)]
+pub const CTLBASE: usize = 0x008;
+const USEBURSTCLR: usize = 0x01C;
+const REQMASKCLR: usize = 0x024;
+const ENASET: usize = 0x028;
+const ENACLR: usize = 0x02C;
+const ALTCLR: usize = 0x034;
+const PRIOCLR: usize = 0x03C;
+const CHIS: usize = 0x504;
+const CHMAP0: usize = 0x510;
+
+/// The MASTEN bit of DMACFG
+#[doc(hidden)]
+pub const CFG_MASTEN: u32 = 1 << 0;
+
+/// The most items one control structure can move
+pub const MAX_TRANSFER: usize = 1024;
+
+/// Number of uDMA channels
+pub const CHANNELS: usize = 32;
+
+const INC_BYTE: u32 = 0;
+const INC_NONE: u32 = 3;
+/// Rearbitrate after four items
+const ARBSIZE_4: u32 = 2;
+const MODE_MASK: u32 = 0x7;
+const MODE_STOP: u32 = 0;
+const MODE_BASIC: u32 = 1;
+const MODE_PING_PONG: u32 = 3;
+
+/// One channel control structure
+#[doc(hidden)]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Descriptor {
+    /// Address of the last source item
+    pub source_end: u32,
+    /// Address of the last destination item
+    pub destination_end: u32,
+    /// The DMACHCTL word
+    pub control: u32,
+    unused: u32,
+}
+
+impl Descriptor {
+    const STOPPED: Descriptor = Descriptor {
+        source_end: 0,
+        destination_end: 0,
+        control: 0,
+        unused: 0,
+    };
+
+    fn control(source_inc: u32, destination_inc: u32, length: usize, ping_pong: bool) -> u32 {
+        let mode = if ping_pong {
+            MODE_PING_PONG
+        } else {
+            MODE_BASIC
+        };
+        destination_inc << 30
+            | source_inc << 26
+            | ARBSIZE_4 << 14
+            | ((length - 1) as u32) << 4
+            | mode
+    }
+
+    /// Moves the `length` bytes at `source` to the data register at
+    /// `register`, one byte at a time. `length` must be between 1 and
+    /// [`MAX_TRANSFER`].
+    pub fn memory_to_peripheral(
+        source: usize,
+        length: usize,
+        register: usize,
+        ping_pong: bool,
+    ) -> Self {
+        Descriptor {
+            source_end: (source + length - 1) as u32,
+            destination_end: register as u32,
+            control: Self::control(INC_BYTE, INC_NONE, length, ping_pong),
+            unused: 0,
+        }
+    }
+
+    /// Moves `length` bytes from the data register at `register` to
+    /// `destination`, one byte at a time. `length` must be between 1 and
+    /// [`MAX_TRANSFER`].
+    pub fn peripheral_to_memory(
+        register: usize,
+        destination: usize,
+        length: usize,
+        ping_pong: bool,
+    ) -> Self {
+        Descriptor {
+            source_end: register as u32,
+            destination_end: (destination + length - 1) as u32,
+            control: Self::control(INC_NONE, INC_BYTE, length, ping_pong),
+            unused: 0,
+        }
+    }
+}
+
+/// The channel control structures, which the uDMA controller reads and
+/// writes as it goes. It must live for as long as the controller is in use.
+#[repr(C, align(1024))]
+pub struct ControlTable {
+    /// The primary structures, then the alternate ones
+    descriptors: [Descriptor; 2 * CHANNELS],
+}
+
+impl ControlTable {
+    /// A table with every channel stopped
+    pub const fn new() -> Self {
+        ControlTable {
+            descriptors: [Descriptor::STOPPED; 2 * CHANNELS],
+        }
+    }
+}
+
+impl Default for ControlTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe fn write(offset: usize, value: u32) {
+    core::ptr::write_volatile((BASE + offset) as *mut u32, value);
+}
+
+unsafe fn read(offset: usize) -> u32 {
+    core::ptr::read_volatile((BASE + offset) as *const u32)
+}
+
+/// Routes the requests of the peripheral with encoding `encoding` to
+/// `channel`, and sets the channel up to take single and burst requests at
+/// default priority, starting with the primary structure
+///
+/// # Safety
+///
+/// The controller must be enabled, and the channel not in use.
+#[doc(hidden)]
+pub unsafe fn assign(channel: usize, encoding: u32) {
+    let map = CHMAP0 + channel / 8 * 4;
+    let shift = channel % 8 * 4;
+    write(map, read(map) & !(0xF << shift) | encoding << shift);
+    let bit = 1 << channel;
+    write(USEBURSTCLR, bit);
+    write(REQMASKCLR, bit);
+    write(ALTCLR, bit);
+    write(PRIOCLR, bit);
+}
+
+/// Sets the primary, or alternate, control structure of `channel`
+///
+/// # Safety
+///
+/// The controller must be enabled, and nothing else may be using the
+/// channel. The structure must not be in use by the controller.
+#[doc(hidden)]
+pub unsafe fn set_descriptor(channel: usize, alternate: bool, descriptor: &Descriptor) {
+    let table = read(CTLBASE) as *mut Descriptor;
+    let index = channel + if alternate { CHANNELS } else { 0 };
+    core::ptr::write_volatile(table.add(index), *descriptor);
+}
+
+/// Has the controller finished with the primary, or alternate, control
+/// structure of `channel`? It sets the mode of a structure to stop once it
+/// has moved all its data.
+///
+/// # Safety
+///
+/// The controller must be enabled.
+#[doc(hidden)]
+pub unsafe fn is_stopped(channel: usize, alternate: bool) -> bool {
+    let table = read(CTLBASE) as *const Descriptor;
+    let index = channel + if alternate { CHANNELS } else { 0 };
+    let control = core::ptr::read_volatile(core::ptr::addr_of!((*table.add(index)).control));
+    control & MODE_MASK == MODE_STOP
+}
+
+/// Enables `channel`, so it moves data when its peripheral asks
+///
+/// # Safety
+///
+/// The channel's control structures must be set up.
+#[doc(hidden)]
+pub unsafe fn enable(channel: usize) {
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+    write(ENASET, 1 << channel);
+}
+
+/// Disables `channel`
+///
+/// # Safety
+///
+/// Nothing else may be using the channel.
+#[doc(hidden)]
+pub unsafe fn disable(channel: usize) {
+    write(ENACLR, 1 << channel);
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+/// Returns whether `channel` has completed a control structure since this
+/// was last called, and clears its completion flag
+///
+/// # Safety
+///
+/// Nothing else may be using the channel.
+#[doc(hidden)]
+pub unsafe fn take_done(channel: usize) -> bool {
+    let done = read(CHIS) & 1 << channel != 0;
+    if done {
+        write(CHIS, 1 << channel);
+    }
+    done
+}
+
+/// An error starting or following a uDMA transfer
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DmaError {
+    /// The buffer is empty, too long, or for a circular transfer, not of
+    /// even length
+    BadLength,
+    /// A transfer is already in progress
+    Busy,
+    /// A circular transfer refilled a half of its buffer before its last
+    /// contents were taken
+    Overrun,
+}
+
+impl core::fmt::Display for DmaError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DmaError::BadLength => write!(f, "Buffer length is not supported"),
+            DmaError::Busy => write!(f, "A transfer is in progress"),
+            DmaError::Overrun => write!(f, "Buffer refilled before it was read"),
+        }
+    }
+}
+
+/// A half of a transfer finished
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DmaEvent {
+    /// The first half of the buffer is done
+    HalfComplete,
+    /// The second half of the buffer is done. A circular transfer carries
+    /// on into the first half.
+    Complete,
+}
+
+/// A transfer of a buffer in two halves, one for each control structure,
+/// shared between the application and the interrupt handler
+#[doc(hidden)]
+pub struct Transfer {
+    buffer: usize,
+    length: usize,
+    /// Start again once the second half is done?
+    circular: bool,
+    active: bool,
+    /// Halves completed since the start
+    completed: usize,
+    /// Halves reported to the application
+    reported: usize,
+    waker: Option<Waker>,
+}
+
+impl Transfer {
+    /// No transfer
+    pub const fn new() -> Self {
+        Transfer {
+            buffer: 0,
+            length: 0,
+            circular: false,
+            active: false,
+            completed: 0,
+            reported: 0,
+            waker: None,
+        }
+    }
+
+    /// Starts a transfer of the `length` bytes at `buffer`, returning the
+    /// descriptors for the two halves: the alternate one is `None` when
+    /// a one byte transfer can't be halved. `descriptor` makes the
+    /// descriptor for part of the buffer, given its address, length and
+    /// whether another will follow it.
+    pub fn start(
+        &mut self,
+        buffer: usize,
+        length: usize,
+        circular: bool,
+        descriptor: impl Fn(usize, usize, bool) -> Descriptor,
+    ) -> Result<(Descriptor, Option<Descriptor>), DmaError> {
+        if self.active {
+            return Err(DmaError::Busy);
+        }
+        if length == 0 || length > 2 * MAX_TRANSFER || (circular && !length.is_multiple_of(2)) {
+            return Err(DmaError::BadLength);
+        }
+        *self = Transfer {
+            buffer,
+            length,
+            circular,
+            active: true,
+            ..Transfer::new()
+        };
+        let first = length.div_ceil(2);
+        if first == length {
+            return Ok((descriptor(buffer, length, false), None));
+        }
+        Ok((
+            descriptor(buffer, first, true),
+            Some(descriptor(buffer + first, length - first, circular)),
+        ))
+    }
+
+    /// Records that a control structure has completed. Returns whether
+    /// it was the alternate one, which the interrupt handler must set up
+    /// again if the transfer is circular, and the task to wake, if any.
+    pub fn complete(&mut self) -> (bool, Option<Waker>) {
+        if !self.active {
+            return (false, None);
+        }
+        let alternate = self.next_alternate();
+        if self.length == 1 {
+            // There was nothing to halve
+            self.completed += 2;
+        } else {
+            self.completed += 1;
+        }
+        if !self.circular && self.completed >= 2 {
+            self.active = false;
+        }
+        (alternate, self.waker.take())
+    }
+
+    /// Is the alternate control structure the next to complete?
+    pub fn next_alternate(&self) -> bool {
+        self.completed % 2 == 1
+    }
+
+    /// Returns the address and length of the whole buffer
+    pub fn buffer(&self) -> (usize, usize) {
+        (self.buffer, self.length)
+    }
+
+    /// Is the transfer still going?
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Stops the transfer
+    pub fn stop(&mut self) {
+        self.active = false;
+    }
+
+    /// Takes the next half completed, if there is one
+    pub fn take_event(&mut self) -> Result<Option<DmaEvent>, DmaError> {
+        if self.reported == self.completed {
+            return Ok(None);
+        }
+        if self.circular && self.completed - self.reported >= 2 {
+            // The half waiting to be taken is being filled again
+            self.reported = self.completed;
+            return Err(DmaError::Overrun);
+        }
+        let event = if self.reported.is_multiple_of(2) {
+            DmaEvent::HalfComplete
+        } else {
+            DmaEvent::Complete
+        };
+        self.reported += 1;
+        Ok(Some(event))
+    }
+
+    /// Wakes `waker` when the next half completes
+    pub fn register(&mut self, waker: &Waker) {
+        self.waker = Some(waker.clone());
+    }
+
+    /// Takes the next half completed, or has `waker` woken when there is
+    /// one. Once the transfer has stopped and every half has been taken,
+    /// nothing would wake it, so this is ready with `None` instead.
+    pub fn poll_event(&mut self, waker: &Waker) -> Poll<Result<Option<DmaEvent>, DmaError>> {
+        match self.take_event() {
+            Ok(None) if self.active => {
+                self.register(waker);
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// Returns the address and length of the half of the buffer `event`
+    /// is about
+    pub fn half(&self, event: DmaEvent) -> (usize, usize) {
+        let first = self.length.div_ceil(2);
+        match event {
+            DmaEvent::HalfComplete => (self.buffer, first),
+            DmaEvent::Complete => (self.buffer + first, self.length - first),
+        }
+    }
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An internal macro to implement the uDMA controller. The invoking module
+/// must import `UDMA` and `sysctl`.
+#[macro_export]
+macro_rules! udma_hal_macro {
+    () => {
+        /// The uDMA controller
+        pub struct Udma {
+            udma: UDMA,
+            table: &'static mut $crate::udma::ControlTable,
+        }
+
+        impl Udma {
+            /// Enables the uDMA controller, with `table` as its control
+            /// table
+            pub fn new(
+                udma: UDMA,
+                table: &'static mut $crate::udma::ControlTable,
+                pc: &sysctl::PowerControl,
+            ) -> Self {
+                sysctl::control_power(
+                    pc,
+                    sysctl::Domain::MicroDma,
+                    sysctl::RunMode::Run,
+                    sysctl::PowerState::On,
+                );
+                sysctl::reset(pc, sysctl::Domain::MicroDma);
+
+                // The registers are reached by address, so drivers can
+                // share them; we own the controller, so this is safe
+                let base = $crate::udma::BASE;
+                unsafe {
+                    core::ptr::write_volatile(
+                        (base + $crate::udma::CFG) as *mut u32,
+                        $crate::udma::CFG_MASTEN,
+                    );
+                    core::ptr::write_volatile(
+                        (base + $crate::udma::CTLBASE) as *mut u32,
+                        table as *mut $crate::udma::ControlTable as u32,
+                    );
+                }
+                Udma { udma, table }
+            }
+
+            /// Disables the uDMA controller, and releases it and its
+            /// control table
+            pub fn free(self) -> (UDMA, &'static mut $crate::udma::ControlTable) {
+                unsafe {
+                    core::ptr::write_volatile(
+                        ($crate::udma::BASE + $crate::udma::CFG) as *mut u32,
+                        0,
+                    );
+                }
+                (self.udma, self.table)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(buffer: usize, length: usize, ping_pong: bool) -> Descriptor {
+        Descriptor::peripheral_to_memory(0x4000_C000, buffer, length, ping_pong)
+    }
+
+    #[test]
+    fn control_words() {
+        let tx = Descriptor::memory_to_peripheral(0x2000_0000, 16, 0x4000_C000, false);
+        assert_eq!(tx.source_end, 0x2000_000F);
+        assert_eq!(tx.destination_end, 0x4000_C000);
+        assert_eq!(tx.control, 0xC000_8000 | 15 << 4 | 1);
+
+        let rx = descriptor(0x2000_0000, 1024, true);
+        assert_eq!(rx.source_end, 0x4000_C000);
+        assert_eq!(rx.destination_end, 0x2000_03FF);
+        assert_eq!(rx.control, 0x0C00_8000 | 1023 << 4 | 3);
+    }
+
+    #[test]
+    fn halves() {
+        let mut transfer = Transfer::new();
+        let (primary, alternate) = transfer.start(0x2000_0000, 5, false, descriptor).unwrap();
+        assert_eq!(primary, descriptor(0x2000_0000, 3, true));
+        assert_eq!(alternate, Some(descriptor(0x2000_0003, 2, false)));
+        assert_eq!(transfer.start(0, 4, false, descriptor), Err(DmaError::Busy));
+
+        assert!(!transfer.next_alternate());
+        assert!(!transfer.complete().0);
+        assert!(transfer.next_alternate());
+        assert_eq!(transfer.take_event(), Ok(Some(DmaEvent::HalfComplete)));
+        assert_eq!(transfer.take_event(), Ok(None));
+        assert!(transfer.is_active());
+        assert!(transfer.complete().0);
+        assert!(!transfer.is_active());
+        assert_eq!(transfer.take_event(), Ok(Some(DmaEvent::Complete)));
+        assert_eq!(transfer.half(DmaEvent::Complete), (0x2000_0003, 2));
+
+        let (primary, alternate) = transfer.start(0x2000_0000, 1, false, descriptor).unwrap();
+        assert_eq!(primary, descriptor(0x2000_0000, 1, false));
+        assert_eq!(alternate, None);
+        transfer.complete();
+        assert!(!transfer.is_active());
+        assert_eq!(transfer.take_event(), Ok(Some(DmaEvent::HalfComplete)));
+        assert_eq!(transfer.take_event(), Ok(Some(DmaEvent::Complete)));
+    }
+
+    #[test]
+    fn poll_after_stop() {
+        let waker = Waker::noop();
+        let mut transfer = Transfer::new();
+        assert_eq!(transfer.poll_event(waker), Poll::Ready(Ok(None)));
+
+        transfer.start(0x2000_0000, 4, false, descriptor).unwrap();
+        assert_eq!(transfer.poll_event(waker), Poll::Pending);
+        transfer.complete();
+        transfer.complete();
+        assert_eq!(
+            transfer.poll_event(waker),
+            Poll::Ready(Ok(Some(DmaEvent::HalfComplete)))
+        );
+        assert_eq!(
+            transfer.poll_event(waker),
+            Poll::Ready(Ok(Some(DmaEvent::Complete)))
+        );
+        assert_eq!(transfer.poll_event(waker), Poll::Ready(Ok(None)));
+
+        transfer.start(0x2000_0000, 4, true, descriptor).unwrap();
+        assert_eq!(transfer.poll_event(waker), Poll::Pending);
+        transfer.stop();
+        assert_eq!(transfer.poll_event(waker), Poll::Ready(Ok(None)));
+    }
+
+    #[test]
+    fn circular() {
+        let mut transfer = Transfer::new();
+        assert_eq!(
+            transfer.start(0x2000_0000, 7, true, descriptor),
+            Err(DmaError::BadLength)
+        );
+        assert_eq!(
+            transfer.start(0x2000_0000, 2 * MAX_TRANSFER + 2, true, descriptor),
+            Err(DmaError::BadLength)
+        );
+        let (_, alternate) = transfer.start(0x2000_0000, 8, true, descriptor).unwrap();
+        assert_eq!(alternate, Some(descriptor(0x2000_0004, 4, true)));
+
+        for _ in 0..3 {
+            transfer.complete();
+            assert_eq!(transfer.take_event(), Ok(Some(DmaEvent::HalfComplete)));
+            transfer.complete();
+            assert_eq!(transfer.take_event(), Ok(Some(DmaEvent::Complete)));
+        }
+        assert!(transfer.is_active());
+
+        // Fall behind
+        transfer.complete();
+        transfer.complete();
+        assert_eq!(transfer.take_event(), Err(DmaError::Overrun));
+        assert_eq!(transfer.take_event(), Ok(None));
+    }
+}
@@ -0,0 +1,923 @@
+//! A/B firmware updates on the internal flash.
+//!
+//! The flash is split into a bootloader, two image slots and a little space
+//! for bookkeeping (see [`Layout`]). The running application lives in the
+//! primary slot. It downloads a new image into the secondary slot, and asks
+//! for it to be installed at the next boot. The bootloader then installs it,
+//! either by swapping the two slots (so the old image can be restored) or by
+//! copying the new image over the old one.
+//!
+//! After a swap, the new image must [`confirm`](Updater::confirm) itself. If
+//! it doesn't before the next reset, the bootloader swaps the old image back.
+//!
+//! In the application:
+//!
+//! ```ignore
+//! let mut updater = Updater::new(flash, LAYOUT)?;
+//! updater.confirm()?;
+//! // ... later, with a new image (header included) ...
+//! updater.erase_secondary()?;
+//! updater.write_secondary(0, &image)?;
+//! updater.request_update(Mode::Swap)?;
+//! ```
+//!
+//! In the bootloader:
+//!
+//! ```ignore
+//! let mut updater = Updater::new(flash, LAYOUT)?;
+//! if updater.boot()?.is_some() {
+//!     unsafe { start_application(&LAYOUT) }
+//! }
+//! ```
+//!
+//! # Images
+//!
+//! An image starts with a [`HEADER_SIZE`] byte header, which is padded with
+//! `0xFF` and which leaves the vector table suitably aligned. The header
+//! starts with (all little-endian):
+//!
+//! | Offset | Size | Contents                                   |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 4    | Magic number `0x5746_4D54` (`"TMFW"`)      |
+//! | 4      | 4    | Version                                    |
+//! | 8      | 4    | Length of the image, after the header      |
+//! | 12     | 4    | CRC-32 of the image, after the header      |
+//! | 16     | 4    | CRC-32 of bytes 0 to 15                    |
+//!
+//! [`ImageHeader::to_bytes`] builds one, and as this crate builds on a host,
+//! it can be used by the tool which packages the image.
+//!
+//! # State
+//!
+//! Progress is kept in the state area as a log of words, each written once,
+//! so that a reset at any point (even during the installation) leaves enough
+//! to carry on from. Every step of a swap or copy can be safely repeated, so
+//! the bootloader simply redoes the last step it hadn't logged.
+//!
+//! A swap moves each page in three steps, through the scratch page: primary
+//! to scratch, secondary to primary, and scratch to secondary. Rolling back
+//! is another swap.
+
+use core::fmt;
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::crc::crc32_update;
+
+/// The size of the image header. Images are linked to start this far into
+/// the slot.
+pub const HEADER_SIZE: usize = 0x400;
+
+/// The magic number at the start of an image header
+pub const HEADER_MAGIC: u32 = 0x5746_4D54;
+
+/// The start of a request record; the low bits hold the mode and page count
+const RECORD_REQUEST: u32 = 0x5100_0000;
+/// The start of a swap step record; the low bits hold the step
+const RECORD_SWAP: u32 = 0x5300_0000;
+/// The start of a copy step record; the low bits hold the page
+const RECORD_COPY: u32 = 0x4300_0000;
+/// The start of a roll back step record; the low bits hold the step
+const RECORD_BACK: u32 = 0x4200_0000;
+/// The new image has been swapped in, and must be confirmed
+const RECORD_TESTING: u32 = 0x5445_5354;
+/// The new image has been confirmed (or copied)
+const RECORD_CONFIRMED: u32 = 0x4F4B_4159;
+/// The old image has been swapped back in
+const RECORD_ROLLED_BACK: u32 = 0x524F_4C4C;
+/// The requested image was invalid
+const RECORD_REJECTED: u32 = 0x524A_4354;
+/// Nothing written yet
+const RECORD_NONE: u32 = 0xFFFF_FFFF;
+
+/// The bits of a record which say what it is
+const RECORD_TAG: u32 = 0xFF00_0000;
+
+/// The size of the buffer used to copy and check images
+const CHUNK: usize = 256;
+
+/// Possible errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    /// The flash failed
+    Flash(E),
+    /// The layout isn't aligned to the flash's pages, has areas which overlap
+    /// or don't fit, or has too small a state area
+    BadLayout,
+    /// The image in the slot is missing, too big or corrupt
+    InvalidImage,
+    /// That can't be done in the current state
+    WrongState(State),
+    /// The state area is full
+    StateFull,
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Flash(e) => write!(f, "flash error: {:?}", e),
+            Error::BadLayout => write!(f, "layout is unaligned, overlapping or out of bounds"),
+            Error::InvalidImage => write!(f, "image is invalid"),
+            Error::WrongState(state) => write!(f, "not allowed in state {:?}", state),
+            Error::StateFull => write!(f, "state area is full"),
+        }
+    }
+}
+
+/// How an update is installed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Swap the slots, so the old image can be restored if the new one isn't
+    /// confirmed
+    Swap,
+    /// Copy the new image over the old one. This is quicker, but the new
+    /// image can't be rolled back.
+    Copy,
+}
+
+/// Where an update has got to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// No update has been requested
+    Idle,
+    /// An update has been requested, and will be installed at the next boot
+    Pending(Mode),
+    /// The bootloader was installing an update
+    Installing(Mode),
+    /// The new image has been swapped in, and must be confirmed before the
+    /// next reset
+    Testing,
+    /// The new image was confirmed (or copied in)
+    Confirmed,
+    /// The bootloader was restoring the old image
+    RollingBack,
+    /// The new image wasn't confirmed, and the old image has been restored
+    RolledBack,
+    /// The requested image was invalid, so it wasn't installed
+    Rejected,
+}
+
+/// An image header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// The image's version
+    pub version: u32,
+    /// The length of the image, after the header
+    pub length: u32,
+    /// The CRC-32 of the image, after the header
+    pub crc: u32,
+}
+
+impl ImageHeader {
+    /// The number of bytes of the header which are used; it's padded to
+    /// [`HEADER_SIZE`] with `0xFF`
+    pub const ENCODED_LEN: usize = 20;
+
+    /// Makes the header for `image`, which doesn't include the header
+    pub fn for_image(version: u32, image: &[u8]) -> Self {
+        ImageHeader {
+            version,
+            length: image.len() as u32,
+            crc: !crc32_update(!0, image),
+        }
+    }
+
+    /// Encodes the header
+    pub fn to_bytes(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut bytes = [0; Self::ENCODED_LEN];
+        bytes[0..4].copy_from_slice(&HEADER_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.crc.to_le_bytes());
+        let crc = !crc32_update(!0, &bytes[..16]);
+        bytes[16..20].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a header, if it's valid
+    pub fn from_bytes(bytes: &[u8; Self::ENCODED_LEN]) -> Option<Self> {
+        let word = |n: usize| {
+            let mut word = [0; 4];
+            word.copy_from_slice(&bytes[n * 4..n * 4 + 4]);
+            u32::from_le_bytes(word)
+        };
+        if word(0) != HEADER_MAGIC || word(4) != !crc32_update(!0, &bytes[..16]) {
+            return None;
+        }
+        Some(ImageHeader {
+            version: word(1),
+            length: word(2),
+            crc: word(3),
+        })
+    }
+}
+
+/// Where the slots and bookkeeping are, as flash addresses. Every area
+/// must be page aligned, and no two may overlap. The state area needs six
+/// words for each page of a slot, and four more.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// The start of the primary slot, which holds the running image
+    pub primary: u32,
+    /// The start of the secondary slot, which takes the new image
+    pub secondary: u32,
+    /// The size of each slot
+    pub slot_size: u32,
+    /// The scratch page, used while swapping
+    pub scratch: u32,
+    /// The start of the state area
+    pub state: u32,
+    /// The size of the state area
+    pub state_size: u32,
+}
+
+impl Layout {
+    /// The address the application is linked at, just after the primary
+    /// slot's header
+    pub fn application_start(&self) -> u32 {
+        self.primary + HEADER_SIZE as u32
+    }
+}
+
+/// Where the log in the state area has got to
+#[derive(Clone, Copy, Debug)]
+struct Log {
+    state: State,
+    /// Pages to swap or copy
+    pages: u32,
+    /// Steps of the swap, copy or roll back done
+    progress: u32,
+    /// Index of the next free word
+    end: u32,
+}
+
+/// Installs and rolls back images. The application and the bootloader each
+/// use one of these, with the same [`Layout`].
+pub struct Updater<F> {
+    flash: F,
+    layout: Layout,
+}
+
+impl<F: NorFlash> Updater<F> {
+    /// Creates an updater, checking that `layout` suits `flash`
+    pub fn new(flash: F, layout: Layout) -> Result<Self, Error<F::Error>> {
+        let page = F::ERASE_SIZE as u32;
+        let aligned = [
+            layout.primary,
+            layout.secondary,
+            layout.slot_size,
+            layout.scratch,
+            layout.state,
+            layout.state_size,
+        ]
+        .iter()
+        .all(|value| value % page == 0);
+        let areas = [
+            (layout.primary, layout.slot_size),
+            (layout.secondary, layout.slot_size),
+            (layout.scratch, page),
+            (layout.state, layout.state_size),
+        ];
+        let fits = areas.iter().all(|&(start, size)| {
+            start
+                .checked_add(size)
+                .is_some_and(|end| end as usize <= flash.capacity())
+        });
+        let apart = areas.iter().enumerate().all(|(n, &(start, size))| {
+            areas[n + 1..].iter().all(|&(other, other_size)| {
+                u64::from(start) + u64::from(size) <= u64::from(other)
+                    || u64::from(other) + u64::from(other_size) <= u64::from(start)
+            })
+        });
+        // A request, the three steps per page of a swap and of a roll back,
+        // and the markers after each. The request only has room for 16 bits
+        // of page count.
+        let pages = layout.slot_size / page;
+        let roomy = pages <= 0xFFFF && u64::from(layout.state_size / 4) >= 6 * u64::from(pages) + 4;
+        if !aligned
+            || !fits
+            || !apart
+            || !roomy
+            || layout.slot_size as usize <= HEADER_SIZE
+            || 4 % F::WRITE_SIZE != 0
+            || 4 % F::READ_SIZE != 0
+        {
+            return Err(Error::BadLayout);
+        }
+        Ok(Updater { flash, layout })
+    }
+
+    /// Releases the flash
+    pub fn free(self) -> F {
+        self.flash
+    }
+
+    /// Returns the layout
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Where an update has got to
+    pub fn state(&mut self) -> Result<State, Error<F::Error>> {
+        Ok(self.read_log()?.state)
+    }
+
+    /// The header of the image in the primary slot, if it's valid
+    pub fn primary_header(&mut self) -> Result<Option<ImageHeader>, Error<F::Error>> {
+        self.valid_header(self.layout.primary)
+    }
+
+    /// The header of the image in the secondary slot, if it's valid
+    pub fn secondary_header(&mut self) -> Result<Option<ImageHeader>, Error<F::Error>> {
+        self.valid_header(self.layout.secondary)
+    }
+
+    /// Erases the secondary slot, ready for a new image. This isn't allowed
+    /// while the secondary slot is part of an update.
+    pub fn erase_secondary(&mut self) -> Result<(), Error<F::Error>> {
+        self.check_secondary_free()?;
+        let start = self.layout.secondary;
+        self.flash
+            .erase(start, start + self.layout.slot_size)
+            .map_err(Error::Flash)
+    }
+
+    /// Writes part of a new image, header included, at `offset` in the
+    /// secondary slot. This isn't allowed while the secondary slot is part of
+    /// an update.
+    pub fn write_secondary(&mut self, offset: u32, data: &[u8]) -> Result<(), Error<F::Error>> {
+        self.check_secondary_free()?;
+        match offset.checked_add(data.len() as u32) {
+            Some(end) if end <= self.layout.slot_size => {}
+            _ => return Err(Error::InvalidImage),
+        }
+        self.flash
+            .write(self.layout.secondary + offset, data)
+            .map_err(Error::Flash)
+    }
+
+    /// Asks the bootloader to install the image in the secondary slot at the
+    /// next boot. The image is checked first.
+    pub fn request_update(&mut self, mode: Mode) -> Result<(), Error<F::Error>> {
+        let log = self.check_secondary_free()?;
+        let new = self.secondary_header()?.ok_or(Error::InvalidImage)?;
+        // Swapping must move the whole of the old image too
+        let old = match mode {
+            Mode::Swap => self.primary_header()?.map_or(0, |header| header.length),
+            Mode::Copy => 0,
+        };
+        let bytes = HEADER_SIZE as u32 + new.length.max(old);
+        let page = F::ERASE_SIZE as u32;
+        let pages = bytes.div_ceil(page);
+
+        if log.end != 0 {
+            self.flash
+                .erase(
+                    self.layout.state,
+                    self.layout.state + self.layout.state_size,
+                )
+                .map_err(Error::Flash)?;
+        }
+        let mode_bits = match mode {
+            Mode::Swap => 1 << 16,
+            Mode::Copy => 2 << 16,
+        };
+        self.append(0, RECORD_REQUEST | mode_bits | pages)
+    }
+
+    /// Confirms the running image, after an update was swapped in, so that
+    /// it's kept. It's fine to call this when there's nothing to confirm.
+    pub fn confirm(&mut self) -> Result<(), Error<F::Error>> {
+        let log = self.read_log()?;
+        match log.state {
+            State::Testing => self.append(log.end, RECORD_CONFIRMED),
+            State::Idle | State::Confirmed | State::RolledBack | State::Rejected => Ok(()),
+            state => Err(Error::WrongState(state)),
+        }
+    }
+
+    /// Does the bootloader's work: installs a requested update, carries on
+    /// with one that was interrupted, or rolls back an image that wasn't
+    /// confirmed. Returns the header of the image to start, in the primary
+    /// slot, or `None` if there's no valid image.
+    pub fn boot(&mut self) -> Result<Option<ImageHeader>, Error<F::Error>> {
+        let log = self.read_log()?;
+        match log.state {
+            State::Pending(_) if self.secondary_header()?.is_none() => {
+                self.append(log.end, RECORD_REJECTED)?;
+            }
+            State::Pending(Mode::Swap) | State::Installing(Mode::Swap) => {
+                let end = self.swap(log, RECORD_SWAP)?;
+                self.append(end, RECORD_TESTING)?;
+            }
+            State::Pending(Mode::Copy) | State::Installing(Mode::Copy) => {
+                let mut end = log.end;
+                for page in log.progress..log.pages {
+                    let offset = page * F::ERASE_SIZE as u32;
+                    self.copy_page(self.layout.secondary + offset, self.layout.primary + offset)?;
+                    self.append(end, RECORD_COPY | page)?;
+                    end += 1;
+                }
+                self.append(end, RECORD_CONFIRMED)?;
+            }
+            // The new image didn't confirm itself before this reset
+            State::Testing | State::RollingBack => {
+                let end = self.swap(log, RECORD_BACK)?;
+                self.append(end, RECORD_ROLLED_BACK)?;
+            }
+            State::Idle | State::Confirmed | State::RolledBack | State::Rejected => {}
+        }
+        self.primary_header()
+    }
+
+    /// Swaps the first `log.pages` pages of the slots, carrying on from
+    /// `log.progress`, and logging each step with `record`. Returns the
+    /// index of the next free word of the log.
+    fn swap(&mut self, log: Log, record: u32) -> Result<u32, Error<F::Error>> {
+        let page_size = F::ERASE_SIZE as u32;
+        let mut end = log.end;
+        for step in log.progress..3 * log.pages {
+            let offset = (step / 3) * page_size;
+            let primary = self.layout.primary + offset;
+            let secondary = self.layout.secondary + offset;
+            match step % 3 {
+                0 => self.copy_page(primary, self.layout.scratch)?,
+                1 => self.copy_page(secondary, primary)?,
+                _ => self.copy_page(self.layout.scratch, secondary)?,
+            }
+            self.append(end, record | step)?;
+            end += 1;
+        }
+        Ok(end)
+    }
+
+    /// Erases the page at `to`, and copies the page at `from` into it
+    fn copy_page(&mut self, from: u32, to: u32) -> Result<(), Error<F::Error>> {
+        let page = F::ERASE_SIZE as u32;
+        self.flash.erase(to, to + page).map_err(Error::Flash)?;
+        let mut buffer = [0; CHUNK];
+        let mut done = 0;
+        while done < page {
+            let count = (CHUNK as u32).min(page - done);
+            let chunk = &mut buffer[..count as usize];
+            self.flash.read(from + done, chunk).map_err(Error::Flash)?;
+            // Erased flash needn't be programmed
+            if chunk.iter().any(|&byte| byte != 0xFF) {
+                self.flash.write(to + done, chunk).map_err(Error::Flash)?;
+            }
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Reads and checks the header and image in the slot at `slot`
+    fn valid_header(&mut self, slot: u32) -> Result<Option<ImageHeader>, Error<F::Error>> {
+        let mut bytes = [0; ImageHeader::ENCODED_LEN];
+        self.flash.read(slot, &mut bytes).map_err(Error::Flash)?;
+        let header = match ImageHeader::from_bytes(&bytes) {
+            Some(header)
+                if (header.length as usize) <= self.layout.slot_size as usize - HEADER_SIZE =>
+            {
+                header
+            }
+            _ => return Ok(None),
+        };
+
+        let mut buffer = [0; CHUNK];
+        let mut crc = !0;
+        let mut done = 0;
+        while done < header.length {
+            let count = (CHUNK as u32).min(header.length - done);
+            let chunk = &mut buffer[..count as usize];
+            self.flash
+                .read(slot + HEADER_SIZE as u32 + done, chunk)
+                .map_err(Error::Flash)?;
+            crc = crc32_update(crc, chunk);
+            done += count;
+        }
+        Ok(if !crc == header.crc {
+            Some(header)
+        } else {
+            None
+        })
+    }
+
+    /// Checks that the secondary slot isn't part of an update, and returns
+    /// the log
+    fn check_secondary_free(&mut self) -> Result<Log, Error<F::Error>> {
+        let log = self.read_log()?;
+        match log.state {
+            State::Idle | State::Confirmed | State::RolledBack | State::Rejected => Ok(log),
+            state => Err(Error::WrongState(state)),
+        }
+    }
+
+    /// Reads the log in the state area
+    fn read_log(&mut self) -> Result<Log, Error<F::Error>> {
+        let mut log = Log {
+            state: State::Idle,
+            pages: 0,
+            progress: 0,
+            end: 0,
+        };
+        let words = self.layout.state_size / 4;
+        while log.end < words {
+            let mut bytes = [0; 4];
+            self.flash
+                .read(self.layout.state + log.end * 4, &mut bytes)
+                .map_err(Error::Flash)?;
+            let record = u32::from_le_bytes(bytes);
+            if record == RECORD_NONE {
+                break;
+            }
+            log.end += 1;
+
+            // A record only counts if it follows on from the ones before. A
+            // word which was being written when the power failed won't, and
+            // is skipped.
+            let low = record & !RECORD_TAG;
+            let expected = log.progress;
+            match (record & RECORD_TAG, log.state) {
+                (RECORD_REQUEST, State::Idle) => {
+                    let mode = match low >> 16 {
+                        1 => Mode::Swap,
+                        2 => Mode::Copy,
+                        _ => continue,
+                    };
+                    log.state = State::Pending(mode);
+                    log.pages = low & 0xFFFF;
+                }
+                (RECORD_SWAP, State::Pending(Mode::Swap))
+                | (RECORD_SWAP, State::Installing(Mode::Swap))
+                    if low == expected =>
+                {
+                    log.state = State::Installing(Mode::Swap);
+                    log.progress += 1;
+                }
+                (RECORD_COPY, State::Pending(Mode::Copy))
+                | (RECORD_COPY, State::Installing(Mode::Copy))
+                    if low == expected =>
+                {
+                    log.state = State::Installing(Mode::Copy);
+                    log.progress += 1;
+                }
+                (RECORD_BACK, State::Testing) if low == 0 => {
+                    log.state = State::RollingBack;
+                    log.progress = 1;
+                }
+                (RECORD_BACK, State::RollingBack) if low == expected => {
+                    log.progress += 1;
+                }
+                _ => match (record, log.state) {
+                    (RECORD_TESTING, State::Pending(Mode::Swap))
+                    | (RECORD_TESTING, State::Installing(Mode::Swap)) => {
+                        log.state = State::Testing;
+                        log.progress = 0;
+                    }
+                    (RECORD_CONFIRMED, State::Testing)
+                    | (RECORD_CONFIRMED, State::Pending(Mode::Copy))
+                    | (RECORD_CONFIRMED, State::Installing(Mode::Copy)) => {
+                        log.state = State::Confirmed;
+                    }
+                    (RECORD_ROLLED_BACK, State::Testing)
+                    | (RECORD_ROLLED_BACK, State::RollingBack) => {
+                        log.state = State::RolledBack;
+                    }
+                    (RECORD_REJECTED, State::Pending(_)) => {
+                        log.state = State::Rejected;
+                    }
+                    _ => {}
+                },
+            }
+        }
+        Ok(log)
+    }
+
+    /// Writes `record` to word `index` of the state area
+    fn append(&mut self, index: u32, record: u32) -> Result<(), Error<F::Error>> {
+        if index >= self.layout.state_size / 4 {
+            return Err(Error::StateFull);
+        }
+        self.flash
+            .write(self.layout.state + index * 4, &record.to_le_bytes())
+            .map_err(Error::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+    const PAGE: usize = 1024;
+    const SIZE: usize = 10 * PAGE;
+
+    const LAYOUT: Layout = Layout {
+        primary: 0,
+        secondary: 0x1000,
+        slot_size: 0x1000,
+        scratch: 0x2000,
+        state: 0x2400,
+        state_size: 0x400,
+    };
+
+    /// Simulated flash which can lose power part way through an erase or
+    /// write
+    struct Flash {
+        data: [u8; SIZE],
+        /// Page erases and writes that can be done before the power fails
+        budget: Option<usize>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct PowerFailed;
+
+    impl NorFlashError for PowerFailed {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    impl Flash {
+        fn new() -> Self {
+            Flash {
+                data: [0xFF; SIZE],
+                budget: None,
+            }
+        }
+
+        /// Uses up one operation, if the power hasn't failed
+        fn spend(&mut self) -> bool {
+            match &mut self.budget {
+                Some(0) => false,
+                Some(budget) => {
+                    *budget -= 1;
+                    true
+                }
+                None => true,
+            }
+        }
+    }
+
+    impl ErrorType for Flash {
+        type Error = PowerFailed;
+    }
+
+    impl ReadNorFlash for Flash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerFailed> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            SIZE
+        }
+    }
+
+    impl NorFlash for Flash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = PAGE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerFailed> {
+            for page in (from as usize..to as usize).step_by(PAGE) {
+                if !self.spend() {
+                    // Half erased
+                    self.data[page..page + PAGE / 2].fill(0xFF);
+                    return Err(PowerFailed);
+                }
+                self.data[page..page + PAGE].fill(0xFF);
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerFailed> {
+            let (result, bytes) = if self.spend() {
+                (Ok(()), bytes)
+            } else {
+                // Half written
+                (Err(PowerFailed), &bytes[..bytes.len() / 2])
+            };
+            let offset = offset as usize;
+            for (byte, &new) in self.data[offset..offset + bytes.len()]
+                .iter_mut()
+                .zip(bytes)
+            {
+                *byte &= new;
+            }
+            result
+        }
+    }
+
+    /// An image, header included, with a body of `length` bytes
+    fn image(version: u32, length: usize) -> [u8; 0x1000] {
+        let mut image = [0xFF; 0x1000];
+        for (n, byte) in image[HEADER_SIZE..HEADER_SIZE + length]
+            .iter_mut()
+            .enumerate()
+        {
+            *byte = (n as u32 * 7 + version) as u8;
+        }
+        let header = ImageHeader::for_image(version, &image[HEADER_SIZE..HEADER_SIZE + length]);
+        image[..ImageHeader::ENCODED_LEN].copy_from_slice(&header.to_bytes());
+        image
+    }
+
+    /// Flash with version 1 installed, and version 2 downloaded and requested
+    fn requested(mode: Mode) -> Updater<Flash> {
+        let mut flash = Flash::new();
+        flash.data[..0x1000].copy_from_slice(&image(1, 2000));
+        let mut updater = Updater::new(flash, LAYOUT).unwrap();
+        updater.confirm().unwrap();
+        updater.erase_secondary().unwrap();
+        updater.write_secondary(0, &image(2, 2500)).unwrap();
+        updater.request_update(mode).unwrap();
+        updater
+    }
+
+    /// The version of the image in the primary slot, checking it's intact
+    fn primary_version(updater: &mut Updater<Flash>) -> u32 {
+        updater.primary_header().unwrap().unwrap().version
+    }
+
+    /// Reboots, with the power failing after `budget` operations
+    fn reboot(updater: Updater<Flash>, budget: Option<usize>) -> (Updater<Flash>, bool) {
+        let mut flash = updater.free();
+        flash.budget = budget;
+        let mut updater = Updater::new(flash, LAYOUT).unwrap();
+        let failed = updater.boot().is_err();
+        updater.flash.budget = None;
+        (updater, failed)
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = ImageHeader::for_image(3, b"123456789");
+        assert_eq!(header.crc, 0xCBF4_3926);
+        let mut bytes = header.to_bytes();
+        assert_eq!(ImageHeader::from_bytes(&bytes), Some(header));
+        bytes[5] ^= 1;
+        assert_eq!(ImageHeader::from_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn bad_layout() {
+        let unaligned = Layout {
+            secondary: 0x1001,
+            ..LAYOUT
+        };
+        assert!(matches!(
+            Updater::new(Flash::new(), unaligned),
+            Err(Error::BadLayout)
+        ));
+        let too_big = Layout {
+            state_size: 0x800,
+            ..LAYOUT
+        };
+        assert!(matches!(
+            Updater::new(Flash::new(), too_big),
+            Err(Error::BadLayout)
+        ));
+    }
+
+    #[test]
+    fn overlapping_layout() {
+        let slots = Layout {
+            secondary: 0x800,
+            ..LAYOUT
+        };
+        assert!(matches!(
+            Updater::new(Flash::new(), slots),
+            Err(Error::BadLayout)
+        ));
+        let scratch = Layout {
+            scratch: 0x1C00,
+            ..LAYOUT
+        };
+        assert!(matches!(
+            Updater::new(Flash::new(), scratch),
+            Err(Error::BadLayout)
+        ));
+        let state = Layout {
+            state: 0x2000,
+            ..LAYOUT
+        };
+        assert!(matches!(
+            Updater::new(Flash::new(), state),
+            Err(Error::BadLayout)
+        ));
+        // Each area of LAYOUT ends where the next starts, which is fine
+        assert!(Updater::new(Flash::new(), LAYOUT).is_ok());
+    }
+
+    #[test]
+    fn small_state_area() {
+        // Four pages per slot need 28 words of log
+        let empty = Layout {
+            state_size: 0,
+            ..LAYOUT
+        };
+        assert!(matches!(
+            Updater::new(Flash::new(), empty),
+            Err(Error::BadLayout)
+        ));
+    }
+
+    #[test]
+    fn swap_and_confirm() {
+        let mut updater = requested(Mode::Swap);
+        assert_eq!(updater.state(), Ok(State::Pending(Mode::Swap)));
+        assert_eq!(
+            updater.erase_secondary(),
+            Err(Error::WrongState(State::Pending(Mode::Swap)))
+        );
+
+        let (mut updater, _) = reboot(updater, None);
+        assert_eq!(updater.state(), Ok(State::Testing));
+        assert_eq!(primary_version(&mut updater), 2);
+        assert_eq!(updater.secondary_header().unwrap().unwrap().version, 1);
+
+        updater.confirm().unwrap();
+        let (mut updater, _) = reboot(updater, None);
+        assert_eq!(updater.state(), Ok(State::Confirmed));
+        assert_eq!(primary_version(&mut updater), 2);
+    }
+
+    #[test]
+    fn unconfirmed_image_rolls_back() {
+        let (updater, _) = reboot(requested(Mode::Swap), None);
+        let (mut updater, _) = reboot(updater, None);
+        assert_eq!(updater.state(), Ok(State::RolledBack));
+        assert_eq!(primary_version(&mut updater), 1);
+        assert_eq!(updater.secondary_header().unwrap().unwrap().version, 2);
+        assert_eq!(&updater.flash.data[..0x1000], &image(1, 2000)[..]);
+    }
+
+    #[test]
+    fn copy() {
+        let (mut updater, _) = reboot(requested(Mode::Copy), None);
+        assert_eq!(updater.state(), Ok(State::Confirmed));
+        assert_eq!(&updater.flash.data[..0x1000], &image(2, 2500)[..]);
+    }
+
+    #[test]
+    fn invalid_image_is_rejected() {
+        let mut updater = requested(Mode::Swap);
+        // Corrupted after the request
+        updater.flash.data[0x1000 + HEADER_SIZE] ^= 1;
+        let (mut updater, _) = reboot(updater, None);
+        assert_eq!(updater.state(), Ok(State::Rejected));
+        assert_eq!(primary_version(&mut updater), 1);
+
+        updater.erase_secondary().unwrap();
+        assert_eq!(updater.request_update(Mode::Swap), Err(Error::InvalidImage));
+    }
+
+    #[test]
+    fn interrupted_swap_completes() {
+        for budget in 0.. {
+            let (updater, failed) = reboot(requested(Mode::Swap), Some(budget));
+            let (mut updater, _) = reboot(updater, None);
+            if failed {
+                // The second boot finished the job
+                assert_eq!(updater.state(), Ok(State::Testing), "budget {}", budget);
+                assert_eq!(&updater.flash.data[..0x1000], &image(2, 2500)[..]);
+                assert_eq!(&updater.flash.data[0x1000..0x2000], &image(1, 2000)[..]);
+            } else {
+                // The second boot rolled it back
+                assert_eq!(updater.state(), Ok(State::RolledBack));
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn interrupted_roll_back_completes() {
+        for budget in 0.. {
+            let (updater, _) = reboot(requested(Mode::Swap), None);
+            let (updater, failed) = reboot(updater, Some(budget));
+            let (mut updater, _) = reboot(updater, None);
+            assert_eq!(updater.state(), Ok(State::RolledBack), "budget {}", budget);
+            assert_eq!(&updater.flash.data[..0x1000], &image(1, 2000)[..]);
+            assert_eq!(&updater.flash.data[0x1000..0x2000], &image(2, 2500)[..]);
+            if !failed {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn interrupted_copy_completes() {
+        for budget in 0.. {
+            let (updater, failed) = reboot(requested(Mode::Copy), Some(budget));
+            let (mut updater, _) = reboot(updater, None);
+            assert_eq!(updater.state(), Ok(State::Confirmed), "budget {}", budget);
+            assert_eq!(&updater.flash.data[..0x1000], &image(2, 2500)[..]);
+            if !failed {
+                break;
+            }
+        }
+    }
+}
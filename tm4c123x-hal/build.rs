@@ -4,14 +4,31 @@ use std::io::Write;
 use std::path::PathBuf;
 
 fn main() {
+    // The A/B update layout has a different memory.x for the bootloader and
+    // the application, chosen by TM4C_AB_IMAGE
+    let memory: &[u8] = if env::var_os("CARGO_FEATURE_AB_LAYOUT").is_none() {
+        include_bytes!("memory.x")
+    } else {
+        match env::var("TM4C_AB_IMAGE").as_deref() {
+            Ok("bootloader") => include_bytes!("memory-ab-bootloader.x"),
+            Ok("application") | Err(env::VarError::NotPresent) => {
+                include_bytes!("memory-ab-application.x")
+            }
+            _ => panic!("TM4C_AB_IMAGE must be `bootloader` or `application`"),
+        }
+    };
+
     // Put the linker script somewhere the linker can find it
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-ab-bootloader.x");
+    println!("cargo:rerun-if-changed=memory-ab-application.x");
+    println!("cargo:rerun-if-env-changed=TM4C_AB_IMAGE");
 }
@@ -0,0 +1,7 @@
+/* The bootloader, for A/B updates (the `ab-layout` feature with
+ * `TM4C_AB_IMAGE=bootloader`) */
+MEMORY
+{
+    FLASH (rx)  : ORIGIN = 0x00000000, LENGTH = 0x00004000
+    RAM   (rwx) : ORIGIN = 0x20000000, LENGTH = 0x00008000
+}
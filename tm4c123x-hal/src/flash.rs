@@ -0,0 +1,28 @@
+//! Code for programming the internal flash.
+//!
+//! The TM4C123x erases flash in 1 KiB pages.
+
+use tm4c123x::FLASH_CTRL;
+pub use tm4c_hal::flash::{
+    BootConfig, BootPin, FlashError, ProtectionChange, ProtectionMode, WRITE_BUFFER_WORDS,
+};
+
+/// The key for FMC and FMC2 writes, which depends on the KEY bit of BOOTCFG
+fn write_key(flash: &FLASH_CTRL) -> u32 {
+    if flash.bootcfg.read().bits() & (1 << 4) != 0 {
+        0xA442
+    } else {
+        0x71D5
+    }
+}
+
+/// The size of the flash, from FSIZE, which counts 2 KiB units
+fn flash_size(flash: &FLASH_CTRL) -> usize {
+    ((flash.fsize.read().bits() & 0xFFFF) as usize + 1) * 2048
+}
+
+tm4c_hal::flash_hal_macro!(
+    page_size: 1024,
+    protection_block: 2048,
+    protection_registers: 4
+);
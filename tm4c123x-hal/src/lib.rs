@@ -24,7 +24,7 @@
 #![no_std]
 
 pub use tm4c123x as pac;
-pub use tm4c_hal::{bb, delay, time};
+pub use tm4c_hal::{bb, delay, systick, time};
 
 use eh0 as hal;
 
@@ -37,13 +37,21 @@ mod sealed {
 }
 
 pub mod eeprom;
+pub mod flash;
 pub mod gpio;
 pub mod adc;
 pub mod hib;
 pub mod i2c;
+#[cfg(feature = "rtic-monotonic")]
+pub mod monotonic;
 pub mod prelude;
 pub mod pwm;
+pub mod rom;
 pub mod serial;
 pub mod spi;
 pub mod sysctl;
+#[cfg(feature = "embassy-time-driver")]
+pub mod time_driver;
 pub mod timer;
+pub mod udma;
+pub mod update;
@@ -0,0 +1,209 @@
+//! An RTIC monotonic, backed by a 64-bit wide timer
+//!
+//! The WTIMERn peripherals can be concatenated into a single 64-bit
+//! up-counter, which never wraps in practice. The monotonic uses the 64-bit
+//! match register to wake the timer queue.
+//!
+//! The prescaler isn't available in 64-bit mode, so the counter always runs
+//! at the system clock. The tick rate of the monotonic must divide the system
+//! clock exactly, and the counter value is divided down in software.
+//!
+//! ```ignore
+//! use tm4c123x_hal::wide_timer_monotonic;
+//!
+//! // A 1 MHz monotonic on WTIMER0, using the WTIMER0A interrupt
+//! wide_timer_monotonic!(Mono, WTIMER0, WTIMER0A, 1_000_000);
+//!
+//! // In your init:
+//! Mono::start(p.WTIMER0, &sc.power_control, &clocks);
+//! ```
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::peripheral::NVIC;
+use rtic_time::timer_queue::{TimerQueue, TimerQueueBackend};
+use tm4c123x::{Interrupt, WTIMER0, WTIMER1, WTIMER2, WTIMER3, WTIMER4, WTIMER5};
+
+use crate::sysctl::{self, Clocks};
+
+#[doc(hidden)]
+pub use fugit;
+#[doc(hidden)]
+pub use rtic_time;
+#[doc(hidden)]
+pub use rtic_time::monotonic::TimerQueueBasedMonotonic;
+
+/// Timer queue backend for a 64-bit wide timer. Use the
+/// [`wide_timer_monotonic!`](crate::wide_timer_monotonic) macro rather than
+/// this type directly.
+pub struct WideTimerBackend<TIM> {
+    _tim: PhantomData<TIM>,
+}
+
+macro_rules! backend {
+    ($($TIM:ident: ($powerDomain:ident, $interrupt:ident),)+) => {
+        $(
+            impl WideTimerBackend<$TIM> {
+                /// The number of timer cycles per monotonic tick
+                fn divider() -> &'static AtomicU32 {
+                    static DIVIDER: AtomicU32 = AtomicU32::new(1);
+                    &DIVIDER
+                }
+
+                /// Reads the raw 64-bit counter
+                fn counter() -> u64 {
+                    // We only ever read the counter here, so this is safe.
+                    let tim = unsafe { &*$TIM::ptr() };
+                    // Read the top half either side of the bottom half, in
+                    // case the bottom half wrapped in between
+                    loop {
+                        let high = tim.tbv.read().bits();
+                        let low = tim.tav.read().bits();
+                        if tim.tbv.read().bits() == high {
+                            return (u64::from(high) << 32) | u64::from(low);
+                        }
+                    }
+                }
+
+                #[doc(hidden)]
+                pub fn _start(
+                    tim: $TIM,
+                    tick_rate_hz: u32,
+                    pc: &sysctl::PowerControl,
+                    clocks: &Clocks,
+                ) {
+                    assert!(
+                        tick_rate_hz != 0 && clocks.sysclk.0 % tick_rate_hz == 0,
+                        "monotonic tick rate must divide the system clock"
+                    );
+                    Self::divider().store(clocks.sysclk.0 / tick_rate_hz, Ordering::Relaxed);
+
+                    // power up
+                    sysctl::control_power(
+                        pc, sysctl::Domain::$powerDomain,
+                        sysctl::RunMode::Run, sysctl::PowerState::On);
+                    sysctl::reset(pc, sysctl::Domain::$powerDomain);
+
+                    // Stop Timers
+                    tim.ctl.write(|w|
+                                  w.taen().clear_bit()
+                                  .tben().clear_bit()
+                                  .tastall().set_bit()
+                    );
+
+                    // 64-bit concatenated, periodic, counting up, with the
+                    // match interrupt enabled
+                    tim.cfg.write(|w| w.cfg()._32_bit_timer());
+                    tim.tamr.write(|w| w.tamr().period().tacdir().set_bit().tamie().set_bit());
+
+                    tim.tailr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+                    tim.tbilr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+                    tim.tbmatchr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+                    tim.tamatchr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+
+                    tim.icr.write(|w| w.tamcint().set_bit());
+                    tim.imr.write(|w| w.tamim().set_bit());
+
+                    Self::timer_queue().initialize(WideTimerBackend { _tim: PhantomData });
+
+                    // start counter
+                    tim.ctl.modify(|_, w| w.taen().set_bit());
+
+                    unsafe { NVIC::unmask(Interrupt::$interrupt) };
+                }
+            }
+
+            impl TimerQueueBackend for WideTimerBackend<$TIM> {
+                type Ticks = u64;
+
+                fn now() -> u64 {
+                    Self::counter() / u64::from(Self::divider().load(Ordering::Relaxed))
+                }
+
+                fn set_compare(instant: u64) {
+                    // We only touch the match registers here, so this is safe.
+                    let tim = unsafe { &*$TIM::ptr() };
+                    let target =
+                        instant.saturating_mul(u64::from(Self::divider().load(Ordering::Relaxed)));
+                    tim.tbmatchr.write(|w| unsafe { w.bits((target >> 32) as u32) });
+                    tim.tamatchr.write(|w| unsafe { w.bits(target as u32) });
+
+                    // The match only fires when the counter equals the
+                    // target, so if we've already gone past it, fire now.
+                    if Self::counter() >= target {
+                        Self::pend_interrupt();
+                    }
+                }
+
+                fn clear_compare_flag() {
+                    // Write-one-to-clear, so this is safe
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.icr.write(|w| w.tamcint().set_bit());
+                }
+
+                fn pend_interrupt() {
+                    NVIC::pend(Interrupt::$interrupt);
+                }
+
+                fn timer_queue() -> &'static TimerQueue<Self> {
+                    static QUEUE: TimerQueue<WideTimerBackend<$TIM>> = TimerQueue::new();
+                    &QUEUE
+                }
+            }
+        )+
+    }
+}
+
+backend! {
+    WTIMER0: (WideTimer0, WTIMER0A),
+    WTIMER1: (WideTimer1, WTIMER1A),
+    WTIMER2: (WideTimer2, WTIMER2A),
+    WTIMER3: (WideTimer3, WTIMER3A),
+    WTIMER4: (WideTimer4, WTIMER4A),
+    WTIMER5: (WideTimer5, WTIMER5A),
+}
+
+/// Creates an RTIC monotonic called `$name`, running on the wide timer
+/// `$timer`, whose A-half interrupt is `$interrupt`, ticking at
+/// `$tick_rate_hz`.
+///
+/// The macro defines the interrupt handler, so don't bind `$interrupt` to a
+/// task yourself.
+#[macro_export]
+macro_rules! wide_timer_monotonic {
+    ($name:ident, $timer:ident, $interrupt:ident, $tick_rate_hz:expr) => {
+        /// A monotonic backed by a 64-bit wide timer
+        pub struct $name;
+
+        impl $name {
+            /// Starts the monotonic. Call this once, before using it.
+            pub fn start(
+                timer: $crate::pac::$timer,
+                pc: &$crate::sysctl::PowerControl,
+                clocks: &$crate::sysctl::Clocks,
+            ) {
+                #[no_mangle]
+                #[allow(non_snake_case)]
+                unsafe extern "C" fn $interrupt() {
+                    use $crate::monotonic::rtic_time::timer_queue::TimerQueueBackend;
+                    $crate::monotonic::WideTimerBackend::<$crate::pac::$timer>::timer_queue()
+                        .on_monotonic_interrupt();
+                }
+
+                $crate::monotonic::WideTimerBackend::<$crate::pac::$timer>::_start(
+                    timer,
+                    $tick_rate_hz,
+                    pc,
+                    clocks,
+                );
+            }
+        }
+
+        impl $crate::monotonic::TimerQueueBasedMonotonic for $name {
+            type Backend = $crate::monotonic::WideTimerBackend<$crate::pac::$timer>;
+            type Instant = $crate::monotonic::fugit::Instant<u64, 1, { $tick_rate_hz }>;
+            type Duration = $crate::monotonic::fugit::Duration<u64, 1, { $tick_rate_hz }>;
+        }
+    };
+}
@@ -0,0 +1,37 @@
+//! Driver and boot loader routines in ROM. See [`tm4c_hal::rom`].
+
+pub use tm4c_hal::rom::{crc16, crc16_array, crc16_array3, crc8_ccitt, update_usb, version};
+
+use crate::{i2c::I2C, serial::Serial, spi::Spi};
+use tm4c123x::{I2C0, SSI0, UART0};
+use tm4c_hal::rom::{enter_boot_loader, index};
+
+/// Enters the ROM boot loader on UART0, which keeps the baud rate and frame
+/// format `serial` was set up with.
+///
+/// Interrupts are disabled first. The boot loader resets the chip once it
+/// has been told to start the new image.
+pub fn update_uart<TX, RX, RTS, CTS>(serial: Serial<UART0, TX, RX, RTS, CTS>) -> ! {
+    core::mem::forget(serial);
+    unsafe { enter_boot_loader(index::UPDATE_UART) }
+}
+
+/// Enters the ROM boot loader on SSI0, as an SSI slave, using the pins
+/// `spi` was set up with.
+///
+/// Interrupts are disabled first. The boot loader resets the chip once it
+/// has been told to start the new image.
+pub fn update_ssi<PINS>(spi: Spi<SSI0, PINS>) -> ! {
+    core::mem::forget(spi);
+    unsafe { enter_boot_loader(index::UPDATE_SSI) }
+}
+
+/// Enters the ROM boot loader on I2C0, as an I2C slave at address 0x42,
+/// using the pins `i2c` was set up with.
+///
+/// Interrupts are disabled first. The boot loader resets the chip once it
+/// has been told to start the new image.
+pub fn update_i2c<PINS>(i2c: I2C<I2C0, PINS>) -> ! {
+    core::mem::forget(i2c);
+    unsafe { enter_boot_loader(index::UPDATE_I2C) }
+}
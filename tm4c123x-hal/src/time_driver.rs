@@ -0,0 +1,73 @@
+//! An `embassy-time` driver, backed by a wide or general purpose timer
+//!
+//! The WTIMERn peripherals can be concatenated into a single 64-bit
+//! up-counter, which never wraps in practice. The 64-bit match register is
+//! used as the alarm.
+//!
+//! The 16/32-bit TIMERn peripherals are concatenated into a free-running
+//! 32-bit up-counter instead, which is extended to 64 bits in software by
+//! counting overflows in the time-out interrupt. The match register is only
+//! armed once the alarm falls within the current 32-bit period; every
+//! overflow re-arms the alarm, so alarms further out are picked up as the
+//! counter gets there. The wide timers are the better choice if one is free.
+//!
+//! The prescaler isn't available in 64-bit or 32-bit mode, so the counter
+//! always runs at the system clock. The `embassy-time` tick rate (set with its
+//! `tick-hz-*` features) must divide the system clock exactly, and the
+//! counter value is divided down in software.
+//!
+//! ```ignore
+//! // Provide the embassy-time driver on WTIMER0, using the WTIMER0A interrupt
+//! tm4c123x_hal::timer_time_driver!(WTIMER0, WTIMER0A);
+//!
+//! // Before using anything from embassy-time:
+//! tm4c123x_hal::time_driver::start(p.WTIMER0, &sc.power_control, &clocks);
+//! ```
+
+use tm4c123x::{
+    Interrupt, TIMER0, TIMER1, TIMER2, TIMER3, TIMER4, TIMER5, WTIMER0, WTIMER1, WTIMER2, WTIMER3,
+    WTIMER4, WTIMER5,
+};
+
+use crate::sysctl::{self, Clocks};
+
+tm4c_hal::time_driver_macro! {
+    wide: [
+        WTIMER0: (WideTimer0, WTIMER0A),
+        WTIMER1: (WideTimer1, WTIMER1A),
+        WTIMER2: (WideTimer2, WTIMER2A),
+        WTIMER3: (WideTimer3, WTIMER3A),
+        WTIMER4: (WideTimer4, WTIMER4A),
+        WTIMER5: (WideTimer5, WTIMER5A),
+    ],
+    timers: [
+        TIMER0: (Timer0, TIMER0A),
+        TIMER1: (Timer1, TIMER1A),
+        TIMER2: (Timer2, TIMER2A),
+        TIMER3: (Timer3, TIMER3A),
+        TIMER4: (Timer4, TIMER4A),
+        TIMER5: (Timer5, TIMER5A),
+    ],
+}
+
+/// Provides the `embassy-time` driver on the wide or general purpose timer
+/// `$timer`, whose A-half interrupt is `$interrupt`. Start it with [`start`] before using
+/// `embassy-time`.
+///
+/// The macro defines the interrupt handler, so don't bind `$interrupt`
+/// yourself.
+#[macro_export]
+macro_rules! timer_time_driver {
+    ($timer:ident, $interrupt:ident) => {
+        $crate::time_driver::embassy_time_driver::time_driver_impl!(
+            static __TM4C_TIME_DRIVER: $crate::time_driver::TimerDriver<$crate::pac::$timer> =
+                $crate::time_driver::TimerDriver::new()
+        );
+
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn $interrupt() {
+            __TM4C_TIME_DRIVER._on_interrupt();
+        }
+    };
+}
@@ -1,16 +1,37 @@
 //! Timers
+//!
+//! As well as the embedded-hal 0.2 `CountDown` and `Periodic` traits, a
+//! `Timer` is a blocking and an async `DelayNs`. Using it as a delay stops
+//! any count down in progress; call `start` again afterwards if you need it.
+//!
+//! The async delay sleeps until the timer's A-half time-out interrupt, which
+//! it unmasks in the NVIC. Forward that interrupt to `Timer::on_interrupt`:
+//!
+//! ```ignore
+//! #[interrupt]
+//! fn TIMER0A() {
+//!     Timer::<TIMER0>::on_interrupt();
+//! }
+//! ```
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
 
 use crate::{
     hal::timer::{CountDown, Periodic},
     sysctl::{self, Clocks},
 };
 
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::NVIC;
 #[rustfmt::skip]
 use tm4c123x::{
+    Interrupt,
     TIMER0, TIMER1, TIMER2, TIMER3, TIMER4, TIMER5,
     WTIMER0, WTIMER1, WTIMER2, WTIMER3, WTIMER4, WTIMER5,
 };
-use tm4c_hal::time::Hertz;
+use tm4c_hal::{delay, time::Hertz};
 use void::Void;
 
 /// Hardware timers
@@ -26,8 +47,12 @@ pub enum Event {
     TimeOut,
 }
 
+/// The longest period the delay runs the timer for in one go. The 16/32-bit
+/// timers can't count any further, and it keeps the wide timers simple.
+const MAX_DELAY_PERIOD: u64 = 0xFFFF_FFFF;
+
 macro_rules! hal {
-    ($($TIM:ident: ($tim:ident, $powerDomain:ident),)+) => {
+    ($($TIM:ident: ($tim:ident, $powerDomain:ident, $interrupt:ident),)+) => {
         $(
             impl Periodic for Timer<$TIM> {}
 
@@ -68,7 +93,99 @@ macro_rules! hal {
                 }
             }
 
+            impl embedded_hal::delay::DelayNs for Timer<$TIM> {
+                fn delay_ns(&mut self, ns: u32) {
+                    let mut remaining = delay::cycles(ns, self.clocks.sysclk);
+                    while remaining > 0 {
+                        let cycles = remaining.min(MAX_DELAY_PERIOD);
+                        self.start_delay_period(cycles as u32);
+                        while self.tim.ris.read().tatoris().bit_is_clear() {}
+                        remaining -= cycles;
+                    }
+                    self.stop_delay_period();
+                }
+            }
+
+            impl embedded_hal_async::delay::DelayNs for Timer<$TIM> {
+                async fn delay_ns(&mut self, ns: u32) {
+                    let mut remaining = delay::cycles(ns, self.clocks.sysclk);
+                    unsafe { NVIC::unmask(Interrupt::$interrupt) };
+                    while remaining > 0 {
+                        let cycles = remaining.min(MAX_DELAY_PERIOD);
+                        self.start_delay_period(cycles as u32);
+                        poll_fn(|cx| {
+                            if self.tim.ris.read().tatoris().bit_is_set() {
+                                return Poll::Ready(());
+                            }
+                            interrupt::free(|cs| {
+                                Self::waker().borrow(cs).replace(Some(cx.waker().clone()));
+                            });
+                            self.tim.imr.modify(|_, w| w.tatoim().set_bit());
+                            // The interrupt only fires from here on, so check
+                            // we didn't miss the time-out while registering.
+                            if self.tim.ris.read().tatoris().bit_is_set() {
+                                self.tim.imr.modify(|_, w| w.tatoim().clear_bit());
+                                return Poll::Ready(());
+                            }
+                            Poll::Pending
+                        })
+                        .await;
+                        remaining -= cycles;
+                    }
+                    self.stop_delay_period();
+                }
+            }
+
             impl Timer<$TIM> {
+                /// The task waiting in an async delay, if any
+                fn waker() -> &'static Mutex<RefCell<Option<Waker>>> {
+                    static WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+                    &WAKER
+                }
+
+                /// Handles the time-out interrupt for an async delay. Call
+                /// this from the timer's A-half interrupt handler.
+                pub fn on_interrupt() {
+                    // We only mask the time-out interrupt here, and leave the
+                    // flag set for the delay to see, so this is safe.
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.imr.modify(|_, w| w.tatoim().clear_bit());
+                    if let Some(waker) = interrupt::free(|cs| Self::waker().borrow(cs).take()) {
+                        waker.wake();
+                    }
+                }
+
+                /// Runs the timer for one delay period of `cycles` cycles,
+                /// without listening for the time-out
+                fn start_delay_period(&mut self, cycles: u32) {
+                    self.tim.ctl.modify(|_, w|
+                        w.taen().clear_bit()
+                        .tben().clear_bit()
+                    );
+                    self.tim.imr.modify(|_, w| w.tatoim().clear_bit());
+                    self.tim.icr.write(|w| w.tatocint().set_bit());
+
+                    // On a wide timer the upper half of the count lives in
+                    // TBILR; on the 16/32-bit timers it aliases the top of
+                    // TAILR, which the write after this one overrides.
+                    self.tim.tbilr.write(|w| unsafe { w.bits(0) });
+                    self.tim.tailr.write(|w| unsafe { w.bits(cycles) });
+                    self.tim.tav.write(|w| unsafe { w.bits(cycles) });
+
+                    self.tim.ctl.modify(|_, w|
+                        w.taen().set_bit()
+                    );
+                }
+
+                /// Stops the timer at the end of a delay
+                fn stop_delay_period(&mut self) {
+                    self.tim.ctl.modify(|_, w|
+                        w.taen().clear_bit()
+                        .tben().clear_bit()
+                    );
+                    self.tim.icr.write(|w| w.tatocint().set_bit());
+                }
+
                 // XXX(why not name this `new`?) bummer: constructors need to have different names
                 // even if the `$TIM` are non overlapping (compare to the `free` function below
                 // which just works)
@@ -145,17 +262,17 @@ macro_rules! hal {
 }
 
 hal! {
-    TIMER0: (timer0, Timer0),
-    TIMER1: (timer1, Timer1),
-    TIMER2: (timer2, Timer2),
-    TIMER3: (timer3, Timer3),
-    TIMER4: (timer4, Timer4),
-    TIMER5: (timer5, Timer5),
-
-    WTIMER0: (wtimer0, WideTimer0),
-    WTIMER1: (wtimer1, WideTimer1),
-    WTIMER2: (wtimer2, WideTimer2),
-    WTIMER3: (wtimer3, WideTimer3),
-    WTIMER4: (wtimer4, WideTimer4),
-    WTIMER5: (wtimer5, WideTimer5),
+    TIMER0: (timer0, Timer0, TIMER0A),
+    TIMER1: (timer1, Timer1, TIMER1A),
+    TIMER2: (timer2, Timer2, TIMER2A),
+    TIMER3: (timer3, Timer3, TIMER3A),
+    TIMER4: (timer4, Timer4, TIMER4A),
+    TIMER5: (timer5, Timer5, TIMER5A),
+
+    WTIMER0: (wtimer0, WideTimer0, WTIMER0A),
+    WTIMER1: (wtimer1, WideTimer1, WTIMER1A),
+    WTIMER2: (wtimer2, WideTimer2, WTIMER2A),
+    WTIMER3: (wtimer3, WideTimer3, WTIMER3A),
+    WTIMER4: (wtimer4, WideTimer4, WTIMER4A),
+    WTIMER5: (wtimer5, WideTimer5, WTIMER5A),
 }
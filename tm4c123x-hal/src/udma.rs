@@ -0,0 +1,8 @@
+//! Micro Direct Memory Access (uDMA) controller
+
+use crate::sysctl;
+use tm4c123x::UDMA;
+
+pub use tm4c_hal::udma::{ControlTable, DmaError, DmaEvent};
+
+tm4c_hal::udma_hal_macro!();
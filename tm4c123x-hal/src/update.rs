@@ -0,0 +1,39 @@
+//! A/B firmware updates on the internal flash. See [`tm4c_hal::update`].
+//!
+//! [`LAYOUT`] matches `memory-ab-bootloader.x` and
+//! `memory-ab-application.x`, which the `ab-layout` feature uses in place of
+//! `memory.x`. The `TM4C_AB_IMAGE` environment variable picks between them:
+//! build the bootloader with `TM4C_AB_IMAGE=bootloader`, and the application
+//! with `TM4C_AB_IMAGE=application`, or with it unset.
+
+pub use tm4c_hal::update::{
+    Error, ImageHeader, Layout, Mode, State, Updater, HEADER_MAGIC, HEADER_SIZE,
+};
+
+/// The flash layout for A/B updates: a 16 KiB bootloader, two
+/// 112 KiB slots, a 1 KiB scratch page and 15 KiB for the state
+pub const LAYOUT: Layout = Layout {
+    primary: 0x0000_4000,
+    secondary: 0x0002_0000,
+    slot_size: 0x0001_C000,
+    scratch: 0x0003_C000,
+    state: 0x0003_C400,
+    state_size: 0x0000_3C00,
+};
+
+/// Starts the application in the primary slot of `layout`, from the
+/// bootloader.
+///
+/// # Safety
+///
+/// The primary slot must hold a valid image, as checked by
+/// [`Updater::boot`]. Anything the bootloader set up (peripherals,
+/// interrupts, clocks) is left as it is, so the application must cope with
+/// that.
+pub unsafe fn start_application(layout: &Layout) -> ! {
+    let vector_table = layout.application_start() as *const u32;
+    (*cortex_m::peripheral::SCB::PTR)
+        .vtor
+        .write(vector_table as u32);
+    cortex_m::asm::bootload(vector_table)
+}
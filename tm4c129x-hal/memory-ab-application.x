@@ -0,0 +1,7 @@
+/* The application, in the primary slot after its header, for A/B updates
+ * (the `ab-layout` feature with `TM4C_AB_IMAGE=application`) */
+MEMORY
+{
+    FLASH (rx)  : ORIGIN = 0x00010400, LENGTH = 0x0006FC00
+    RAM   (rwx) : ORIGIN = 0x20000000, LENGTH = 0x00040000
+}
@@ -0,0 +1,13 @@
+//! Code for the EEProm module.
+//!
+//! The TM4C129x has 6 KiB of EEPROM in 96 blocks, but the driver reads the
+//! geometry from the EESIZE register rather than assuming it.
+
+use crate::sysctl::{self};
+use tm4c129x::{Interrupt, EEPROM, FLASH_CTRL};
+pub use tm4c_hal::eeprom::{
+    kv, Blocks, Busy, EepromAddress, EepromError, Erase, InitError, Protection, Read,
+    SupportStatus, Write,
+};
+
+tm4c_hal::eeprom_hal_macro!(hide: [0: eehide0, 1: eehide1, 2: eehide2]);
@@ -0,0 +1,25 @@
+//! Code for programming the internal flash.
+//!
+//! The TM4C129x erases flash in 16 KiB pages, but protects it in 2 KiB
+//! blocks, each a bit of FMPREn and FMPPEn.
+
+use tm4c129x::FLASH_CTRL;
+pub use tm4c_hal::flash::{
+    BootConfig, BootPin, FlashError, ProtectionChange, ProtectionMode, WRITE_BUFFER_WORDS,
+};
+
+/// The key for FMC and FMC2 writes, from FLASHPEKEY
+fn write_key(flash: &FLASH_CTRL) -> u32 {
+    flash.flpekey.read().bits() & 0xFFFF
+}
+
+/// The size of the flash, from FLASHPP, which counts 2 KiB units
+fn flash_size(flash: &FLASH_CTRL) -> usize {
+    ((flash.pp.read().bits() & 0xFFFF) as usize + 1) * 2048
+}
+
+tm4c_hal::flash_hal_macro!(
+    page_size: 16 * 1024,
+    protection_block: 2048,
+    protection_registers: 16
+);
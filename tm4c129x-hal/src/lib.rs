@@ -26,7 +26,7 @@
 #![allow(deprecated)]
 
 pub use tm4c129x as pac;
-pub use tm4c_hal::{bb, delay, time};
+pub use tm4c_hal::{bb, delay, systick, time};
 
 use sealed::Sealed;
 mod sealed {
@@ -36,12 +36,21 @@ mod sealed {
     impl Sealed for () {}
 }
 
+pub mod eeprom;
+pub mod flash;
 pub mod gpio;
 pub mod hib;
 pub mod i2c;
+#[cfg(feature = "rtic-monotonic")]
+pub mod monotonic;
 pub mod prelude;
+pub mod rom;
 pub mod serial;
 // pub mod spi;
 pub mod sysctl;
+#[cfg(feature = "embassy-time-driver")]
+pub mod time_driver;
+pub mod udma;
+pub mod update;
 
 use eh0 as hal;
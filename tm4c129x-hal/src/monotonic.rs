@@ -0,0 +1,242 @@
+//! An RTIC monotonic, backed by a general purpose timer
+//!
+//! The TM4C129x has no 32/64-bit wide timers, so the monotonic runs a
+//! 16/32-bit GPTM as a free-running 32-bit up-counter and extends it to 64
+//! bits in software by counting overflows in the time-out interrupt. The
+//! match register is only armed once the target falls within the current
+//! 32-bit period; every overflow re-evaluates the timer queue, so targets
+//! further out are picked up as the counter gets there.
+//!
+//! The prescaler isn't available in 32-bit mode, so the counter always runs
+//! at the system clock. The tick rate of the monotonic must divide the system
+//! clock exactly, and the counter value is divided down in software.
+//!
+//! ```ignore
+//! use tm4c129x_hal::timer_monotonic;
+//!
+//! // A 1 MHz monotonic on TIMER0, using the TIMER0A interrupt
+//! timer_monotonic!(Mono, TIMER0, TIMER0A, 1_000_000);
+//!
+//! // In your init:
+//! Mono::start(p.TIMER0, &sc.power_control, &clocks);
+//! ```
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::{interrupt, peripheral::NVIC};
+use rtic_time::timer_queue::{TimerQueue, TimerQueueBackend};
+use tm4c129x::{Interrupt, TIMER0, TIMER1, TIMER2, TIMER3, TIMER4, TIMER5};
+
+use crate::sysctl::{self, Clocks};
+
+#[doc(hidden)]
+pub use fugit;
+#[doc(hidden)]
+pub use rtic_time;
+#[doc(hidden)]
+pub use rtic_time::monotonic::TimerQueueBasedMonotonic;
+
+/// Timer queue backend for a general purpose timer. Use the
+/// [`timer_monotonic!`](crate::timer_monotonic) macro rather than this type
+/// directly.
+pub struct TimerBackend<TIM> {
+    _tim: PhantomData<TIM>,
+}
+
+macro_rules! backend {
+    ($($TIM:ident: ($powerDomain:ident, $interrupt:ident),)+) => {
+        $(
+            impl TimerBackend<$TIM> {
+                /// The number of timer cycles per monotonic tick
+                fn divider() -> &'static AtomicU32 {
+                    static DIVIDER: AtomicU32 = AtomicU32::new(1);
+                    &DIVIDER
+                }
+
+                /// The number of times the 32-bit counter has wrapped
+                fn overflows() -> &'static AtomicU32 {
+                    static OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+                    &OVERFLOWS
+                }
+
+                /// Reads the counter, extended to 64 bits
+                fn counter() -> u64 {
+                    // We only ever read the counter here, so this is safe.
+                    let tim = unsafe { &*$TIM::ptr() };
+                    interrupt::free(|_| {
+                        let mut high = Self::overflows().load(Ordering::Relaxed);
+                        let low = tim.tav.read().bits();
+                        // If the counter has wrapped but the interrupt
+                        // hasn't run yet, account for it here.
+                        if tim.ris.read().tatoris().bit_is_set() && low < 0x8000_0000 {
+                            high += 1;
+                        }
+                        (u64::from(high) << 32) | u64::from(low)
+                    })
+                }
+
+                #[doc(hidden)]
+                pub fn _start(
+                    tim: $TIM,
+                    tick_rate_hz: u32,
+                    pc: &sysctl::PowerControl,
+                    clocks: &Clocks,
+                ) {
+                    assert!(
+                        tick_rate_hz != 0 && clocks.sysclk.0 % tick_rate_hz == 0,
+                        "monotonic tick rate must divide the system clock"
+                    );
+                    Self::divider().store(clocks.sysclk.0 / tick_rate_hz, Ordering::Relaxed);
+                    Self::overflows().store(0, Ordering::Relaxed);
+
+                    // power up
+                    sysctl::control_power(
+                        pc, sysctl::Domain::$powerDomain,
+                        sysctl::RunMode::Run, sysctl::PowerState::On);
+                    sysctl::reset(pc, sysctl::Domain::$powerDomain);
+
+                    // Stop Timers
+                    tim.ctl.write(|w|
+                                  w.taen().clear_bit()
+                                  .tben().clear_bit()
+                                  .tastall().set_bit()
+                    );
+
+                    // 32-bit, periodic, counting up, with the match interrupt
+                    // enabled
+                    tim.cfg.write(|w| w.cfg()._32_bit_timer());
+                    tim.tamr.write(|w| w.tamr().period().tacdir().set_bit().tamie().set_bit());
+
+                    tim.tailr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+                    tim.tamatchr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+
+                    // Only the time-out is enabled for now; the match is
+                    // enabled by `set_compare`
+                    tim.icr.write(|w| w.tatocint().set_bit().tamcint().set_bit());
+                    tim.imr.write(|w| w.tatoim().set_bit());
+
+                    Self::timer_queue().initialize(TimerBackend { _tim: PhantomData });
+
+                    // start counter
+                    tim.ctl.modify(|_, w| w.taen().set_bit());
+
+                    unsafe { NVIC::unmask(Interrupt::$interrupt) };
+                }
+            }
+
+            impl TimerQueueBackend for TimerBackend<$TIM> {
+                type Ticks = u64;
+
+                fn now() -> u64 {
+                    Self::counter() / u64::from(Self::divider().load(Ordering::Relaxed))
+                }
+
+                fn set_compare(instant: u64) {
+                    // We only touch the match registers here, so this is safe.
+                    let tim = unsafe { &*$TIM::ptr() };
+                    let target =
+                        instant.saturating_mul(u64::from(Self::divider().load(Ordering::Relaxed)));
+                    let now = Self::counter();
+
+                    if (target >> 32) == (now >> 32) {
+                        tim.tamatchr.write(|w| unsafe { w.bits(target as u32) });
+                        tim.imr.modify(|_, w| w.tamim().set_bit());
+                    } else {
+                        // Too far away (or already gone); the overflow will
+                        // wake us up again.
+                        tim.imr.modify(|_, w| w.tamim().clear_bit());
+                    }
+
+                    // The match only fires when the counter equals the
+                    // target, so if we've already gone past it, fire now.
+                    if Self::counter() >= target {
+                        Self::pend_interrupt();
+                    }
+                }
+
+                fn clear_compare_flag() {
+                    // Write-one-to-clear, so this is safe
+                    let tim = unsafe { &*$TIM::ptr() };
+                    tim.icr.write(|w| w.tamcint().set_bit());
+                }
+
+                fn pend_interrupt() {
+                    NVIC::pend(Interrupt::$interrupt);
+                }
+
+                fn on_interrupt() {
+                    // Write-one-to-clear, so this is safe
+                    let tim = unsafe { &*$TIM::ptr() };
+                    interrupt::free(|_| {
+                        if tim.ris.read().tatoris().bit_is_set() {
+                            tim.icr.write(|w| w.tatocint().set_bit());
+                            let overflows = Self::overflows();
+                            overflows.store(
+                                overflows.load(Ordering::Relaxed).wrapping_add(1),
+                                Ordering::Relaxed,
+                            );
+                        }
+                    });
+                }
+
+                fn timer_queue() -> &'static TimerQueue<Self> {
+                    static QUEUE: TimerQueue<TimerBackend<$TIM>> = TimerQueue::new();
+                    &QUEUE
+                }
+            }
+        )+
+    }
+}
+
+backend! {
+    TIMER0: (Timer0, TIMER0A),
+    TIMER1: (Timer1, TIMER1A),
+    TIMER2: (Timer2, TIMER2A),
+    TIMER3: (Timer3, TIMER3A),
+    TIMER4: (Timer4, TIMER4A),
+    TIMER5: (Timer5, TIMER5A),
+}
+
+/// Creates an RTIC monotonic called `$name`, running on the timer `$timer`,
+/// whose A-half interrupt is `$interrupt`, ticking at `$tick_rate_hz`.
+///
+/// The macro defines the interrupt handler, so don't bind `$interrupt` to a
+/// task yourself.
+#[macro_export]
+macro_rules! timer_monotonic {
+    ($name:ident, $timer:ident, $interrupt:ident, $tick_rate_hz:expr) => {
+        /// A monotonic backed by a general purpose timer
+        pub struct $name;
+
+        impl $name {
+            /// Starts the monotonic. Call this once, before using it.
+            pub fn start(
+                timer: $crate::pac::$timer,
+                pc: &$crate::sysctl::PowerControl,
+                clocks: &$crate::sysctl::Clocks,
+            ) {
+                #[no_mangle]
+                #[allow(non_snake_case)]
+                unsafe extern "C" fn $interrupt() {
+                    use $crate::monotonic::rtic_time::timer_queue::TimerQueueBackend;
+                    $crate::monotonic::TimerBackend::<$crate::pac::$timer>::timer_queue()
+                        .on_monotonic_interrupt();
+                }
+
+                $crate::monotonic::TimerBackend::<$crate::pac::$timer>::_start(
+                    timer,
+                    $tick_rate_hz,
+                    pc,
+                    clocks,
+                );
+            }
+        }
+
+        impl $crate::monotonic::TimerQueueBasedMonotonic for $name {
+            type Backend = $crate::monotonic::TimerBackend<$crate::pac::$timer>;
+            type Instant = $crate::monotonic::fugit::Instant<u64, 1, { $tick_rate_hz }>;
+            type Duration = $crate::monotonic::fugit::Duration<u64, 1, { $tick_rate_hz }>;
+        }
+    };
+}
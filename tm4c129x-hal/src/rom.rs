@@ -0,0 +1,67 @@
+//! Driver and boot loader routines in ROM. See [`tm4c_hal::rom`].
+//!
+//! Enable the `simplelink` feature on MSP432E4 parts, which ship the
+//! SimpleLink routines in ROM in place of TivaWare.
+
+pub use tm4c_hal::rom::{crc16, crc16_array, crc16_array3, crc8_ccitt, update_usb, version};
+
+use crate::{i2c::I2C, serial::Serial, sysctl::Clocks};
+use tm4c129x::{I2C0, UART0};
+use tm4c_hal::rom::{enter_boot_loader, function, index};
+
+/// Continues a CRC-32 calculation over `data`, starting from `crc`
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    unsafe {
+        let crc32: extern "C" fn(u32, *const u8, u32) -> u32 =
+            core::mem::transmute(function(index::CRC32));
+        crc32(crc, data.as_ptr(), data.len() as u32)
+    }
+}
+
+/// Enters the ROM boot loader on UART0, which keeps the baud rate and frame
+/// format `serial` was set up with.
+///
+/// Interrupts are disabled first. The boot loader resets the chip once it
+/// has been told to start the new image.
+pub fn update_uart<TX, RX, RTS, CTS>(serial: Serial<UART0, TX, RX, RTS, CTS>) -> ! {
+    core::mem::forget(serial);
+    unsafe { enter_boot_loader(index::UPDATE_UART) }
+}
+
+/// Enters the ROM boot loader on I2C0, as an I2C slave at address 0x42,
+/// using the pins `i2c` was set up with.
+///
+/// Interrupts are disabled first. The boot loader resets the chip once it
+/// has been told to start the new image.
+pub fn update_i2c<PINS>(i2c: I2C<I2C0, PINS>) -> ! {
+    core::mem::forget(i2c);
+    unsafe { enter_boot_loader(index::UPDATE_I2C) }
+}
+
+/// Enters the ROM boot loader on SSI0, as an SSI slave.
+///
+/// # Safety
+///
+/// SSI0 and its pins must be set up, as this crate has no SSI driver to do
+/// it. Nothing the application has running will be stopped, other than
+/// interrupts.
+pub unsafe fn update_ssi() -> ! {
+    enter_boot_loader(index::UPDATE_SSI)
+}
+
+/// Enters the ROM boot loader on the Ethernet controller, which fetches the
+/// new image over BOOTP and TFTP. The MAC address is taken from the USER0
+/// and USER1 registers.
+///
+/// # Safety
+///
+/// The Ethernet controller must not be in use by the application. Nothing
+/// the application has running will be stopped, other than interrupts.
+pub unsafe fn update_emac(clocks: &Clocks) -> ! {
+    cortex_m::interrupt::disable();
+    let update: extern "C" fn(u32) = core::mem::transmute(function(index::UPDATE_EMAC));
+    update(clocks.sysclk.0);
+    loop {
+        cortex_m::asm::nop();
+    }
+}
@@ -10,12 +10,15 @@ use crate::{
     hal::{prelude::*, serial},
     sysctl::{self, Clocks},
     time::Bps,
+    udma::Udma,
 };
 use nb::{self, block};
 use void::Void;
 
+use tm4c129x::Interrupt;
 pub use tm4c129x::{UART0, UART1, UART2, UART3, UART4, UART5, UART6, UART7};
-pub use tm4c_hal::serial::NewlineMode;
+pub use tm4c_hal::serial::{Config, DataBits, Error, FifoLevel, NewlineMode, Parity, StopBits};
+pub use tm4c_hal::udma::{DmaError, DmaEvent};
 use tm4c_hal::{uart_hal_macro, uart_pin_macro};
 
 /// Serial abstraction
@@ -28,6 +31,12 @@ pub struct Serial<UART, TX, RX, RTS, CTS> {
     nl_mode: NewlineMode,
 }
 
+/// Serial abstraction whose interrupt handler moves data between the FIFOs
+/// and ring buffers. See `Serial::into_buffered`.
+pub struct BufferedSerial<UART, TX, RX, RTS, CTS> {
+    serial: Serial<UART, TX, RX, RTS, CTS>,
+}
+
 /// Serial receiver
 pub struct Rx<UART, RX, CTS> {
     _uart: PhantomData<UART>,
@@ -107,13 +116,19 @@ uart_pin_macro!(UART7,
     tx: [(gpioc::PC5, AF1)],
 );
 
+/// The DMARXIM bit of UARTIM, for the uDMA receive interrupt
+const DMA_RX_INTERRUPT: u32 = 1 << 16;
+/// The DMATXIM bit of UARTIM, for the uDMA transmit interrupt
+const DMA_TX_INTERRUPT: u32 = 1 << 17;
+
 uart_hal_macro! {
-    UART0: (Uart0, uart0),
-    UART1: (Uart1, uart1),
-    UART2: (Uart2, uart2),
-    UART3: (Uart3, uart3),
-    UART4: (Uart4, uart4),
-    UART5: (Uart5, uart5),
-    UART6: (Uart6, uart6),
-    UART7: (Uart7, uart7),
+    // dma: (receive channel, transmit channel, channel map encoding)
+    UART0: (Uart0, uart0, dma: (8, 9, 0)),
+    UART1: (Uart1, uart1, dma: (22, 23, 0)),
+    UART2: (Uart2, uart2, dma: (12, 13, 1)),
+    UART3: (Uart3, uart3, dma: (16, 17, 2)),
+    UART4: (Uart4, uart4, dma: (18, 19, 2)),
+    UART5: (Uart5, uart5, dma: (6, 7, 2)),
+    UART6: (Uart6, uart6, dma: (10, 11, 2)),
+    UART7: (Uart7, uart7, dma: (20, 21, 2)),
 }
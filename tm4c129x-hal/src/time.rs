@@ -1,19 +0,0 @@
-//! Time units
-
-pub use tm4c_hal::time::*;
-
-use crate::sysctl::Clocks;
-
-impl MonoTimer {
-    /// Creates a new `Monotonic` timer
-    pub fn new(mut dwt: DWT, clocks: Clocks) -> Self {
-        dwt.enable_cycle_counter();
-
-        // now the CYCCNT counter can't be stopped or resetted
-        drop(dwt);
-
-        MonoTimer {
-            frequency: clocks.sysclk(),
-        }
-    }
-}
@@ -0,0 +1,59 @@
+//! An `embassy-time` driver, backed by a general purpose timer
+//!
+//! The TM4C129x has no 32/64-bit wide timers, so the driver runs a 16/32-bit
+//! GPTM as a free-running 32-bit up-counter and extends it to 64 bits in
+//! software by counting overflows in the time-out interrupt. The match
+//! register is only armed once the alarm falls within the current 32-bit
+//! period; every overflow re-arms the alarm, so alarms further out are picked
+//! up as the counter gets there.
+//!
+//! The prescaler isn't available in 32-bit mode, so the counter always runs
+//! at the system clock. The `embassy-time` tick rate (set with its
+//! `tick-hz-*` features) must divide the system clock exactly, and the
+//! counter value is divided down in software.
+//!
+//! ```ignore
+//! // Provide the embassy-time driver on TIMER0, using the TIMER0A interrupt
+//! tm4c129x_hal::timer_time_driver!(TIMER0, TIMER0A);
+//!
+//! // Before using anything from embassy-time:
+//! tm4c129x_hal::time_driver::start(p.TIMER0, &sc.power_control, &clocks);
+//! ```
+
+use tm4c129x::{Interrupt, TIMER0, TIMER1, TIMER2, TIMER3, TIMER4, TIMER5};
+
+use crate::sysctl::{self, Clocks};
+
+tm4c_hal::time_driver_macro! {
+    wide: [],
+    timers: [
+        TIMER0: (Timer0, TIMER0A),
+        TIMER1: (Timer1, TIMER1A),
+        TIMER2: (Timer2, TIMER2A),
+        TIMER3: (Timer3, TIMER3A),
+        TIMER4: (Timer4, TIMER4A),
+        TIMER5: (Timer5, TIMER5A),
+    ],
+}
+
+/// Provides the `embassy-time` driver on the timer `$timer`, whose
+/// A-half interrupt is `$interrupt`. Start it with [`start`] before using
+/// `embassy-time`.
+///
+/// The macro defines the interrupt handler, so don't bind `$interrupt`
+/// yourself.
+#[macro_export]
+macro_rules! timer_time_driver {
+    ($timer:ident, $interrupt:ident) => {
+        $crate::time_driver::embassy_time_driver::time_driver_impl!(
+            static __TM4C_TIME_DRIVER: $crate::time_driver::TimerDriver<$crate::pac::$timer> =
+                $crate::time_driver::TimerDriver::new()
+        );
+
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn $interrupt() {
+            __TM4C_TIME_DRIVER._on_interrupt();
+        }
+    };
+}